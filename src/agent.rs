@@ -0,0 +1,440 @@
+use crate::config::Config;
+use crate::error::CliError;
+use crate::server::bind_unix;
+use crate::session::{Session, WireSession};
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Socket of a running agent, exported by `agent` like `SSH_AUTH_SOCK`.
+pub const AWS_MFA_SESSION_AGENT_SOCK: &str = "AWS_MFA_SESSION_AGENT_SOCK";
+/// Process id of the agent, exported by `agent` like `SSH_AGENT_PID`.
+pub const AWS_MFA_SESSION_AGENT_PID: &str = "AWS_MFA_SESSION_AGENT_PID";
+
+// Sessions closer than this to expiry are treated as gone, so a client never
+// starts work with credentials about to be rejected.
+const MIN_REMAINING: i64 = 60;
+// A client sends a few short requests per connection; cap what one may send.
+const MAX_LINE: u64 = 64 * 1024;
+// How long a background agent may take to start listening, in steps.
+const SPAWN_WAIT: [Duration; 100] = [Duration::from_millis(20); 100];
+
+/// `AWS_MFA_SESSION_AGENT_SOCK`, or `aws-mfa-session/agent.sock` under the
+/// runtime directory (the cache directory where there is none).
+pub fn socket_path() -> Option<PathBuf> {
+    match std::env::var(AWS_MFA_SESSION_AGENT_SOCK) {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) => {
+            let mut path = match dirs::runtime_dir() {
+                Some(mut dir) => {
+                    dir.push("aws-mfa-session");
+                    dir
+                }
+                None => Config::cache_dir()?,
+            };
+            path.push("agent.sock");
+            Some(path)
+        }
+    }
+}
+
+/// One request per line, as JSON.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum AgentRequest {
+    Get {
+        profile: String,
+    },
+    Put {
+        profile: String,
+        session: WireSession,
+    },
+    Remove {
+        profile: String,
+    },
+    List,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProfileEntry {
+    pub profile: String,
+    pub expiration: i64,
+}
+
+/// One reply line per request line.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum AgentReply {
+    Ok,
+    Session { session: WireSession },
+    Missing,
+    Profiles { profiles: Vec<ProfileEntry> },
+    Error { message: String },
+}
+
+/// Sessions per profile, held only in memory: the agent never writes them out.
+#[derive(Default)]
+pub struct Agent {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Agent {
+    pub fn handle(&self, request: AgentRequest) -> AgentReply {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires_in() > MIN_REMAINING);
+        match request {
+            AgentRequest::Get { profile } => match sessions.get(&profile) {
+                Some(session) => AgentReply::Session {
+                    session: session.into(),
+                },
+                None => AgentReply::Missing,
+            },
+            AgentRequest::Put { profile, session } => {
+                sessions.insert(profile, session.into());
+                AgentReply::Ok
+            }
+            AgentRequest::Remove { profile } => {
+                sessions.remove(&profile);
+                AgentReply::Ok
+            }
+            AgentRequest::List => {
+                let mut profiles: Vec<_> = sessions
+                    .iter()
+                    .map(|(profile, session)| ProfileEntry {
+                        profile: profile.clone(),
+                        expiration: session.expiration.secs(),
+                    })
+                    .collect();
+                profiles.sort_by(|a, b| a.profile.cmp(&b.profile));
+                AgentReply::Profiles { profiles }
+            }
+        }
+    }
+
+    async fn connection(&self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader.take(MAX_LINE)).lines();
+        while let Some(line) = lines.next_line().await? {
+            let reply = match serde_json::from_str(&line) {
+                Ok(request) => self.handle(request),
+                Err(e) => AgentReply::Error {
+                    message: format!("invalid request: {e}"),
+                },
+            };
+            let mut out = serde_json::to_string(&reply).map_err(io::Error::other)?;
+            out.push('\n');
+            writer.write_all(out.as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Serve until the listener fails, one task per connection. Connections
+    /// from other users are closed unanswered, whatever the socket's mode.
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            match is_current_user(&stream) {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::warn!("refused an agent connection from another user");
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "cannot check the agent client's user");
+                    continue;
+                }
+            }
+            let agent = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = agent.connection(stream).await {
+                    tracing::debug!(error = %e, "agent connection failed");
+                }
+            });
+        }
+    }
+}
+
+/// Whether the process at the other end of `stream` runs as the current user.
+fn is_current_user(stream: &UnixStream) -> io::Result<bool> {
+    // SAFETY: getuid has no preconditions and cannot fail
+    Ok(stream.peer_cred()?.uid() == unsafe { libc::getuid() })
+}
+
+/// Bind the agent socket. The default directory is created private; a live
+/// agent on the same path is an error rather than being replaced.
+pub async fn bind(path: &Path) -> Result<UnixListener, CliError> {
+    if let Some(parent) = path.parent()
+        && !parent.exists()
+    {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(CliError::ValidationError(format!(
+            "an agent is already listening on {}",
+            path.display()
+        )));
+    }
+    Ok(bind_unix(path)?)
+}
+
+/// Start an agent on `path` in the background, detached from the terminal
+/// in a session of its own as ssh-agent is, and wait until it accepts
+/// connections. Returns its process id.
+pub async fn spawn(path: &Path) -> Result<u32, CliError> {
+    // Checked here too: the background agent's own errors go nowhere.
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(CliError::ValidationError(format!(
+            "an agent is already listening on {}",
+            path.display()
+        )));
+    }
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(["agent", "--foreground", "--socket"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: setsid is async-signal-safe, so it may run between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    for delay in SPAWN_WAIT {
+        if AgentClient::connect(path).await.is_ok() {
+            return Ok(child.id());
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(CliError::ValidationError(format!(
+                "agent exited before listening on {}: {status}",
+                path.display()
+            )));
+        }
+        tokio::time::sleep(delay).await;
+    }
+    let _ = child.kill();
+    Err(CliError::ValidationError(format!(
+        "agent did not start listening on {}",
+        path.display()
+    )))
+}
+
+/// Connection to a running agent.
+pub struct AgentClient {
+    stream: BufReader<UnixStream>,
+}
+
+impl AgentClient {
+    /// Connect to the agent at `path`. The agent must run as the current user,
+    /// so an agent planted by someone else is never handed credentials.
+    pub async fn connect(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        if !is_current_user(&stream)? {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("agent on {} runs as another user", path.display()),
+            ));
+        }
+        Ok(AgentClient {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    pub async fn request(&mut self, request: &AgentRequest) -> Result<AgentReply, CliError> {
+        let mut line =
+            serde_json::to_string(request).map_err(|e| CliError::ValidationError(e.to_string()))?;
+        line.push('\n');
+        self.stream.get_mut().write_all(line.as_bytes()).await?;
+
+        let mut reply = String::new();
+        if self.stream.read_line(&mut reply).await? == 0 {
+            return Err(CliError::ValidationError(
+                "agent closed the connection".to_string(),
+            ));
+        }
+        serde_json::from_str(&reply)
+            .map_err(|e| CliError::ValidationError(format!("invalid agent reply: {e}")))
+    }
+
+    pub async fn get(&mut self, profile: &str) -> Result<Option<Session>, CliError> {
+        let request = AgentRequest::Get {
+            profile: profile.to_string(),
+        };
+        match self.request(&request).await? {
+            AgentReply::Session { session } => Ok(Some(session.into())),
+            AgentReply::Missing => Ok(None),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    pub async fn put(&mut self, profile: &str, session: &Session) -> Result<(), CliError> {
+        let request = AgentRequest::Put {
+            profile: profile.to_string(),
+            session: session.into(),
+        };
+        match self.request(&request).await? {
+            AgentReply::Ok => Ok(()),
+            reply => Err(unexpected(reply)),
+        }
+    }
+}
+
+fn unexpected(reply: AgentReply) -> CliError {
+    match reply {
+        AgentReply::Error { message } => CliError::ValidationError(format!("agent: {message}")),
+        reply => CliError::ValidationError(format!("unexpected agent reply: {reply:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::session_expiring_in;
+
+    fn wire(expires_in: i64) -> WireSession {
+        (&session_expiring_in(expires_in)).into()
+    }
+
+    #[test]
+    fn test_protocol_wire_format() {
+        let request: AgentRequest =
+            serde_json::from_str(r#"{"op":"get","profile":"dev"}"#).unwrap();
+        assert_eq!(
+            request,
+            AgentRequest::Get {
+                profile: "dev".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&AgentReply::Missing).unwrap(),
+            r#"{"status":"missing"}"#
+        );
+        assert!(serde_json::from_str::<AgentRequest>(r#"{"op":"dump"}"#).is_err());
+    }
+
+    #[test]
+    fn test_agent_holds_sessions_per_profile() {
+        let agent = Agent::default();
+        let get = |profile: &str| AgentRequest::Get {
+            profile: profile.to_string(),
+        };
+        assert_eq!(agent.handle(get("dev")), AgentReply::Missing);
+
+        let session = wire(3600);
+        let put = AgentRequest::Put {
+            profile: "dev".to_string(),
+            session: session.clone(),
+        };
+        assert_eq!(agent.handle(put), AgentReply::Ok);
+        assert_eq!(agent.handle(get("dev")), AgentReply::Session { session });
+        assert_eq!(agent.handle(get("prod")), AgentReply::Missing);
+
+        let remove = AgentRequest::Remove {
+            profile: "dev".to_string(),
+        };
+        assert_eq!(agent.handle(remove), AgentReply::Ok);
+        assert_eq!(agent.handle(get("dev")), AgentReply::Missing);
+    }
+
+    #[test]
+    fn test_agent_drops_expiring_sessions() {
+        let agent = Agent::default();
+        for (profile, expires_in) in [("live", 3600), ("dying", 30), ("dead", -10)] {
+            agent.handle(AgentRequest::Put {
+                profile: profile.to_string(),
+                session: wire(expires_in),
+            });
+        }
+        let AgentReply::Profiles { profiles } = agent.handle(AgentRequest::List) else {
+            panic!("expected a profile list");
+        };
+        let names: Vec<_> = profiles.iter().map(|p| p.profile.as_str()).collect();
+        assert_eq!(names, ["live"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_round_trip_over_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent").join("agent.sock");
+        let listener = bind(&path).await.unwrap();
+        tokio::spawn(Arc::new(Agent::default()).serve(listener));
+
+        let mut client = AgentClient::connect(&path).await.unwrap();
+        assert!(client.get("dev").await.unwrap().is_none());
+        client.put("dev", &session_expiring_in(3600)).await.unwrap();
+
+        // A second client, as a later `env` would be, sees the session.
+        let mut client = AgentClient::connect(&path).await.unwrap();
+        let session = client.get("dev").await.unwrap().unwrap();
+        assert_eq!(session.access_key_id, "ASIATEST");
+        assert!(session.expires_in() > 3500);
+
+        let reply = client.request(&AgentRequest::List).await.unwrap();
+        assert!(matches!(reply, AgentReply::Profiles { profiles } if profiles.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn test_socket_created_private_in_shared_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // An existing directory others can enter, like /tmp.
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o777)).unwrap();
+        let path = dir.path().join("agent.sock");
+        let _listener = bind(&path).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert!(is_current_user(&a).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bind_refuses_live_agent_and_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent").join("agent.sock");
+        let listener = bind(&path).await.unwrap();
+        let mode = std::fs::metadata(path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        tokio::spawn(Arc::new(Agent::default()).serve(listener));
+        assert!(bind(&path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_agent_rejects_malformed_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let listener = bind(&path).await.unwrap();
+        tokio::spawn(Arc::new(Agent::default()).serve(listener));
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"not json\n").await.unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with(r#"{"status":"error""#));
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::Config;
+use crate::credentials::write_private_file;
+use aws_sdk_iam::Client;
+use ini::Ini;
+use std::io;
+use std::path::PathBuf;
+
+const CACHE_SECTION: &str = "aliases";
+
+/// Account aliases fetched from IAM, keyed by account id. An empty value
+/// records an account with no alias, so that answer is cached too.
+pub struct AliasCache {
+    path: Option<PathBuf>,
+    ini: Ini,
+}
+
+impl AliasCache {
+    pub fn path() -> Option<PathBuf> {
+        Config::cache_dir().map(|dir| dir.join("account-aliases"))
+    }
+
+    /// Load the cache. It only saves an API call, so a missing or unreadable
+    /// file is treated as empty.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let ini = path
+            .as_ref()
+            .and_then(|p| Ini::load_from_file(p).ok())
+            .unwrap_or_default();
+        AliasCache { path, ini }
+    }
+
+    /// `None` if the account was never looked up, `Some(None)` if it has no alias.
+    pub fn get(&self, account: &str) -> Option<Option<String>> {
+        let value = self.ini.section(Some(CACHE_SECTION))?.get(account)?;
+        Some((!value.is_empty()).then(|| value.to_string()))
+    }
+
+    pub fn insert(&mut self, account: &str, alias: Option<&str>) {
+        self.ini
+            .with_section(Some(CACHE_SECTION))
+            .set(account, alias.unwrap_or_default());
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut content = Vec::new();
+        self.ini.write_to(&mut content)?;
+        write_private_file(path, &String::from_utf8_lossy(&content))
+    }
+}
+
+/// Resolve a display alias for `account`: the profile's `account_alias` config
+/// key, then the `[aliases]` map, then (only when `lookup` is set) the cache or
+/// `iam:ListAccountAliases`. Lookup failures are not fatal — the session has
+/// already been minted — so they only fall back to no alias.
+#[tracing::instrument(skip_all, fields(%account))]
+pub async fn account_alias(
+    config: &Config,
+    profile: Option<&str>,
+    account: &str,
+    lookup: bool,
+    iam_client: impl FnOnce() -> Client,
+) -> Option<String> {
+    if let Some(alias) = config
+        .get(profile, "account_alias")
+        .or_else(|| config.alias(account))
+    {
+        return Some(alias.to_string());
+    }
+    if !lookup {
+        return None;
+    }
+
+    let mut cache = AliasCache::load(AliasCache::path());
+    if let Some(alias) = cache.get(account) {
+        return alias;
+    }
+
+    match iam_client().list_account_aliases().send().await {
+        Ok(response) => {
+            let alias = response.account_aliases().first().cloned();
+            cache.insert(account, alias.as_deref());
+            if let Err(e) = cache.save() {
+                tracing::warn!(%e, "cannot write account alias cache");
+            }
+            alias
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "cannot look up account alias");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alias_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("account-aliases");
+
+        let mut cache = AliasCache::load(Some(path.clone()));
+        assert_eq!(cache.get("111111111111"), None);
+        cache.insert("111111111111", Some("acme-dev"));
+        cache.insert("222222222222", None);
+        cache.save().unwrap();
+
+        let cache = AliasCache::load(Some(path));
+        assert_eq!(
+            cache.get("111111111111"),
+            Some(Some("acme-dev".to_string()))
+        );
+        // An account without an alias is cached as such, not looked up again.
+        assert_eq!(cache.get("222222222222"), Some(None));
+        assert_eq!(cache.get("333333333333"), None);
+    }
+
+    #[test]
+    fn test_alias_cache_tolerates_missing_file() {
+        let cache = AliasCache::load(Some(PathBuf::from("/nonexistent/account-aliases")));
+        assert_eq!(cache.get("111111111111"), None);
+        AliasCache::load(None).save().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_account_alias_from_config_skips_lookup() {
+        let config = Config::from_str(
+            "[profile prod]\naccount_alias = from-profile\n\n[aliases]\n111111111111 = from-map\n",
+        );
+        let no_iam = || -> Client { panic!("IAM must not be called") };
+
+        let alias = account_alias(&config, Some("prod"), "111111111111", true, no_iam).await;
+        assert_eq!(alias.as_deref(), Some("from-profile"));
+
+        let alias = account_alias(&config, Some("dev"), "111111111111", true, no_iam).await;
+        assert_eq!(alias.as_deref(), Some("from-map"));
+
+        let alias = account_alias(&config, Some("dev"), "222222222222", false, no_iam).await;
+        assert_eq!(alias, None);
+    }
+}
@@ -1,18 +1,29 @@
 use crate::error::CliError;
+use crate::logging::LogFormat;
+use crate::output::{
+    Output, OutputOptions, parse_k8s_name, parse_k8s_namespace, parse_output, parse_tfvars_names,
+};
+use crate::prompt::validate_template;
+use crate::secret::Secret;
+use crate::server::{Listen, parse_listen, parse_loopback};
+use crate::shell::{AWS_MFA_SESSION, Shell};
+use crate::storage::StorageKind;
 use aws_config::Region;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dialoguer::Input;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
 pub fn region(s: &str) -> Result<Region, CliError> {
     Ok(Region::new(s.to_owned()))
 }
 
-fn parse_code(s: &str) -> Result<String, CliError> {
+fn parse_code(s: &str) -> Result<Secret, CliError> {
     // ASCII digits only: `char::is_numeric` accepts Unicode digits (e.g. "٦٦٦",
     // superscripts) and `str::len` counts bytes, so the old check could pass a
     // non-numeric, non-6-char code. All-ASCII-digit guarantees len == char count.
     if s.len() == 6 && s.bytes().all(|b| b.is_ascii_digit()) {
-        Ok(s.to_string())
+        Ok(Secret::from(s))
     } else {
         Err(CliError::ValidationError(
             "MFA code must be exactly 6 digits".to_string(),
@@ -27,23 +38,26 @@ fn parse_code(s: &str) -> Result<String, CliError> {
     long_about = None,
 )]
 pub struct Args {
-    /// AWS credential profile to use. AWS_PROFILE is used by default
-    #[arg(long = "profile", short = 'p')]
-    pub profile: Option<String>,
+    /// AWS credential profile to use. AWS_PROFILE is used by default. Repeat it to log in to several profiles with --update-profile
+    #[arg(long = "profile", short = 'p', global = true)]
+    pub profiles: Vec<String>,
+    /// Log in to every profile of this group from the [groups] section of the config file
+    #[arg(long = "group", short = 'g', global = true)]
+    pub group: Option<String>,
     /// AWS credentials file location to use. AWS_SHARED_CREDENTIALS_FILE is used if not defined
-    #[arg(long = "credentials-file", short = 'f')]
+    #[arg(long = "credentials-file", short = 'f', global = true)]
     pub credentials_file: Option<String>,
     /// AWS region. AWS_REGION is used if not defined
-    #[arg(long = "region", short = 'r', value_parser = region)]
+    #[arg(long = "region", short = 'r', value_parser = region, global = true)]
     pub region: Option<Region>,
     /// MFA code from MFA resource
-    #[arg(long = "code", short = 'c', value_parser = parse_code)]
-    pub code: Option<String>,
+    #[arg(long = "code", short = 'c', value_parser = parse_code, global = true)]
+    pub code: Option<Secret>,
     /// MFA device ARN. If not provided, will try to read mfa_serial from AWS profile configuration, then fall back to automatic detection
-    #[arg(long = "arn", short = 'a')]
+    #[arg(long = "arn", short = 'a', global = true)]
     pub arn: Option<String>,
     /// Session duration in seconds (900-129600)
-    #[arg(long = "duration", short = 'd', default_value_t = 3600, value_parser = clap::value_parser!(i32).range(900..=129600))]
+    #[arg(long = "duration", short = 'd', default_value_t = 3600, value_parser = clap::value_parser!(i32).range(900..=129600), global = true)]
     pub duration: i32,
     /// Run shell with AWS credentials as environment variables
     #[arg(long = "shell", short = 's')]
@@ -51,31 +65,267 @@ pub struct Args {
     /// Print(export) AWS credentials as environment variables
     #[arg(long = "export", short = 'e')]
     pub export: bool,
+    /// Write the credentials as FORMAT (dotenv, docker-args, docker-env, k8s-secret, github-env, ci, tfvars, tfvars-json) to stdout, or to PATH (0600; dotenv and docker-env keep its other lines)
+    #[arg(long = "output", short = 'o', value_name = "FORMAT[=PATH]", value_parser = parse_output)]
+    pub outputs: Vec<Output>,
+    /// Name of the --output k8s-secret Secret
+    #[arg(long = "k8s-name", value_name = "NAME", default_value = "aws-mfa-session", value_parser = parse_k8s_name)]
+    pub k8s_name: String,
+    /// Namespace of the --output k8s-secret Secret. kubectl's current namespace is used if not defined
+    #[arg(long = "k8s-namespace", value_name = "NS", value_parser = parse_k8s_namespace)]
+    pub k8s_namespace: Option<String>,
+    /// Terraform variables --output tfvars sets to the access key, secret key and token
+    #[arg(long = "tfvars-names", value_name = "ACCESS,SECRET,TOKEN", default_value = "aws_access_key,aws_secret_key,aws_token", value_parser = parse_tfvars_names)]
+    pub tfvars_names: [String; 3],
+    /// Shell syntax of the exports and --shell init script. Detected from SHELL by default
+    #[arg(long = "shell-type", value_enum, global = true)]
+    pub shell_type: Option<Shell>,
     /// Update AWS credential profile with temporary session credentials
     #[arg(long = "update-profile", short = 'u')]
     pub session_profile: Option<String>,
+    /// Assume the role of this AWS CLI profile with the session and cache it in ~/.aws/cli/cache, so `aws --profile PROFILE` asks for no MFA code
+    #[arg(long = "cli-cache", value_name = "PROFILE")]
+    pub cli_cache: Vec<String>,
+    /// Where --update-profile and credential-process store sessions [default: file]
+    #[arg(long = "storage", value_enum, global = true)]
+    pub storage: Option<StorageKind>,
+    /// Prompt template. Placeholders: {user}, {account}, {account_alias}, {profile}, {region}, {expires_in}, {role}
+    #[arg(long = "prompt", value_parser = validate_template)]
+    pub prompt: Option<String>,
+    /// Prepend the prompt to the existing PS1/prompt instead of replacing it
+    #[arg(long = "prompt-prepend")]
+    pub prompt_prepend: bool,
+    /// Look up the account alias with iam:ListAccountAliases for {account_alias} (cached per account)
+    #[arg(long = "lookup-alias")]
+    pub lookup_alias: bool,
+    /// Minutes before expiry when the --shell session starts warning
+    #[arg(long = "warn-before", default_value_t = 5, value_parser = clap::value_parser!(u32).range(0..=2160))]
+    pub warn_before: u32,
+    /// Log more to stderr (-v info, -vv debug, -vvv trace). Credentials and MFA codes are never logged
+    #[arg(long = "verbose", short = 'v', action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Log less: -q silences warnings and status lines too. RUST_LOG overrides -v and -q for logs
+    #[arg(long = "quiet", short = 'q', action = clap::ArgAction::Count, global = true)]
+    pub quiet: u8,
+    /// Log line format
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Commands {
+    /// Renew the credentials of a --shell session and print exports for the current shell
+    Refresh,
+    /// Serve the session at a local container credentials endpoint, renewing it before expiry
+    Serve {
+        /// Address to listen on: a loopback HOST:PORT, or unix:PATH
+        #[arg(long = "listen", default_value = "127.0.0.1:0", value_parser = parse_listen)]
+        listen: Listen,
+        /// Minutes before expiry when the session is renewed (prompting for a new MFA code)
+        #[arg(long = "refresh-before", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=2160))]
+        refresh_before: u32,
+    },
+    /// Serve the session at a local EC2 instance metadata (IMDSv2) endpoint, renewing it before expiry
+    Imds {
+        /// Loopback HOST:PORT to listen on
+        #[arg(long = "listen", default_value = "127.0.0.1:0", value_parser = parse_loopback)]
+        listen: SocketAddr,
+        /// Role name reported under iam/security-credentials/
+        #[arg(long = "role-name", default_value = "aws-mfa-session")]
+        role_name: String,
+        /// Minutes before expiry when the session is renewed (prompting for a new MFA code)
+        #[arg(long = "refresh-before", default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..=2160))]
+        refresh_before: u32,
+    },
+    /// Print the session as credential_process JSON, reusing the stored one until it nears expiry
+    CredentialProcess {
+        /// Name the session is stored under, as with --update-profile [default: PROFILE-mfa-session]
+        #[arg(long = "name")]
+        name: Option<String>,
+    },
+    /// Run an agent that holds sessions in memory for env and exec (like ssh-agent)
+    Agent {
+        /// Socket to listen on. AWS_MFA_SESSION_AGENT_SOCK or a per-user runtime path is used by default
+        #[arg(long = "socket")]
+        socket: Option<PathBuf>,
+        /// Stay in the foreground instead of detaching like ssh-agent
+        #[arg(long = "foreground", short = 'D')]
+        foreground: bool,
+    },
+    /// Print exports for the profile's session, from the agent when it holds one
+    Env,
+    /// Run a command with the profile's session, from the agent when it holds one
+    Exec {
+        /// Run `docker run` with the session in a private env-file: the arguments are docker run's, e.g. --rm IMAGE CMD
+        #[arg(long = "docker")]
+        docker: bool,
+        /// Command and its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Print statements that end the session in the current shell: remove the credential variables and restore the prompt
+    Unset,
+    /// Show the audit log of minted sessions, oldest first; -p limits it to one profile
+    History {
+        /// Show at most this many of the most recent entries
+        #[arg(long = "limit", default_value_t = 20)]
+        limit: usize,
+        /// Print the entries as JSON lines
+        #[arg(long = "json")]
+        json: bool,
+    },
+}
+
+impl Commands {
+    /// Commands that need no MFA code up front: the agent, history and unset
+    /// never mint, and env/exec only ask for a code when the agent has no
+    /// session.
+    pub fn defers_code(&self) -> bool {
+        matches!(
+            self,
+            Commands::Agent { .. }
+                | Commands::Env
+                | Commands::Exec { .. }
+                | Commands::CredentialProcess { .. }
+                | Commands::History { .. }
+                | Commands::Unset
+        )
+    }
 }
 
 impl Args {
+    /// The profile to use: the first `--profile`, if any.
+    pub fn profile(&self) -> Option<&str> {
+        self.profiles.first().map(String::as_str)
+    }
+
+    /// Several profiles are logged in to at once, each with its own code.
+    pub fn is_multi_profile(&self) -> bool {
+        self.profiles.len() > 1 || self.group.is_some()
+    }
+
+    /// Where the session goes, by option and command name, for the audit log.
+    pub fn output_modes(&self) -> Vec<String> {
+        let mut modes = Vec::new();
+        if self.shell {
+            modes.push("shell".to_string());
+        }
+        if self.export {
+            modes.push("export".to_string());
+        }
+        if self.session_profile.is_some() {
+            modes.push("update-profile".to_string());
+        }
+        if !self.cli_cache.is_empty() {
+            modes.push("cli-cache".to_string());
+        }
+        modes.extend(self.outputs.iter().map(Output::name));
+        if let Some(command) = &self.command {
+            let name = match command {
+                Commands::Refresh => "refresh",
+                Commands::Serve { .. } => "serve",
+                Commands::Imds { .. } => "imds",
+                Commands::CredentialProcess { .. } => "credential-process",
+                Commands::Agent { .. } => "agent",
+                Commands::Env => "env",
+                Commands::Exec { .. } => "exec",
+                Commands::History { .. } => "history",
+                Commands::Unset => "unset",
+            };
+            modes.push(name.to_string());
+        }
+        modes
+    }
+
+    /// Settings of the --output formats.
+    pub fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            k8s_name: self.k8s_name.clone(),
+            k8s_namespace: self.k8s_namespace.clone(),
+            tfvars_names: self.tfvars_names.clone(),
+        }
+    }
+
+    /// Whether the output is shell code, so the shell's syntax must be known.
+    pub fn uses_shell_syntax(&self) -> bool {
+        self.shell
+            || self.export
+            || matches!(
+                self.command,
+                Some(
+                    Commands::Refresh
+                        | Commands::Serve { .. }
+                        | Commands::Imds { .. }
+                        | Commands::Agent { .. }
+                        | Commands::Env
+                        | Commands::Unset
+                )
+            )
+    }
+
     /// At least one output mode must be selected. Otherwise `run` would mint a
     /// session token — spending the single-use MFA code — and then discard it.
     pub fn ensure_output_mode(&self) -> Result<(), CliError> {
-        if self.shell || self.export || self.session_profile.is_some() {
+        if self.shell
+            || self.export
+            || self.session_profile.is_some()
+            || !self.outputs.is_empty()
+            || !self.cli_cache.is_empty()
+            || matches!(
+                self.command,
+                Some(
+                    Commands::Serve { .. }
+                        | Commands::Imds { .. }
+                        | Commands::Agent { .. }
+                        | Commands::Env
+                        | Commands::Exec { .. }
+                        | Commands::CredentialProcess { .. }
+                        | Commands::History { .. }
+                        | Commands::Unset
+                )
+            )
+        {
             Ok(())
         } else {
             Err(CliError::ValidationError(
                 "no output mode selected: pass at least one of --shell/-s, \
-                 --export/-e, or --update-profile/-u"
+                 --export/-e, --update-profile/-u, --output/-o, --cli-cache, or use serve"
                     .to_string(),
             ))
         }
     }
 
+    /// `refresh` renews the session of the `--shell` session it runs in, so it
+    /// needs the marker that shell exports. It reuses the profile recorded in
+    /// the marker and always prints exports.
+    pub fn apply_refresh(&mut self) -> Result<(), CliError> {
+        if self.command != Some(Commands::Refresh) {
+            return Ok(());
+        }
+        let profile = std::env::var(AWS_MFA_SESSION).map_err(|_| {
+            CliError::ValidationError(
+                "refresh must be run inside a shell started with --shell".to_string(),
+            )
+        })?;
+        if self.profiles.is_empty() && !profile.is_empty() {
+            self.profiles.push(profile);
+        }
+        self.export = true;
+        Ok(())
+    }
+
     pub fn get_code(&mut self) -> Result<(), CliError> {
+        // Several profiles are asked for a code per MFA device as they are
+        // logged in to.
+        if self.is_multi_profile() || self.command.as_ref().is_some_and(Commands::defers_code) {
+            return Ok(());
+        }
         self.code = match &self.code {
             None => {
                 if cfg!(test) {
-                    Some("123456".to_string())
+                    Some(Secret::from("123456"))
                 } else {
                     Some(ask_code_interactive()?)
                 }
@@ -86,25 +336,32 @@ impl Args {
     }
 }
 
-fn ask_code_interactive() -> Result<String, CliError> {
-    let code: String = Input::new()
-        .with_prompt("Enter MFA code")
+pub(crate) fn ask_code_interactive() -> Result<Secret, CliError> {
+    ask_code("Enter MFA code")
+}
+
+pub(crate) fn ask_code(prompt: &str) -> Result<Secret, CliError> {
+    let code = Input::<String>::new()
+        .with_prompt(prompt)
         .interact_text()
+        .map(Secret::from)
         .map_err(|e| CliError::ValidationError(e.to_string()))?;
 
-    parse_code(&code)
+    parse_code(code.expose())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use clap::CommandFactory;
+    use serial_test::serial;
+    use std::path::Path;
 
     #[test]
     fn test_parse_code_valid() {
-        assert_eq!(parse_code("123456").unwrap(), "123456");
-        assert_eq!(parse_code("000000").unwrap(), "000000");
-        assert_eq!(parse_code("999999").unwrap(), "999999");
+        assert_eq!(parse_code("123456").unwrap().expose(), "123456");
+        assert_eq!(parse_code("000000").unwrap().expose(), "000000");
+        assert_eq!(parse_code("999999").unwrap().expose(), "999999");
     }
 
     #[test]
@@ -152,6 +409,333 @@ mod tests {
         assert!(args.ensure_output_mode().is_ok());
     }
 
+    #[test]
+    fn test_args_parsing_serve() {
+        let args = Args::try_parse_from(["aws-mfa-session", "serve", "-c", "123456"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Serve {
+                listen: Listen::Tcp("127.0.0.1:0".parse().unwrap()),
+                refresh_before: 5,
+            })
+        );
+        // serve is an output mode of its own.
+        assert!(args.ensure_output_mode().is_ok());
+
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "serve",
+            "--listen",
+            "127.0.0.1:9911",
+            "--refresh-before",
+            "10",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Commands::Serve {
+                refresh_before: 10,
+                ..
+            })
+        ));
+
+        assert!(
+            Args::try_parse_from(["aws-mfa-session", "serve", "--listen", "0.0.0.0:80"]).is_err()
+        );
+        assert!(
+            Args::try_parse_from(["aws-mfa-session", "serve", "--refresh-before", "0"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_args_parsing_imds() {
+        let args = Args::try_parse_from(["aws-mfa-session", "imds", "-c", "123456"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Imds {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                role_name: "aws-mfa-session".to_string(),
+                refresh_before: 5,
+            })
+        );
+        assert!(args.ensure_output_mode().is_ok());
+
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "imds",
+            "--listen",
+            "127.0.0.1:1338",
+            "--role-name",
+            "dev",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Commands::Imds { ref role_name, .. }) if role_name == "dev"
+        ));
+
+        assert!(
+            Args::try_parse_from(["aws-mfa-session", "imds", "--listen", "0.0.0.0:80"]).is_err()
+        );
+        // Instance metadata is only ever reached over TCP.
+        assert!(
+            Args::try_parse_from(["aws-mfa-session", "imds", "--listen", "unix:/tmp/s"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_args_parsing_agent_commands() {
+        let args = Args::try_parse_from(["aws-mfa-session", "agent"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Agent {
+                socket: None,
+                foreground: false
+            })
+        );
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "agent", "--socket", "/tmp/a.sock", "-D"])
+                .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Agent {
+                socket: Some(PathBuf::from("/tmp/a.sock")),
+                foreground: true
+            })
+        );
+
+        let args = Args::try_parse_from(["aws-mfa-session", "env", "-p", "dev"]).unwrap();
+        assert_eq!(args.command, Some(Commands::Env));
+        assert_eq!(args.profile(), Some("dev"));
+        assert!(args.ensure_output_mode().is_ok());
+
+        // Everything after the command name belongs to the command.
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "exec", "-p", "dev", "--", "aws", "-v"])
+                .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Exec {
+                docker: false,
+                command: vec!["aws".to_string(), "-v".to_string()]
+            })
+        );
+
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "exec",
+            "--docker",
+            "--rm",
+            "amazon/aws-cli",
+            "--version",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::Exec {
+                docker: true,
+                command: vec![
+                    "--rm".to_string(),
+                    "amazon/aws-cli".to_string(),
+                    "--version".to_string()
+                ]
+            })
+        );
+        assert!(Args::try_parse_from(["aws-mfa-session", "exec"]).is_err());
+    }
+
+    #[test]
+    fn test_get_code_deferred_for_agent_commands() {
+        for command in ["agent", "env", "history", "unset"] {
+            let mut args = Args::try_parse_from(["aws-mfa-session", command]).unwrap();
+            args.get_code().unwrap();
+            assert_eq!(args.code, None, "{command} should not ask for a code");
+        }
+        let mut args = Args::try_parse_from(["aws-mfa-session", "-e"]).unwrap();
+        args.get_code().unwrap();
+        assert!(args.code.is_some());
+    }
+
+    #[test]
+    fn test_args_parsing_history() {
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "history", "-p", "dev", "--limit", "5"])
+                .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::History {
+                limit: 5,
+                json: false
+            })
+        );
+        assert_eq!(args.profile(), Some("dev"));
+        assert!(args.ensure_output_mode().is_ok());
+    }
+
+    #[test]
+    fn test_args_parsing_multiple_profiles() {
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "-p",
+            "dev",
+            "--profile",
+            "prod",
+            "-u",
+            "{profile}-session",
+        ])
+        .unwrap();
+        assert_eq!(args.profiles, ["dev", "prod"]);
+        assert_eq!(args.profile(), Some("dev"));
+        assert!(args.is_multi_profile());
+
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "-g", "work", "-u", "{profile}-mfa"]).unwrap();
+        assert_eq!(args.group.as_deref(), Some("work"));
+        assert!(args.is_multi_profile());
+
+        let args = Args::try_parse_from(["aws-mfa-session", "-p", "dev", "-e"]).unwrap();
+        assert!(!args.is_multi_profile());
+    }
+
+    #[test]
+    fn test_args_parsing_output() {
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "--output", "dotenv", "-o", "dotenv=.env"])
+                .unwrap();
+        assert_eq!(args.outputs.len(), 2);
+        assert_eq!(args.outputs[1].path.as_deref(), Some(Path::new(".env")));
+        assert!(args.ensure_output_mode().is_ok());
+        assert_eq!(args.output_modes(), ["dotenv", "dotenv"]);
+        assert!(!args.uses_shell_syntax());
+        assert!(Args::try_parse_from(["aws-mfa-session", "-o", "yaml"]).is_err());
+
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "-o",
+            "k8s-secret",
+            "--k8s-name",
+            "aws",
+            "--k8s-namespace",
+            "dev",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.output_options(),
+            OutputOptions {
+                k8s_name: "aws".to_string(),
+                k8s_namespace: Some("dev".to_string()),
+                tfvars_names: [
+                    "aws_access_key".to_string(),
+                    "aws_secret_key".to_string(),
+                    "aws_token".to_string()
+                ],
+            }
+        );
+        assert_eq!(args.output_modes(), ["k8s-secret"]);
+        assert!(Args::try_parse_from(["aws-mfa-session", "--k8s-name", "AWS"]).is_err());
+
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "-o",
+            "tfvars=session.auto.tfvars",
+            "--tfvars-names",
+            "key,secret,token",
+        ])
+        .unwrap();
+        assert_eq!(args.tfvars_names, ["key", "secret", "token"]);
+        assert!(
+            Args::try_parse_from(["aws-mfa-session", "-o", "tfvars", "--tfvars-names", "a,b"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_args_parsing_cli_cache() {
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "-p",
+            "mycompany",
+            "--cli-cache",
+            "dev-account",
+            "--cli-cache",
+            "prod-account",
+        ])
+        .unwrap();
+        assert_eq!(args.cli_cache, ["dev-account", "prod-account"]);
+        assert!(args.ensure_output_mode().is_ok());
+        assert_eq!(args.output_modes(), ["cli-cache"]);
+        assert!(!args.uses_shell_syntax());
+    }
+
+    #[test]
+    fn test_args_parsing_shell_type() {
+        let args = Args::try_parse_from(["aws-mfa-session", "-e", "--shell-type", "fish"]).unwrap();
+        assert_eq!(args.shell_type, Some(Shell::Fish));
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "env", "--shell-type", "pwsh"]).unwrap();
+        assert_eq!(args.shell_type, Some(Shell::PowerShell));
+        assert!(Args::try_parse_from(["aws-mfa-session", "-e", "--shell-type", "ksh"]).is_err());
+        assert!(args.uses_shell_syntax());
+        let args = Args::try_parse_from(["aws-mfa-session", "-u", "dev-session"]).unwrap();
+        assert!(!args.uses_shell_syntax());
+    }
+
+    #[test]
+    fn test_get_code_defers_for_multiple_profiles() {
+        let mut args = Args::try_parse_from([
+            "aws-mfa-session",
+            "-p",
+            "dev",
+            "-p",
+            "prod",
+            "-u",
+            "{profile}-s",
+        ])
+        .unwrap();
+        args.get_code().unwrap();
+        assert!(args.code.is_none());
+    }
+
+    #[test]
+    fn test_output_modes() {
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "-s", "-u", "dev-session", "serve"]).unwrap();
+        assert_eq!(args.output_modes(), ["shell", "update-profile", "serve"]);
+        let args = Args::try_parse_from(["aws-mfa-session", "-e"]).unwrap();
+        assert_eq!(args.output_modes(), ["export"]);
+    }
+
+    #[test]
+    fn test_args_parsing_credential_process() {
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "credential-process",
+            "-p",
+            "dev",
+            "--storage",
+            "keyring",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::CredentialProcess { name: None })
+        );
+        assert_eq!(args.storage, Some(StorageKind::Keyring));
+        assert!(args.ensure_output_mode().is_ok());
+        assert!(args.command.as_ref().unwrap().defers_code());
+
+        let args =
+            Args::try_parse_from(["aws-mfa-session", "credential-process", "--name", "s"]).unwrap();
+        assert_eq!(
+            args.command,
+            Some(Commands::CredentialProcess {
+                name: Some("s".to_string())
+            })
+        );
+        assert_eq!(args.storage, None);
+        assert!(Args::try_parse_from(["aws-mfa-session", "--storage", "vault"]).is_err());
+    }
+
     #[test]
     fn test_region_parsing() {
         let parsed_region = region("us-east-1").unwrap();
@@ -166,7 +750,7 @@ mod tests {
         let args = Args::try_parse_from(["aws-mfa-session", "--code", "123456"]);
         assert!(args.is_ok());
         let args = args.unwrap();
-        assert_eq!(args.code, Some("123456".to_string()));
+        assert_eq!(args.code, Some(Secret::from("123456")));
         assert_eq!(args.duration, 3600); // default
         assert!(!args.shell);
         assert!(!args.export);
@@ -196,13 +780,13 @@ mod tests {
 
         assert!(args.is_ok());
         let args = args.unwrap();
-        assert_eq!(args.profile, Some("test-profile".to_string()));
+        assert_eq!(args.profile(), Some("test-profile"));
         assert_eq!(
             args.credentials_file,
             Some("/custom/path/credentials".to_string())
         );
         assert_eq!(args.region.unwrap().to_string(), "us-west-2");
-        assert_eq!(args.code, Some("654321".to_string()));
+        assert_eq!(args.code, Some(Secret::from("654321")));
         assert_eq!(
             args.arn,
             Some("arn:aws:iam::123456789012:mfa/test-user".to_string())
@@ -269,10 +853,10 @@ mod tests {
 
         assert!(args.is_ok());
         let args = args.unwrap();
-        assert_eq!(args.profile, Some("profile".to_string()));
+        assert_eq!(args.profile(), Some("profile"));
         assert_eq!(args.credentials_file, Some("/path/to/file".to_string()));
         assert_eq!(args.region.unwrap().to_string(), "ap-southeast-1");
-        assert_eq!(args.code, Some("123456".to_string()));
+        assert_eq!(args.code, Some(Secret::from("123456")));
         assert_eq!(args.arn, Some("arn:aws:iam::123:mfa/user".to_string()));
         assert_eq!(args.duration, 1800);
         assert!(args.shell);
@@ -280,6 +864,97 @@ mod tests {
         assert_eq!(args.session_profile, Some("session".to_string()));
     }
 
+    #[test]
+    fn test_args_parsing_prompt() {
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "--prompt",
+            "[{profile}@{account_alias}] ",
+            "--prompt-prepend",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.prompt,
+            Some("[{profile}@{account_alias}] ".to_string())
+        );
+        assert!(args.prompt_prepend);
+
+        let args = Args::try_parse_from(["aws-mfa-session"]).unwrap();
+        assert_eq!(args.prompt, None);
+        assert!(!args.prompt_prepend);
+
+        assert!(Args::try_parse_from(["aws-mfa-session", "--prompt", "{unknown}"]).is_err());
+    }
+
+    #[test]
+    fn test_args_parsing_warn_before() {
+        let args = Args::try_parse_from(["aws-mfa-session"]).unwrap();
+        assert_eq!(args.warn_before, 5);
+        let args = Args::try_parse_from(["aws-mfa-session", "--warn-before", "15"]).unwrap();
+        assert_eq!(args.warn_before, 15);
+        assert!(Args::try_parse_from(["aws-mfa-session", "--warn-before", "3000"]).is_err());
+        assert!(Args::try_parse_from(["aws-mfa-session", "--warn-before", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_args_parsing_lookup_alias() {
+        let args = Args::try_parse_from(["aws-mfa-session", "--lookup-alias"]).unwrap();
+        assert!(args.lookup_alias);
+        let args = Args::try_parse_from(["aws-mfa-session"]).unwrap();
+        assert!(!args.lookup_alias);
+    }
+
+    #[test]
+    fn test_args_parsing_refresh() {
+        let args = Args::try_parse_from(["aws-mfa-session", "refresh"]).unwrap();
+        assert_eq!(args.command, Some(Commands::Refresh));
+
+        // Session options are global, so they may follow the subcommand.
+        let args = Args::try_parse_from([
+            "aws-mfa-session",
+            "refresh",
+            "--code",
+            "123456",
+            "-d",
+            "7200",
+        ])
+        .unwrap();
+        assert_eq!(args.code, Some(Secret::from("123456")));
+        assert_eq!(args.duration, 7200);
+
+        let args = Args::try_parse_from(["aws-mfa-session", "-e"]).unwrap();
+        assert_eq!(args.command, None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply_refresh() {
+        unsafe { std::env::remove_var(AWS_MFA_SESSION) };
+        let mut args = Args::try_parse_from(["aws-mfa-session", "refresh"]).unwrap();
+        assert!(args.apply_refresh().is_err());
+
+        unsafe { std::env::set_var(AWS_MFA_SESSION, "dev") };
+        let mut args = Args::try_parse_from(["aws-mfa-session", "refresh"]).unwrap();
+        let result = args.apply_refresh();
+        let mut explicit =
+            Args::try_parse_from(["aws-mfa-session", "refresh", "-p", "prod"]).unwrap();
+        let explicit_result = explicit.apply_refresh();
+        unsafe { std::env::remove_var(AWS_MFA_SESSION) };
+
+        result.unwrap();
+        assert!(args.export);
+        assert_eq!(args.profile(), Some("dev"));
+        explicit_result.unwrap();
+        assert_eq!(explicit.profile(), Some("prod"));
+    }
+
+    #[test]
+    fn test_apply_refresh_ignores_other_modes() {
+        let mut args = Args::try_parse_from(["aws-mfa-session", "-s"]).unwrap();
+        args.apply_refresh().unwrap();
+        assert!(!args.export);
+    }
+
     #[test]
     fn test_command_structure() {
         let cmd = Args::command();
@@ -300,17 +975,19 @@ mod tests {
         let args = Args::try_parse_from(["aws-mfa-session", "--code", "123456"]).unwrap();
         let debug_str = format!("{args:?}");
         assert!(debug_str.contains("Args"));
-        assert!(debug_str.contains("123456"));
+        // The MFA code is redacted.
+        assert!(!debug_str.contains("123456"));
+        assert!(debug_str.contains("code: Some([REDACTED])"));
     }
 
     #[test]
     fn test_get_code_with_existing_code() {
         let mut args = Args::try_parse_from(["aws-mfa-session", "--code", "654321"]).unwrap();
-        assert_eq!(args.code, Some("654321".to_string()));
+        assert_eq!(args.code, Some(Secret::from("654321")));
 
         // get_code should not change existing code
         args.get_code().unwrap();
-        assert_eq!(args.code, Some("654321".to_string()));
+        assert_eq!(args.code, Some(Secret::from("654321")));
     }
 
     #[test]
@@ -320,7 +997,7 @@ mod tests {
 
         // In test mode, get_code should set code to "123456"
         args.get_code().unwrap();
-        assert_eq!(args.code, Some("123456".to_string()));
+        assert_eq!(args.code, Some(Secret::from("123456")));
     }
 
     #[test]
@@ -0,0 +1,274 @@
+use crate::config::Config;
+use crate::credentials::set_mode;
+use crate::error::CliError;
+use crate::identity::Identity;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use aws_sdk_sts::primitives::{DateTime, DateTimeFormat};
+use serde::{Deserialize, Serialize};
+
+/// One minted session. Deliberately holds no credential material: the access
+/// key id, secret, token and MFA code are never recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub profile: String,
+    pub mfa_serial: String,
+    pub account: String,
+    pub role: Option<String>,
+    pub duration: i32,
+    pub outputs: Vec<String>,
+    pub hostname: Option<String>,
+}
+
+/// Append-only JSON-lines log of minted sessions, owner read/write only.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog { path }
+    }
+
+    /// `audit.jsonl` next to the tool's config file.
+    pub fn default_path() -> Option<PathBuf> {
+        Config::path().and_then(|path| path.parent().map(|dir| dir.join("audit.jsonl")))
+    }
+
+    /// The log at the default path when the config enables it
+    /// (`audit_log = true`).
+    pub fn from_config(config: &Config, profile: Option<&str>) -> Result<Option<Self>, CliError> {
+        if !config.get_bool(profile, "audit_log")?.unwrap_or(false) {
+            return Ok(None);
+        }
+        let path = Self::default_path().ok_or_else(|| {
+            CliError::ValidationError("cannot determine the audit log path".to_string())
+        })?;
+        Ok(Some(AuditLog::new(path)))
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Append `entry` as a single write, so concurrent runs never interleave
+    /// within a line.
+    #[tracing::instrument(skip_all, fields(path = %self.path.display()))]
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), CliError> {
+        let mut line =
+            serde_json::to_string(entry).map_err(|e| CliError::ValidationError(e.to_string()))?;
+        line.push('\n');
+
+        if let Some(dir) = self.path.parent()
+            && !dir.as_os_str().is_empty()
+            && !dir.exists()
+        {
+            fs::create_dir_all(dir)?;
+            let _ = set_mode(dir, 0o700);
+        }
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path)?;
+        // The mode above only applies to a new file; tighten an existing one.
+        set_mode(&self.path, 0o600)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// All entries, oldest first. A missing log has none.
+    pub fn read(&self) -> Result<Vec<AuditEntry>, CliError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+            .map(|(n, line)| {
+                serde_json::from_str(&line?).map_err(|e| {
+                    CliError::ValidationError(format!(
+                        "audit log {} line {}: {e}",
+                        self.path.display(),
+                        n + 1
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Records every session a `SessionMinter` mints, with the context of the run.
+pub struct Auditor {
+    log: AuditLog,
+    profile: String,
+    outputs: Vec<String>,
+}
+
+impl Auditor {
+    pub fn new(log: AuditLog, profile: String, outputs: Vec<String>) -> Self {
+        Auditor {
+            log,
+            profile,
+            outputs,
+        }
+    }
+
+    pub fn record(
+        &self,
+        mfa_serial: &str,
+        duration: i32,
+        identity: &Identity,
+    ) -> Result<(), CliError> {
+        let timestamp = DateTime::from(SystemTime::now())
+            .fmt(DateTimeFormat::DateTime)
+            .map_err(|e| CliError::ValidationError(e.to_string()))?;
+        self.log.append(&AuditEntry {
+            timestamp,
+            profile: self.profile.clone(),
+            mfa_serial: mfa_serial.to_string(),
+            account: identity.account.clone(),
+            role: identity.role.clone(),
+            duration,
+            outputs: self.outputs.clone(),
+            hostname: hostname(),
+        })
+    }
+}
+
+/// A history line: time, profile, account, role, duration, outputs, host.
+pub fn format_entry(entry: &AuditEntry) -> String {
+    format!(
+        "{}  {}  {}  {}  {}s  {}  {}",
+        entry.timestamp,
+        entry.profile,
+        entry.account,
+        entry.role.as_deref().unwrap_or("-"),
+        entry.duration,
+        if entry.outputs.is_empty() {
+            "-".to_string()
+        } else {
+            entry.outputs.join(",")
+        },
+        entry.hostname.as_deref().unwrap_or("-"),
+    )
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the pointer and length describe a writable buffer we own
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity::from_arn(
+            "123456789012",
+            "arn:aws:sts::123456789012:assumed-role/Admin/alice",
+        )
+    }
+
+    #[test]
+    fn test_audit_log_appends_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config").join("audit.jsonl");
+        let auditor = Auditor::new(
+            AuditLog::new(path.clone()),
+            "dev".to_string(),
+            vec!["export".to_string()],
+        );
+        auditor
+            .record("arn:aws:iam::123456789012:mfa/alice", 3600, &identity())
+            .unwrap();
+        auditor
+            .record("arn:aws:iam::123456789012:mfa/alice", 900, &identity())
+            .unwrap();
+
+        let entries = AuditLog::new(path.clone()).read().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].profile, "dev");
+        assert_eq!(entries[0].account, "123456789012");
+        assert_eq!(entries[0].role.as_deref(), Some("Admin"));
+        assert_eq!(entries[0].duration, 3600);
+        assert_eq!(entries[1].duration, 900);
+        assert_eq!(entries[1].outputs, ["export"]);
+        assert!(entries[0].timestamp.ends_with('Z'));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_audit_log_tightens_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        fs::write(&path, "").unwrap();
+        #[cfg(unix)]
+        set_mode(&path, 0o644).unwrap();
+        Auditor::new(AuditLog::new(path.clone()), "dev".to_string(), Vec::new())
+            .record("GAHT12345678", 3600, &identity())
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(AuditLog::new(path).read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_audit_log_missing_and_malformed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        assert!(AuditLog::new(path.clone()).read().unwrap().is_empty());
+        fs::write(&path, "not json\n").unwrap();
+        assert!(AuditLog::new(path).read().is_err());
+    }
+
+    #[test]
+    fn test_format_entry() {
+        let entry = AuditEntry {
+            timestamp: "2024-01-01T12:00:00Z".to_string(),
+            profile: "dev".to_string(),
+            mfa_serial: "GAHT12345678".to_string(),
+            account: "123456789012".to_string(),
+            role: None,
+            duration: 3600,
+            outputs: vec!["shell".to_string(), "update-profile".to_string()],
+            hostname: Some("laptop".to_string()),
+        };
+        assert_eq!(
+            format_entry(&entry),
+            "2024-01-01T12:00:00Z  dev  123456789012  -  3600s  shell,update-profile  laptop"
+        );
+    }
+}
@@ -1,26 +1,30 @@
-use aws_mfa_session::{Args, run};
+use aws_mfa_session::{Args, CliError, logging, run};
 use clap::Parser;
 use std::process::exit;
-use tracing_subscriber::{EnvFilter, fmt};
 
 #[tokio::main]
 async fn main() {
     let mut opts = Args::parse();
 
-    // Configure tracing
-    let level = "off";
-    let filter = EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"));
-    fmt().with_env_filter(filter).init();
+    logging::init(opts.verbose, opts.quiet, opts.log_format);
 
-    if let Err(e) = opts.get_code() {
-        tracing::error!(?e, "application error");
+    // Check `refresh` preconditions before asking for an MFA code it cannot use.
+    // Errors are reported once on stderr below; the log records the variant.
+    if let Err(e) = opts.apply_refresh().and_then(|_| opts.get_code()) {
+        tracing::debug!(error = ?e, "application error");
         // Report the error once, as a fancy miette diagnostic.
         eprintln!("{}", miette::Report::new(e));
         exit(1);
     }
 
-    if let Err(e) = run(opts).await {
-        eprintln!("Error: {e}");
-        exit(1);
+    match run(opts).await {
+        Ok(()) => {}
+        // The command reported its own failure; pass its status on.
+        Err(CliError::CommandFailed(code)) => exit(code),
+        Err(e) => {
+            tracing::debug!(error = ?e, "application error");
+            eprintln!("Error: {e}");
+            exit(1);
+        }
     }
 }
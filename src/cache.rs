@@ -0,0 +1,419 @@
+use crate::config::Config;
+use crate::credentials::{set_mode, write_private_file};
+use crate::error::CliError;
+use crate::secret::Secret;
+use crate::session::{Session, WireSession};
+use crate::storage::SessionStore;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use dialoguer::Password;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Passphrase for the encrypted cache, instead of the key file.
+pub const AWS_MFA_SESSION_PASSPHRASE: &str = "AWS_MFA_SESSION_PASSPHRASE";
+/// Key file for the encrypted cache, instead of the default location.
+pub const AWS_MFA_SESSION_KEY_FILE: &str = "AWS_MFA_SESSION_KEY_FILE";
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// How long to wait for a key file another process has just created to be
+/// written, in steps.
+const KEY_FILE_WAIT: [Duration; 5] = [Duration::from_millis(10); 5];
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn random<const N: usize>() -> Result<[u8; N], CliError> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes)
+        .map_err(|e| CliError::ValidationError(format!("cannot generate random bytes: {e}")))?;
+    Ok(bytes)
+}
+
+/// How an entry's key is derived.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Kdf {
+    /// The key file holds the key itself.
+    KeyFile,
+    /// Argon2id over the passphrase, salted per entry.
+    Argon2id,
+}
+
+/// Where the cache key comes from.
+pub enum CacheKey {
+    /// A random key kept in a `0600` file, created on first use.
+    File(PathBuf),
+    /// A passphrase, from `AWS_MFA_SESSION_PASSPHRASE` or asked for once on
+    /// first use.
+    Passphrase(OnceLock<Secret>),
+}
+
+impl CacheKey {
+    /// The passphrase if `AWS_MFA_SESSION_PASSPHRASE` is set or the config
+    /// asks for one (`cache_passphrase = true`), otherwise the key file at
+    /// `AWS_MFA_SESSION_KEY_FILE` or `aws-mfa-session/cache.key` under the
+    /// platform config directory.
+    pub fn from_config(config: &Config, profile: Option<&str>) -> Result<Self, CliError> {
+        if let Ok(passphrase) = std::env::var(AWS_MFA_SESSION_PASSPHRASE) {
+            return Ok(CacheKey::Passphrase(OnceLock::from(Secret::from(
+                passphrase,
+            ))));
+        }
+        if config
+            .get_bool(profile, "cache_passphrase")?
+            .unwrap_or(false)
+        {
+            return Ok(CacheKey::Passphrase(OnceLock::new()));
+        }
+        let path = match std::env::var(AWS_MFA_SESSION_KEY_FILE) {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => dirs::config_dir()
+                .map(|dir| dir.join("aws-mfa-session").join("cache.key"))
+                .ok_or_else(|| {
+                    CliError::ValidationError("cannot determine the cache key file".to_string())
+                })?,
+        };
+        Ok(CacheKey::File(path))
+    }
+
+    fn kdf(&self) -> Kdf {
+        match self {
+            CacheKey::File(_) => Kdf::KeyFile,
+            CacheKey::Passphrase(_) => Kdf::Argon2id,
+        }
+    }
+
+    /// The key in the key file, created on first use. Processes starting at
+    /// once race to create it exclusively; the losers read the winner's key,
+    /// so every entry is encrypted with the key that stays on disk.
+    fn key_file(path: &Path) -> Result<Zeroizing<[u8; KEY_LEN]>, CliError> {
+        if let Some(key) = Self::read_key_file(path)? {
+            return Ok(key);
+        }
+        let key = Zeroizing::new(random::<KEY_LEN>()?);
+        match Self::create_key_file(path, &key) {
+            Ok(()) => return Ok(key),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e.into()),
+        }
+        for delay in KEY_FILE_WAIT {
+            if let Some(key) = Self::read_key_file(path)? {
+                return Ok(key);
+            }
+            std::thread::sleep(delay);
+        }
+        Self::read_key_file(path)?.ok_or_else(|| {
+            CliError::ValidationError(format!("invalid cache key file {}", path.display()))
+        })
+    }
+
+    /// The key in `path`; `None` while the file is missing or still empty.
+    fn read_key_file(path: &Path) -> Result<Option<Zeroizing<[u8; KEY_LEN]>>, CliError> {
+        let content = match fs::read_to_string(path).map(Secret::from) {
+            Ok(content) if content.expose().trim().is_empty() => return Ok(None),
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        unhex(content.expose().trim())
+            .map(Zeroizing::new)
+            .and_then(|key| <[u8; KEY_LEN]>::try_from(key.as_slice()).ok())
+            .map(|key| Some(Zeroizing::new(key)))
+            .ok_or_else(|| {
+                CliError::ValidationError(format!("invalid cache key file {}", path.display()))
+            })
+    }
+
+    /// Create `path` holding `key`, failing with `AlreadyExists` if another
+    /// process got there first. Owner-only from the start, never replaced.
+    fn create_key_file(path: &Path, key: &[u8; KEY_LEN]) -> io::Result<()> {
+        if let Some(dir) = path.parent()
+            && !dir.as_os_str().is_empty()
+            && !dir.exists()
+        {
+            fs::create_dir_all(dir)?;
+            let _ = set_mode(dir, 0o700);
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(Secret::from(hex(key)).expose().as_bytes())
+            .and_then(|()| file.sync_all())
+            .inspect_err(|_| {
+                // An empty key file would block every later run.
+                let _ = fs::remove_file(path);
+            })
+    }
+
+    fn passphrase(cell: &OnceLock<Secret>) -> Result<&str, CliError> {
+        if let Some(passphrase) = cell.get() {
+            return Ok(passphrase.expose());
+        }
+        let passphrase = Password::new()
+            .with_prompt("Session cache passphrase")
+            .interact()
+            .map(Secret::from)
+            .map_err(|e| CliError::ValidationError(e.to_string()))?;
+        Ok(cell.get_or_init(|| passphrase).expose())
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>, CliError> {
+        match self {
+            CacheKey::File(path) => Self::key_file(path),
+            CacheKey::Passphrase(cell) => {
+                let mut key = Zeroizing::new([0u8; KEY_LEN]);
+                Argon2::default()
+                    .hash_password_into(Self::passphrase(cell)?.as_bytes(), salt, &mut *key)
+                    .map_err(|e| CliError::ValidationError(format!("key derivation: {e}")))?;
+                Ok(key)
+            }
+        }
+    }
+}
+
+/// An encrypted cache file.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u8,
+    kdf: Kdf,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Sessions encrypted with ChaCha20-Poly1305, one file per name in the
+/// per-user cache directory. The name is authenticated with the session, so a
+/// file renamed to another entry fails to decrypt.
+pub struct EncryptedStore {
+    dir: PathBuf,
+    key: CacheKey,
+}
+
+impl EncryptedStore {
+    pub fn new(dir: PathBuf, key: CacheKey) -> Self {
+        EncryptedStore { dir, key }
+    }
+
+    /// `sessions` under the tool's cache directory.
+    pub fn default_dir() -> Option<PathBuf> {
+        Config::cache_dir().map(|dir| dir.join("sessions"))
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, CliError> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(CliError::ValidationError(format!(
+                "invalid session name for the encrypted cache: {name:?}"
+            )));
+        }
+        Ok(self.dir.join(format!("{name}.enc")))
+    }
+}
+
+impl SessionStore for EncryptedStore {
+    #[tracing::instrument(skip_all, fields(entry = %name))]
+    fn save(&self, name: &str, session: &Session, _region: Option<&str>) -> Result<(), CliError> {
+        let path = self.path(name)?;
+        let plaintext = serde_json::to_vec(&WireSession::from(session))
+            .map(Zeroizing::new)
+            .map_err(|e| CliError::ValidationError(e.to_string()))?;
+        let salt = random::<SALT_LEN>()?;
+        let nonce = random::<NONCE_LEN>()?;
+        let key = self.key.derive(&salt)?;
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&*key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext[..],
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| CliError::ValidationError("encryption failed".to_string()))?;
+
+        let envelope = Envelope {
+            version: 1,
+            kdf: self.key.kdf(),
+            salt: hex(&salt),
+            nonce: hex(&nonce),
+            ciphertext: hex(&ciphertext),
+        };
+        let content = serde_json::to_string(&envelope)
+            .map_err(|e| CliError::ValidationError(e.to_string()))?;
+        Ok(write_private_file(&path, &content)?)
+    }
+
+    #[tracing::instrument(skip_all, fields(entry = %name))]
+    fn load(&self, name: &str) -> Result<Option<Session>, CliError> {
+        let path = self.path(name)?;
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = |what: &str| {
+            CliError::ValidationError(format!("cache entry {}: {what}", path.display()))
+        };
+        let envelope: Envelope =
+            serde_json::from_str(&content).map_err(|_| invalid("not a cache file"))?;
+        if envelope.version != 1 {
+            return Err(invalid("unsupported version"));
+        }
+        if envelope.kdf != self.key.kdf() {
+            return Err(invalid(match envelope.kdf {
+                Kdf::KeyFile => "encrypted with the key file, not a passphrase",
+                Kdf::Argon2id => "encrypted with a passphrase, not the key file",
+            }));
+        }
+        let (Some(salt), Some(nonce), Some(ciphertext)) = (
+            unhex(&envelope.salt),
+            unhex(&envelope.nonce).filter(|n| n.len() == NONCE_LEN),
+            unhex(&envelope.ciphertext),
+        ) else {
+            return Err(invalid("malformed"));
+        };
+
+        let key = self.key.derive(&salt)?;
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&*key))
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| invalid("wrong key or corrupted"))?;
+        let wire: WireSession =
+            serde_json::from_slice(&plaintext[..]).map_err(|_| invalid("malformed session"))?;
+        Ok(Some(wire.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::session_expiring_in;
+
+    fn passphrase(p: &str) -> CacheKey {
+        CacheKey::Passphrase(OnceLock::from(Secret::from(p)))
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(hex(&[0x00, 0xab, 0xff]), "00abff");
+        assert_eq!(unhex("00abff"), Some(vec![0x00, 0xab, 0xff]));
+        assert_eq!(unhex("abc"), None);
+        assert_eq!(unhex("zz"), None);
+    }
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("config").join("cache.key");
+        let store = EncryptedStore::new(
+            dir.path().join("sessions"),
+            CacheKey::File(key_path.clone()),
+        );
+        let session = session_expiring_in(3600);
+
+        assert!(store.load("dev").unwrap().is_none());
+        store.save("dev", &session, None).unwrap();
+        let loaded = store.load("dev").unwrap().unwrap();
+        assert_eq!(loaded.secret_access_key, session.secret_access_key);
+        assert_eq!(loaded.expiration.secs(), session.expiration.secs());
+
+        // Neither the key file nor the cache file holds the secret in the clear.
+        let file = std::fs::read_to_string(dir.path().join("sessions").join("dev.enc")).unwrap();
+        assert!(!file.contains(session.secret_access_key.expose()));
+        assert!(!file.contains(session.session_token.expose()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for path in [key_path, dir.path().join("sessions").join("dev.enc")] {
+                let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_file_created_once_under_contention() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.key");
+        let keys: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| CacheKey::key_file(&path).unwrap()))
+                .collect();
+            handles.into_iter().map(|h| *h.join().unwrap()).collect()
+        });
+        assert!(keys.iter().all(|key| *key == keys[0]));
+        assert_eq!(*CacheKey::key_file(&path).unwrap(), keys[0]);
+    }
+
+    #[test]
+    fn test_passphrase_round_trip_and_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = EncryptedStore::new(dir.path().to_path_buf(), passphrase("correct horse"));
+        store.save("dev", &session_expiring_in(3600), None).unwrap();
+        assert!(store.load("dev").unwrap().is_some());
+
+        let wrong = EncryptedStore::new(dir.path().to_path_buf(), passphrase("battery staple"));
+        assert!(wrong.load("dev").is_err());
+
+        let key_file = EncryptedStore::new(
+            dir.path().to_path_buf(),
+            CacheKey::File(dir.path().join("cache.key")),
+        );
+        assert!(key_file.load("dev").is_err());
+    }
+
+    #[test]
+    fn test_entry_is_bound_to_its_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = EncryptedStore::new(dir.path().to_path_buf(), passphrase("p"));
+        store.save("dev", &session_expiring_in(3600), None).unwrap();
+        std::fs::rename(dir.path().join("dev.enc"), dir.path().join("prod.enc")).unwrap();
+        assert!(store.load("prod").is_err());
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let store = EncryptedStore::new(PathBuf::from("/nonexistent"), passphrase("p"));
+        for name in ["", "../escape", ".hidden", "a/b", "sp ace"] {
+            assert!(store.load(name).is_err(), "{name:?} should be rejected");
+        }
+        assert!(store.load("dev-mfa_session.1").unwrap().is_none());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::credentials::write_private_file;
+use crate::error::CliError;
+use crate::profile::aws_config_path;
+use crate::session::Session;
+
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use aws_config::SdkConfig;
+use aws_sdk_sts::Client as StsClient;
+use aws_sdk_sts::config::Credentials as StaticCredentials;
+use aws_sdk_sts::primitives::DateTimeFormat;
+use aws_sdk_sts::types::{AssumedRoleUser, Credentials};
+use ini::Ini;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use zeroize::Zeroizing;
+
+/// Where the AWS CLI caches the credentials of assume-role profiles.
+pub fn cache_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".aws").join("cli").join("cache"))
+}
+
+/// The settings of an assume-role profile that the AWS CLI passes to
+/// `AssumeRole`, and so derives its cache key from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoleProfile {
+    pub role_arn: String,
+    pub role_session_name: Option<String>,
+    pub external_id: Option<String>,
+    pub mfa_serial: Option<String>,
+    pub duration_seconds: Option<i32>,
+}
+
+impl RoleProfile {
+    /// Read the profile `name` from the AWS config file.
+    pub fn load(name: &str) -> Result<Self, CliError> {
+        let path = aws_config_path().ok_or_else(|| {
+            CliError::ValidationError("cannot determine the AWS config file path".to_string())
+        })?;
+        let conf = Ini::load_from_file(&path).map_err(|e| {
+            CliError::ValidationError(format!("cannot read AWS config file {path}: {e}"))
+        })?;
+        Self::from_ini(&conf, name)
+    }
+
+    fn from_ini(conf: &Ini, name: &str) -> Result<Self, CliError> {
+        let section_name = format!("profile {name}");
+        let section = conf
+            .section(Some(section_name.as_str()))
+            .or_else(|| match name {
+                "default" => conf.section(Some("default")),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                CliError::ValidationError(format!("profile {name} is not in the AWS config file"))
+            })?;
+        let get = |key: &str| section.get(key).map(str::to_string);
+        let role_arn = get("role_arn").ok_or_else(|| {
+            CliError::ValidationError(format!(
+                "profile {name} has no role_arn, so the AWS CLI does not cache its credentials"
+            ))
+        })?;
+        let duration_seconds = get("duration_seconds")
+            .map(|duration| {
+                duration.parse().map_err(|_| {
+                    CliError::ValidationError(format!(
+                        "profile {name}: duration_seconds {duration:?} is not a number"
+                    ))
+                })
+            })
+            .transpose()?;
+        Ok(RoleProfile {
+            role_arn,
+            role_session_name: get("role_session_name"),
+            external_id: get("external_id"),
+            mfa_serial: get("mfa_serial"),
+            duration_seconds,
+        })
+    }
+
+    /// The file name botocore gives the cached credentials: the hex SHA-1 of
+    /// the `AssumeRole` arguments as `json.dumps(args, sort_keys=True)`,
+    /// leaving out a generated session name.
+    pub fn cache_key(&self) -> String {
+        // After DurationSeconds, already in sorted order.
+        let strings = [
+            ("ExternalId", self.external_id.as_deref()),
+            ("RoleArn", Some(self.role_arn.as_str())),
+            ("RoleSessionName", self.role_session_name.as_deref()),
+            ("SerialNumber", self.mfa_serial.as_deref()),
+        ];
+        let mut args: Vec<String> = self
+            .duration_seconds
+            .map(|duration| format!("\"DurationSeconds\": {duration}"))
+            .into_iter()
+            .collect();
+        for (key, value) in strings {
+            if let Some(value) = value {
+                args.push(format!("\"{key}\": {}", python_json_string(value)));
+            }
+        }
+        let json = format!("{{{}}}", args.join(", "));
+        Sha1::digest(json.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Assume the role with the MFA session, which satisfies a trust policy
+    /// that requires MFA without spending another code.
+    #[tracing::instrument(name = "assume_role", skip_all, fields(role = %self.role_arn))]
+    async fn assume(
+        &self,
+        config: &SdkConfig,
+        session: &Session,
+    ) -> Result<Zeroizing<String>, CliError> {
+        let credentials = StaticCredentials::new(
+            &session.access_key_id,
+            session.secret_access_key.expose(),
+            Some(session.session_token.expose().to_string()),
+            None,
+            "aws-mfa-session",
+        );
+        let sts_config = aws_sdk_sts::config::Builder::from(config)
+            .credentials_provider(credentials)
+            .build();
+        let session_name = match &self.role_session_name {
+            Some(name) => name.clone(),
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                format!("aws-mfa-session-{}", now.as_secs())
+            }
+        };
+        let response = StsClient::from_conf(sts_config)
+            .assume_role()
+            .role_arn(&self.role_arn)
+            .role_session_name(session_name)
+            .set_external_id(self.external_id.clone())
+            .set_duration_seconds(self.duration_seconds)
+            .send()
+            .await?;
+        let credentials = response.credentials().ok_or(CliError::NoCredentials)?;
+        tracing::info!(expiration = %credentials.expiration(), "assumed role");
+        cache_entry(credentials, response.assumed_role_user())
+    }
+}
+
+/// Assume the role of the AWS CLI profile `profile` with `session` and
+/// cache the credentials where `aws --profile PROFILE` looks for them.
+pub async fn store(
+    config: &SdkConfig,
+    session: &Session,
+    profile: &str,
+) -> Result<PathBuf, CliError> {
+    let role = RoleProfile::load(profile)?;
+    let path = cache_dir()
+        .ok_or_else(|| {
+            CliError::ValidationError("cannot determine the home directory".to_string())
+        })?
+        .join(format!("{}.json", role.cache_key()));
+    let entry = role.assume(config, session).await?;
+    write_private_file(&path, &entry)?;
+    Ok(path)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CacheEntry<'a> {
+    credentials: CachedCredentials<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assumed_role_user: Option<CachedRoleUser<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CachedCredentials<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    session_token: &'a str,
+    expiration: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CachedRoleUser<'a> {
+    assumed_role_id: &'a str,
+    arn: &'a str,
+}
+
+/// The `AssumeRole` response in the form botocore caches it.
+fn cache_entry(
+    credentials: &Credentials,
+    user: Option<&AssumedRoleUser>,
+) -> Result<Zeroizing<String>, CliError> {
+    let expiration = credentials
+        .expiration()
+        .fmt(DateTimeFormat::DateTime)
+        .map_err(|e| CliError::ValidationError(format!("invalid role expiration: {e}")))?;
+    let entry = CacheEntry {
+        credentials: CachedCredentials {
+            access_key_id: credentials.access_key_id(),
+            secret_access_key: credentials.secret_access_key(),
+            session_token: credentials.session_token(),
+            expiration,
+        },
+        assumed_role_user: user.map(|user| CachedRoleUser {
+            assumed_role_id: user.assumed_role_id(),
+            arn: user.arn(),
+        }),
+    };
+    Ok(Zeroizing::new(
+        serde_json::to_string(&entry).map_err(io::Error::other)?,
+    ))
+}
+
+/// `value` as Python's `json.dumps` writes it: non-ASCII characters as
+/// `\uXXXX` escapes of their UTF-16 code units.
+fn python_json_string(value: &str) -> String {
+    let mut json = String::new();
+    for c in serde_json::to_string(value).unwrap_or_default().chars() {
+        if c.is_ascii() {
+            json.push(c);
+        } else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                json.push_str(&format!("\\u{unit:04x}"));
+            }
+        }
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sts::primitives::DateTime;
+
+    fn role(role_arn: &str) -> RoleProfile {
+        RoleProfile {
+            role_arn: role_arn.to_string(),
+            role_session_name: None,
+            external_id: None,
+            mfa_serial: None,
+            duration_seconds: None,
+        }
+    }
+
+    #[test]
+    fn test_cache_key_matches_botocore() {
+        // Expected keys computed with botocore's AssumeRoleCredentialFetcher.
+        let profile = RoleProfile {
+            mfa_serial: Some("arn:aws:iam::111111111111:mfa/username".to_string()),
+            ..role("arn:aws:iam::222222222222:role/AdminRole")
+        };
+        assert_eq!(
+            profile.cache_key(),
+            "7b5a5cb1b2fa10f3738da40721abb1b63d049524"
+        );
+
+        let profile = RoleProfile {
+            role_session_name: Some("me".to_string()),
+            external_id: Some("ëx\"t".to_string()),
+            duration_seconds: Some(7200),
+            ..role("arn:aws:iam::222222222222:role/AdminRole")
+        };
+        assert_eq!(
+            profile.cache_key(),
+            "f2f9a2d974acd6b29f4c115993ff5ce06e6fb75e"
+        );
+    }
+
+    #[test]
+    fn test_python_json_string() {
+        assert_eq!(python_json_string("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(python_json_string("ë😀"), r#""\u00eb\ud83d\ude00""#);
+    }
+
+    #[test]
+    fn test_role_profile_from_ini() {
+        let conf = Ini::load_from_str(
+            "[profile dev]\n\
+             role_arn = arn:aws:iam::222222222222:role/AdminRole\n\
+             source_profile = mycompany\n\
+             mfa_serial = arn:aws:iam::111111111111:mfa/username\n\
+             duration_seconds = 7200\n\
+             [profile base]\n\
+             region = us-east-1\n\
+             [profile broken]\n\
+             role_arn = arn:aws:iam::222222222222:role/AdminRole\n\
+             duration_seconds = long\n",
+        )
+        .unwrap();
+        assert_eq!(
+            RoleProfile::from_ini(&conf, "dev").unwrap(),
+            RoleProfile {
+                mfa_serial: Some("arn:aws:iam::111111111111:mfa/username".to_string()),
+                duration_seconds: Some(7200),
+                ..role("arn:aws:iam::222222222222:role/AdminRole")
+            }
+        );
+        assert!(RoleProfile::from_ini(&conf, "base").is_err());
+        assert!(RoleProfile::from_ini(&conf, "broken").is_err());
+        assert!(RoleProfile::from_ini(&conf, "missing").is_err());
+    }
+
+    #[test]
+    fn test_cache_entry() {
+        let credentials = Credentials::builder()
+            .access_key_id("ASIAROLE")
+            .secret_access_key("secret")
+            .session_token("token")
+            .expiration(DateTime::from_secs(1_700_000_000))
+            .build()
+            .unwrap();
+        let user = AssumedRoleUser::builder()
+            .assumed_role_id("AROA:me")
+            .arn("arn:aws:sts::222222222222:assumed-role/AdminRole/me")
+            .build()
+            .unwrap();
+        assert_eq!(
+            cache_entry(&credentials, Some(&user)).unwrap().as_str(),
+            r#"{"Credentials":{"AccessKeyId":"ASIAROLE","SecretAccessKey":"secret","SessionToken":"token","Expiration":"2023-11-14T22:13:20Z"},"AssumedRoleUser":{"AssumedRoleId":"AROA:me","Arn":"arn:aws:sts::222222222222:assumed-role/AdminRole/me"}}"#
+        );
+    }
+}
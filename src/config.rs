@@ -0,0 +1,190 @@
+use crate::error::CliError;
+use ini::Ini;
+use std::path::PathBuf;
+
+pub const AWS_MFA_SESSION_CONFIG: &str = "AWS_MFA_SESSION_CONFIG";
+
+/// Settings for this tool (as opposed to the AWS CLI files), read from an INI
+/// file. Keys in a `[profile NAME]` section override those in `[default]`:
+///
+/// ```ini
+/// [default]
+/// prompt = AWS:{user}@{account} \$
+///
+/// [profile prod]
+/// prompt = PROD:{account_alias} \$
+/// prompt_prepend = true
+///
+/// [aliases]
+/// 123456789012 = acme-prod
+///
+/// [groups]
+/// work = dev, prod
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    ini: Ini,
+}
+
+impl Config {
+    /// `AWS_MFA_SESSION_CONFIG`, or `aws-mfa-session/config` under the
+    /// platform config directory (e.g. `~/.config` on Linux).
+    pub fn path() -> Option<PathBuf> {
+        match std::env::var(AWS_MFA_SESSION_CONFIG) {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => {
+                let mut path = dirs::config_dir()?;
+                path.push("aws-mfa-session");
+                path.push("config");
+                Some(path)
+            }
+        }
+    }
+
+    /// Per-user cache directory for this tool, e.g. `~/.cache/aws-mfa-session`.
+    pub fn cache_dir() -> Option<PathBuf> {
+        let mut path = dirs::cache_dir()?;
+        path.push("aws-mfa-session");
+        Some(path)
+    }
+
+    /// Load the config file. A missing file is an empty config; a malformed one
+    /// is an error rather than being silently ignored.
+    pub fn load() -> Result<Self, CliError> {
+        match Self::path() {
+            Some(path) if path.exists() => {
+                let ini = Ini::load_from_file(&path).map_err(|e| {
+                    CliError::ValidationError(format!(
+                        "invalid config file {}: {e}",
+                        path.display()
+                    ))
+                })?;
+                Ok(Config { ini })
+            }
+            _ => Ok(Config::default()),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_str(content: &str) -> Self {
+        Config {
+            ini: Ini::load_from_str(content).unwrap(),
+        }
+    }
+
+    /// Look `key` up in the profile's section, falling back to `[default]`.
+    pub fn get(&self, profile: Option<&str>, key: &str) -> Option<&str> {
+        profile
+            .and_then(|p| self.ini.section(Some(format!("profile {p}"))))
+            .and_then(|s| s.get(key))
+            .or_else(|| self.ini.section(Some("default")).and_then(|s| s.get(key)))
+    }
+
+    /// Display name for `account` from the local `[aliases]` map.
+    pub fn alias(&self, account: &str) -> Option<&str> {
+        self.ini
+            .section(Some("aliases"))
+            .and_then(|s| s.get(account))
+    }
+
+    /// Profiles of the named group in the `[groups]` section, separated by
+    /// commas or whitespace.
+    pub fn group(&self, name: &str) -> Option<Vec<String>> {
+        let profiles = self.ini.section(Some("groups"))?.get(name)?;
+        Some(
+            profiles
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|p| !p.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+        )
+    }
+
+    pub fn get_bool(&self, profile: Option<&str>, key: &str) -> Result<Option<bool>, CliError> {
+        match self.get(profile, key) {
+            None => Ok(None),
+            Some(v) => match v.to_ascii_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(Some(true)),
+                "false" | "no" | "off" | "0" => Ok(Some(false)),
+                _ => Err(CliError::ValidationError(format!(
+                    "config key {key}: expected true or false, got {v:?}"
+                ))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[default]
+prompt = default-prompt
+prompt_prepend = false
+
+[profile prod]
+prompt = prod-prompt
+prompt_prepend = yes
+
+[profile bad]
+prompt_prepend = maybe
+
+[aliases]
+123456789012 = acme-prod
+
+[groups]
+work = dev, prod staging
+"#;
+
+    #[test]
+    fn test_group() {
+        let config = Config::from_str(CONFIG);
+        assert_eq!(
+            config.group("work"),
+            Some(vec![
+                "dev".to_string(),
+                "prod".to_string(),
+                "staging".to_string()
+            ])
+        );
+        assert_eq!(config.group("missing"), None);
+    }
+
+    #[test]
+    fn test_profile_overrides_default() {
+        let config = Config::from_str(CONFIG);
+        assert_eq!(config.get(Some("prod"), "prompt"), Some("prod-prompt"));
+        assert_eq!(config.get(Some("dev"), "prompt"), Some("default-prompt"));
+        assert_eq!(config.get(None, "prompt"), Some("default-prompt"));
+        assert_eq!(config.get(None, "missing"), None);
+    }
+
+    #[test]
+    fn test_get_bool() {
+        let config = Config::from_str(CONFIG);
+        assert_eq!(
+            config.get_bool(Some("prod"), "prompt_prepend").unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            config.get_bool(None, "prompt_prepend").unwrap(),
+            Some(false)
+        );
+        assert_eq!(config.get_bool(None, "missing").unwrap(), None);
+        assert!(config.get_bool(Some("bad"), "prompt_prepend").is_err());
+    }
+
+    #[test]
+    fn test_alias_map() {
+        let config = Config::from_str(CONFIG);
+        assert_eq!(config.alias("123456789012"), Some("acme-prod"));
+        assert_eq!(config.alias("210987654321"), None);
+    }
+
+    #[test]
+    fn test_empty_config() {
+        let config = Config::default();
+        assert_eq!(config.get(Some("prod"), "prompt"), None);
+    }
+}
@@ -1,15 +1,20 @@
+use crate::secret::Secret;
 use dirs::home_dir;
 use std::path::PathBuf;
 use std::time::Duration;
 use std::{fs, io};
 use tempfile::NamedTempFile;
+use zeroize::Zeroizing;
 
 pub struct Profile {
     pub name: String,
     pub access_key_id: String,
-    pub secret_access_key: String,
-    pub session_token: Option<String>,
+    pub secret_access_key: Secret,
+    pub session_token: Option<Secret>,
     pub region: Option<String>,
+    /// RFC 3339 expiration of temporary credentials, kept so a stored session
+    /// can be checked before it is reused.
+    pub expiration: Option<String>,
 }
 
 impl Profile {
@@ -17,28 +22,38 @@ impl Profile {
         format!("[{}]", self.name)
     }
 
-    pub fn config_section(&self) -> String {
-        let mut result = self.config_section_header();
-        result.push('\n');
-        result.push_str("aws_access_key_id = ");
-        result.push_str(&self.access_key_id);
-
-        result.push('\n');
-        result.push_str("aws_secret_access_key = ");
-        result.push_str(&self.secret_access_key);
-
+    /// The profile's section, in a buffer sized up front and wiped on drop,
+    /// as it holds the secret key and session token.
+    pub fn config_section(&self) -> Zeroizing<String> {
+        let mut lines = vec![
+            ("aws_access_key_id = ", self.access_key_id.as_str()),
+            ("aws_secret_access_key = ", self.secret_access_key.expose()),
+        ];
         if let Some(ref session_token) = self.session_token {
-            result.push('\n');
-            result.push_str("aws_session_token = ");
-            result.push_str(session_token);
+            lines.push(("aws_session_token = ", session_token.expose()));
+        }
+        if let Some(ref expiration) = self.expiration {
+            lines.push(("aws_session_expiration = ", expiration));
         }
         if let Some(ref region) = self.region {
-            result.push('\n');
-            result.push_str("region = ");
-            result.push_str(region);
+            lines.push(("region = ", region));
         }
 
-        result.push('\n');
+        let len = self.name.len()
+            + 3
+            + lines
+                .iter()
+                .map(|(key, value)| key.len() + value.len() + 1)
+                .sum::<usize>();
+        let mut result = Zeroizing::new(String::with_capacity(len));
+        result.push('[');
+        result.push_str(&self.name);
+        result.push_str("]\n");
+        for (key, value) in lines {
+            result.push_str(key);
+            result.push_str(value);
+            result.push('\n');
+        }
         result
     }
 }
@@ -51,7 +66,7 @@ impl Profile {
 /// starts with `[`, and the target section is the header line whose fully-trimmed
 /// text equals `[name]` exactly. This avoids the substring/prefix and
 /// `$`-expansion hazards of the previous regex-replacement approach.
-pub fn update_profile(config: &str, profile: &Profile) -> String {
+pub fn update_profile(config: &str, profile: &Profile) -> Zeroizing<String> {
     let target = profile.config_section_header(); // "[name]"
     let new_section = profile.config_section(); // ends with a single '\n'
 
@@ -72,7 +87,7 @@ pub fn update_profile(config: &str, profile: &Profile) -> String {
                 end -= 1;
             }
 
-            let mut out = String::with_capacity(config.len() + new_section.len());
+            let mut out = Zeroizing::new(String::with_capacity(config.len() + new_section.len()));
             for line in &lines[..start] {
                 out.push_str(line);
             }
@@ -84,8 +99,11 @@ pub fn update_profile(config: &str, profile: &Profile) -> String {
         }
         None if config.is_empty() => new_section,
         None => {
-            // Append after the existing content with exactly one blank-line separator.
-            let mut out = config.to_string();
+            // Append after the existing content with exactly one blank-line
+            // separator, in a buffer sized for all of it.
+            let mut out =
+                Zeroizing::new(String::with_capacity(config.len() + 2 + new_section.len()));
+            out.push_str(config);
             if !out.ends_with('\n') {
                 out.push('\n');
             }
@@ -104,7 +122,7 @@ pub const AWS_SHARED_CREDENTIALS_FILE: &str = "AWS_SHARED_CREDENTIALS_FILE";
 // https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-files.html
 // Linux or macOS: ~/.aws/credentials
 // Windows: "%UserProfile%\.aws\credentials"
-fn credential_file() -> io::Result<PathBuf> {
+pub(crate) fn credential_file() -> io::Result<PathBuf> {
     let file = match std::env::var(AWS_SHARED_CREDENTIALS_FILE) {
         Ok(s) => PathBuf::from(s),
         _ => {
@@ -120,13 +138,13 @@ fn credential_file() -> io::Result<PathBuf> {
 
 /// Set the Unix permission bits of `path`. No-op on non-Unix platforms.
 #[cfg(unix)]
-fn set_mode(path: &std::path::Path, mode: u32) -> io::Result<()> {
+pub(crate) fn set_mode(path: &std::path::Path, mode: u32) -> io::Result<()> {
     use std::os::unix::fs::PermissionsExt;
     fs::set_permissions(path, fs::Permissions::from_mode(mode))
 }
 
 #[cfg(not(unix))]
-fn set_mode(_path: &std::path::Path, _mode: u32) -> io::Result<()> {
+pub(crate) fn set_mode(_path: &std::path::Path, _mode: u32) -> io::Result<()> {
     Ok(())
 }
 
@@ -198,17 +216,36 @@ fn persist_with_retry(temp_file: NamedTempFile, dest: &std::path::Path) -> io::R
 }
 
 pub fn update_credentials(profile: &Profile) -> io::Result<()> {
+    update_credentials_all(std::slice::from_ref(profile))
+}
+
+/// Insert or replace every profile's section in a single atomic write, so
+/// readers never see only some of them updated.
+pub fn update_credentials_all(profiles: &[Profile]) -> io::Result<()> {
     let file_path = credential_file()?;
 
     // A missing credentials file is a valid starting point (e.g. env-var-only
-    // auth, fresh setup): treat it as empty and create it below.
-    let config = match fs::read_to_string(&file_path) {
+    // auth, fresh setup): treat it as empty and create it below. Both copies
+    // of the file hold keys, so they are wiped once written.
+    let config = Zeroizing::new(match fs::read_to_string(&file_path) {
         Ok(config) => config,
         Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
         Err(e) => return Err(e),
-    };
-    let updated_config = update_profile(&config, profile);
+    });
+    let updated_config = profiles
+        .iter()
+        .fold(config, |config, profile| update_profile(&config, profile));
 
+    write_private_file(&file_path, &updated_config)
+}
+
+/// Atomically replace `file_path` with `contents`, owner read/write only.
+///
+/// Shared by every file this tool writes that may hold secrets or that other
+/// processes read concurrently: readers see either the old or the new file,
+/// never a torn write.
+#[tracing::instrument(skip_all, fields(path = %file_path.display()))]
+pub(crate) fn write_private_file(file_path: &std::path::Path, contents: &str) -> io::Result<()> {
     // Ensure the parent directory exists so the temp file can be created next to
     // the target (required for an atomic same-filesystem rename). Only adjust
     // permissions on a directory we create ourselves — never re-permission a
@@ -225,14 +262,14 @@ pub fn update_credentials(profile: &Profile) -> io::Result<()> {
     }
 
     let temp_file = NamedTempFile::new_in(temp_dir)?;
-    fs::write(temp_file.path(), updated_config)?;
+    fs::write(temp_file.path(), contents)?;
 
     // Enforce 0600 on the secret-bearing file rather than inheriting whatever
     // permissions the original had (which may have been group/world readable).
     // This is the critical protection, so its failure is propagated.
     set_mode(temp_file.path(), 0o600)?;
 
-    persist_with_retry(temp_file, &file_path)?;
+    persist_with_retry(temp_file, file_path)?;
 
     Ok(())
 }
@@ -246,9 +283,10 @@ mod test {
         Profile {
             name: name.to_string(),
             access_key_id: "AKIATEST".to_string(),
-            secret_access_key: "secret".to_string(),
-            session_token: Some("token".to_string()),
+            secret_access_key: Secret::from("secret"),
+            session_token: Some(Secret::from("token")),
             region: Some("us-east-1".to_string()),
+            expiration: None,
         }
     }
 
@@ -268,12 +306,13 @@ mod test {
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY"),
-            secret_access_key: String::from("SEC123RET"),
+            secret_access_key: Secret::from("SEC123RET"),
             session_token: None,
             region: None,
+            expiration: None,
         };
         // An empty config yields just the section (no leading blank lines).
-        let updated = update_profile("", &profile);
+        let updated = update_profile("", &profile).to_string();
         assert_eq!(
             updated,
             r##"[session-production]
@@ -288,9 +327,10 @@ aws_secret_access_key = SEC123RET
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY"),
-            secret_access_key: String::from("SEC123RET"),
+            secret_access_key: Secret::from("SEC123RET"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         let original = r##"[default]
@@ -303,7 +343,7 @@ aws_secret_access_key = SEC123RET/PROD
 "##;
 
         // Appended after the existing content with a single blank-line separator.
-        let updated = update_profile(original, &profile);
+        let updated = update_profile(original, &profile).to_string();
         assert_eq!(
             updated,
             r##"[default]
@@ -326,9 +366,10 @@ aws_secret_access_key = SEC123RET
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY/NEW"),
-            secret_access_key: String::from("SEC123RET/NEW"),
+            secret_access_key: Secret::from("SEC123RET/NEW"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         let original = r##"[session-production]
@@ -343,7 +384,7 @@ aws_secret_access_key = SEC123RET/DEFAULT
 aws_access_key_id = AACCCCEESSSSKKEEYY/PROD
 aws_secret_access_key = SEC123RET/PROD"##;
 
-        let updated = update_profile(original, &profile);
+        let updated = update_profile(original, &profile).to_string();
         assert_eq!(
             updated,
             r##"[session-production]
@@ -365,9 +406,10 @@ aws_secret_access_key = SEC123RET/PROD"##
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY/NEW"),
-            secret_access_key: String::from("SEC123RET/NEW"),
+            secret_access_key: Secret::from("SEC123RET/NEW"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         let original = r##"[default]
@@ -382,7 +424,7 @@ aws_secret_access_key = SEC123RET/OLD
 aws_access_key_id = AACCCCEESSSSKKEEYY/PROD
 aws_secret_access_key = SEC123RET/PROD"##;
 
-        let updated = update_profile(original, &profile);
+        let updated = update_profile(original, &profile).to_string();
         assert_eq!(
             updated,
             r##"[default]
@@ -404,9 +446,10 @@ aws_secret_access_key = SEC123RET/PROD"##
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY/NEW"),
-            secret_access_key: String::from("SEC123RET/NEW"),
+            secret_access_key: Secret::from("SEC123RET/NEW"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         let original = r##"[default]
@@ -422,7 +465,7 @@ aws_access_key_id = AACCCCEESSSSKKEEYY/OLD
 aws_secret_access_key = SEC123RET/OLD"##;
 
         // Replacing the last section leaves a single trailing newline (no extra blank line).
-        let updated = update_profile(original, &profile);
+        let updated = update_profile(original, &profile).to_string();
         assert_eq!(
             updated,
             r##"[default]
@@ -445,9 +488,10 @@ aws_secret_access_key = SEC123RET/NEW
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY/NEW"),
-            secret_access_key: String::from("SEC123RET/NEW"),
+            secret_access_key: Secret::from("SEC123RET/NEW"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         let original = r##"[default]
@@ -462,8 +506,8 @@ aws_secret_access_key = SEC123RET/OLD
 aws_access_key_id = AACCCCEESSSSKKEEYY/PROD
 aws_secret_access_key = SEC123RET/PROD"##;
 
-        let updated_first = update_profile(original, &profile);
-        let updated = update_profile(&updated_first, &profile);
+        let updated_first = update_profile(original, &profile).to_string();
+        let updated = update_profile(&updated_first, &profile).to_string();
         assert_eq!(
             updated,
             r##"[default]
@@ -485,9 +529,10 @@ aws_secret_access_key = SEC123RET/PROD"##
         let profile = Profile {
             name: String::from("session-production"),
             access_key_id: String::from("AACCCCEESSSSKKEEYY/NEW"),
-            secret_access_key: String::from("SEC123RET/NEW"),
+            secret_access_key: Secret::from("SEC123RET/NEW"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         let original = r##"[default]
@@ -503,8 +548,8 @@ aws_access_key_id = AACCCCEESSSSKKEEYY/OLD
 aws_secret_access_key = SEC123RET/OLD"##;
 
         // Idempotent, and the last-section replace keeps a single trailing newline.
-        let updated_first = update_profile(original, &profile);
-        let updated = update_profile(&updated_first, &profile);
+        let updated_first = update_profile(original, &profile).to_string();
+        let updated = update_profile(&updated_first, &profile).to_string();
         assert_eq!(
             updated,
             r##"[default]
@@ -527,29 +572,42 @@ aws_secret_access_key = SEC123RET/NEW
         let profile = Profile {
             name: String::from("test-session"),
             access_key_id: String::from("AKIATEST"),
-            secret_access_key: String::from("secret123"),
-            session_token: Some(String::from("token456")),
+            secret_access_key: Secret::from("secret123"),
+            session_token: Some(Secret::from("token456")),
             region: Some(String::from("us-west-2")),
+            expiration: None,
         };
 
-        let config_section = profile.config_section();
+        let config_section = profile.config_section().to_string();
         assert!(config_section.contains("aws_access_key_id = AKIATEST"));
         assert!(config_section.contains("aws_secret_access_key = secret123"));
         assert!(config_section.contains("aws_session_token = token456"));
         assert!(config_section.contains("region = us-west-2"));
     }
 
+    #[test]
+    fn test_profile_with_expiration() {
+        let profile = Profile {
+            expiration: Some("2024-01-01T13:00:00Z".to_string()),
+            ..sample_profile("session")
+        };
+        assert!(profile.config_section().contains(
+            "aws_session_token = token\naws_session_expiration = 2024-01-01T13:00:00Z\n"
+        ));
+    }
+
     #[test]
     fn test_profile_without_optional_fields() {
         let profile = Profile {
             name: String::from("minimal-profile"),
             access_key_id: String::from("AKIATEST"),
-            secret_access_key: String::from("secret123"),
+            secret_access_key: Secret::from("secret123"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
-        let config_section = profile.config_section();
+        let config_section = profile.config_section().to_string();
         assert!(config_section.contains("aws_access_key_id = AKIATEST"));
         assert!(config_section.contains("aws_secret_access_key = secret123"));
         assert!(!config_section.contains("aws_session_token"));
@@ -561,12 +619,13 @@ aws_secret_access_key = SEC123RET/NEW
         let profile = Profile {
             name: String::from("special-chars"),
             access_key_id: String::from("AKIA/TEST+KEY="),
-            secret_access_key: String::from("secret/with+special=chars"),
-            session_token: Some(String::from("token/with+special=chars")),
+            secret_access_key: Secret::from("secret/with+special=chars"),
+            session_token: Some(Secret::from("token/with+special=chars")),
             region: Some(String::from("us-east-1")),
+            expiration: None,
         };
 
-        let updated = update_profile("", &profile);
+        let updated = update_profile("", &profile).to_string();
         assert!(updated.contains("AKIA/TEST+KEY="));
         assert!(updated.contains("secret/with+special=chars"));
         assert!(updated.contains("token/with+special=chars"));
@@ -577,12 +636,13 @@ aws_secret_access_key = SEC123RET/NEW
         let profile = Profile {
             name: String::from("empty-test"),
             access_key_id: String::from(""),
-            secret_access_key: String::from(""),
-            session_token: Some(String::from("")),
+            secret_access_key: Secret::from(""),
+            session_token: Some(Secret::from("")),
             region: Some(String::from("")),
+            expiration: None,
         };
 
-        let updated = update_profile("", &profile);
+        let updated = update_profile("", &profile).to_string();
         assert!(updated.contains("[empty-test]"));
         assert!(updated.contains("aws_access_key_id = "));
         assert!(updated.contains("aws_secret_access_key = "));
@@ -611,14 +671,24 @@ aws_secret_access_key = SEC123RET/NEW
         assert!(path_str.ends_with(".aws\\credentials"));
     }
 
+    #[test]
+    fn test_config_section_fills_exact_buffer() {
+        // Sized up front, so no reallocation leaves a copy of the secrets.
+        let section = sample_profile("session").config_section();
+        assert_eq!(section.capacity(), section.len());
+        let updated = update_profile("[other]\nkey = value", &sample_profile("session"));
+        assert!(updated.ends_with(section.as_str()));
+    }
+
     #[test]
     fn test_config_section_header() {
         let profile = Profile {
             name: String::from("test-profile"),
             access_key_id: String::from("key"),
-            secret_access_key: String::from("secret"),
+            secret_access_key: Secret::from("secret"),
             session_token: None,
             region: None,
+            expiration: None,
         };
 
         assert_eq!(profile.config_section_header(), "[test-profile]");
@@ -637,7 +707,7 @@ aws_secret_access_key = DEFAULTSECRET
 aws_access_key_id = OLD
 aws_secret_access_key = OLD
 ";
-        let updated = update_profile(original, &sample_profile("session"));
+        let updated = update_profile(original, &sample_profile("session")).to_string();
         // Untouched section (and its comments) survive byte-for-byte.
         assert!(updated.contains(
             "# top comment\n[default]\naws_access_key_id = DEFAULTKEY\n; keep this comment between keys\naws_secret_access_key = DEFAULTSECRET\n"
@@ -658,7 +728,7 @@ aws_secret_access_key = PRODSECRET
 aws_access_key_id = PRODUCTIONKEY
 aws_secret_access_key = PRODUCTIONSECRET
 ";
-        let updated = update_profile(original, &sample_profile("prod"));
+        let updated = update_profile(original, &sample_profile("prod")).to_string();
         assert!(updated.contains("[prod]\naws_access_key_id = AKIATEST\n"));
         // [production] must be untouched even though [prod] is a prefix of it.
         assert!(updated.contains(
@@ -677,7 +747,7 @@ aws_secret_access_key = PRODUCTIONSECRET
 aws_access_key_id = DEFAULTKEY
 aws_secret_access_key = DEFAULTSECRET
 ";
-        let updated = update_profile(original, &sample_profile("session"));
+        let updated = update_profile(original, &sample_profile("session")).to_string();
         assert!(updated.contains("# remember to rotate [session] keys"));
         // The profile is appended; the only real `[session]` header is the new one.
         assert_eq!(updated.matches("[session]\n").count(), 1);
@@ -691,12 +761,13 @@ aws_secret_access_key = DEFAULTSECRET
         let profile = Profile {
             name: "session".to_string(),
             access_key_id: "AKIATEST".to_string(),
-            secret_access_key: "se$1cret".to_string(),
-            session_token: Some("tok${en}".to_string()),
+            secret_access_key: Secret::from("se$1cret"),
+            session_token: Some(Secret::from("tok${en}")),
             region: Some("us-$0-1".to_string()),
+            expiration: None,
         };
         let original = "[session]\naws_access_key_id = OLD\naws_secret_access_key = OLD\n";
-        let updated = update_profile(original, &profile);
+        let updated = update_profile(original, &profile).to_string();
         assert!(
             updated.contains("aws_secret_access_key = se$1cret"),
             "got:\n{updated}"
@@ -708,7 +779,7 @@ aws_secret_access_key = DEFAULTSECRET
     #[test]
     fn test_update_profile_replace_last_without_trailing_newline() {
         let original = "[default]\naws_access_key_id = D\n\n[session]\naws_access_key_id = OLD\naws_secret_access_key = OLD";
-        let updated = update_profile(original, &sample_profile("session"));
+        let updated = update_profile(original, &sample_profile("session")).to_string();
         assert!(updated.contains("[default]\naws_access_key_id = D\n"));
         assert!(updated.contains("[session]\naws_access_key_id = AKIATEST\n"));
         assert!(!updated.contains("OLD"));
@@ -720,7 +791,7 @@ aws_secret_access_key = DEFAULTSECRET
         // A `[` that is not at the start of a line is not a section boundary, so
         // the span scan must run past it to the real next header ([session]).
         let original = "[default]\naws_access_key_id = OLDKEY\nnote = see [archive] dump\naws_secret_access_key = OLDSECRET\n\n[session]\naws_access_key_id = KEEP\n";
-        let updated = update_profile(original, &sample_profile("default"));
+        let updated = update_profile(original, &sample_profile("default")).to_string();
         // [default] replaced wholesale (note line gone); [session] untouched.
         assert!(!updated.contains("note = see [archive] dump"));
         assert!(!updated.contains("OLDKEY"));
@@ -732,7 +803,7 @@ aws_secret_access_key = DEFAULTSECRET
     fn test_update_profile_handles_crlf_in_untouched_sections() {
         let original =
             "[default]\r\naws_access_key_id = D\r\n\r\n[session]\r\naws_access_key_id = OLD\r\n";
-        let updated = update_profile(original, &sample_profile("session"));
+        let updated = update_profile(original, &sample_profile("session")).to_string();
         // CRLF content outside the target is preserved verbatim.
         assert!(updated.contains("[default]\r\naws_access_key_id = D\r\n"));
         assert!(updated.contains("[session]\naws_access_key_id = AKIATEST\n"));
@@ -742,8 +813,8 @@ aws_secret_access_key = DEFAULTSECRET
     #[test]
     fn test_update_profile_append_then_replace_is_idempotent() {
         let base = "[default]\naws_access_key_id = D\naws_secret_access_key = S\n";
-        let once = update_profile(base, &sample_profile("session"));
-        let twice = update_profile(&once, &sample_profile("session"));
+        let once = update_profile(base, &sample_profile("session")).to_string();
+        let twice = update_profile(&once, &sample_profile("session")).to_string();
         assert_eq!(once, twice);
         assert!(twice.contains("[default]\naws_access_key_id = D\n"));
         assert_eq!(twice.matches("[session]\n").count(), 1);
@@ -769,6 +840,27 @@ aws_secret_access_key = DEFAULTSECRET
         assert_mode(&path, 0o600, "newly created credentials file must be 0600");
     }
 
+    #[test]
+    #[serial]
+    fn test_update_credentials_all_writes_every_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials");
+        std::fs::write(&path, "[dev]\naws_access_key_id = AKIADEV\n").unwrap();
+
+        unsafe { std::env::set_var(AWS_SHARED_CREDENTIALS_FILE, &path) };
+        let result = update_credentials_all(&[
+            sample_profile("dev-session"),
+            sample_profile("prod-session"),
+        ]);
+        unsafe { std::env::remove_var(AWS_SHARED_CREDENTIALS_FILE) };
+        result.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("[dev]\naws_access_key_id = AKIADEV\n"));
+        assert_eq!(written.matches("[dev-session]\n").count(), 1);
+        assert_eq!(written.matches("[prod-session]\n").count(), 1);
+    }
+
     #[test]
     #[serial]
     fn test_update_credentials_enforces_0600_permissions() {
@@ -18,6 +18,10 @@ pub enum CliError {
     SdkError(String),
     #[error("IOError: {0}")]
     IoError(#[from] std::io::Error),
+    /// A command run for the session (`exec --docker`) failed with this exit
+    /// status, which the binary exits with in turn.
+    #[error("Command exited with status {0}")]
+    CommandFailed(i32),
 }
 
 // thiserror's `#[from]` only generates `From` for a concrete type, so the
@@ -49,6 +53,9 @@ mod tests {
 
         let no_account_error = CliError::NoAccount;
         assert_eq!(no_account_error.to_string(), "No returned account");
+
+        let command_error = CliError::CommandFailed(125);
+        assert_eq!(command_error.to_string(), "Command exited with status 125");
     }
 
     #[test]
@@ -116,6 +123,7 @@ mod tests {
             CliError::NoAccount,
             CliError::SdkError("SDK error".to_string()),
             CliError::IoError(std::io::Error::other("test")),
+            CliError::CommandFailed(1),
         ];
 
         for error in errors {
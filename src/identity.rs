@@ -0,0 +1,102 @@
+/// The caller behind a set of credentials, as reported by `GetCallerIdentity`.
+///
+/// Everything is derived from the returned ARN, so no IAM permission is needed
+/// and non-IAM-user principals (assumed roles, federated users, root) work too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub account: String,
+    pub arn: String,
+    /// Display name: the IAM user name, the role session name, the federated
+    /// user name, or `root`.
+    pub user: String,
+    /// Role name when the caller is an assumed role.
+    pub role: Option<String>,
+}
+
+impl Identity {
+    pub fn from_arn(account: &str, arn: &str) -> Self {
+        // arn:partition:service:region:account:resource — the resource part may
+        // itself contain ':' (never for the principals below, but be lenient).
+        let resource = arn.splitn(6, ':').nth(5).unwrap_or(arn);
+        let mut parts = resource.split('/');
+        let kind = parts.next().unwrap_or_default();
+        let rest: Vec<&str> = parts.collect();
+
+        let (user, role) = match (kind, rest.as_slice()) {
+            // arn:aws:sts::123:assumed-role/RoleName/session-name
+            ("assumed-role", [role, .., session]) => {
+                ((*session).to_owned(), Some((*role).to_owned()))
+            }
+            // arn:aws:iam::123:user/optional/path/name,
+            // arn:aws:sts::123:federated-user/name
+            ("user" | "federated-user", [.., name]) => ((*name).to_owned(), None),
+            // arn:aws:iam::123:root
+            ("root", []) => ("root".to_owned(), None),
+            _ => (resource.to_owned(), None),
+        };
+
+        Identity {
+            account: account.to_owned(),
+            arn: arn.to_owned(),
+            user,
+            role,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_from_user_arn() {
+        let id = Identity::from_arn("123456789012", "arn:aws:iam::123456789012:user/alice");
+        assert_eq!(id.user, "alice");
+        assert_eq!(id.role, None);
+        assert_eq!(id.account, "123456789012");
+    }
+
+    #[test]
+    fn test_identity_from_user_arn_with_path() {
+        let id = Identity::from_arn(
+            "123456789012",
+            "arn:aws:iam::123456789012:user/division/team/alice",
+        );
+        assert_eq!(id.user, "alice");
+        assert_eq!(id.role, None);
+    }
+
+    #[test]
+    fn test_identity_from_assumed_role_arn() {
+        let id = Identity::from_arn(
+            "123456789012",
+            "arn:aws:sts::123456789012:assumed-role/AdminRole/alice@example.com",
+        );
+        assert_eq!(id.user, "alice@example.com");
+        assert_eq!(id.role, Some("AdminRole".to_string()));
+    }
+
+    #[test]
+    fn test_identity_from_federated_user_arn() {
+        let id = Identity::from_arn(
+            "123456789012",
+            "arn:aws:sts::123456789012:federated-user/bob",
+        );
+        assert_eq!(id.user, "bob");
+        assert_eq!(id.role, None);
+    }
+
+    #[test]
+    fn test_identity_from_root_arn() {
+        let id = Identity::from_arn("123456789012", "arn:aws:iam::123456789012:root");
+        assert_eq!(id.user, "root");
+        assert_eq!(id.role, None);
+    }
+
+    #[test]
+    fn test_identity_from_unknown_arn_falls_back_to_resource() {
+        let id = Identity::from_arn("123456789012", "arn:aws:iam::123456789012:something/else");
+        assert_eq!(id.user, "something/else");
+        assert_eq!(Identity::from_arn("1", "not-an-arn").user, "not-an-arn");
+    }
+}
@@ -0,0 +1,273 @@
+use crate::error::CliError;
+use crate::server::{CredentialCache, Handler, Request, Response, random_token};
+use crate::session::SessionSource;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use aws_sdk_sts::primitives::{DateTime, DateTimeFormat};
+use serde::Serialize;
+
+/// Variable the SDKs read to find the instance metadata service.
+pub const AWS_EC2_METADATA_SERVICE_ENDPOINT: &str = "AWS_EC2_METADATA_SERVICE_ENDPOINT";
+
+const TOKEN_PATH: &str = "/latest/api/token";
+const CREDENTIALS_PREFIX: &str = "/latest/meta-data/iam/security-credentials/";
+const TOKEN_HEADER: &str = "X-aws-ec2-metadata-token";
+const TTL_HEADER: &str = "X-aws-ec2-metadata-token-ttl-seconds";
+// IMDSv2 limit: session tokens live at most six hours.
+const MAX_TOKEN_TTL: u64 = 21600;
+
+/// Body of `iam/security-credentials/<role>`, as EC2 returns it.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct InstanceCredentials<'a> {
+    code: &'static str,
+    last_updated: String,
+    #[serde(rename = "Type")]
+    kind: &'static str,
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    token: &'a str,
+    expiration: String,
+}
+
+/// Emulates the IMDSv2 endpoints the SDK credential providers use: a session
+/// token from `PUT /latest/api/token`, then the role list and the role's
+/// credentials, both requiring that token.
+pub struct ImdsHandler<S> {
+    cache: Arc<CredentialCache<S>>,
+    role: String,
+    tokens: Mutex<HashMap<String, Instant>>,
+}
+
+impl<S: SessionSource> ImdsHandler<S> {
+    pub fn new(cache: Arc<CredentialCache<S>>, role: String) -> Self {
+        ImdsHandler {
+            cache,
+            role,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn issue_token(&self, request: &Request) -> Response {
+        if request.method != "PUT" {
+            return Response::text(405, String::new());
+        }
+        // As on EC2: a forwarded token request came through a proxy, which
+        // IMDSv2 exists to keep out.
+        if request.header("X-Forwarded-For").is_some() {
+            return Response::text(403, String::new());
+        }
+        let ttl = match request.header(TTL_HEADER).map(str::parse::<u64>) {
+            Some(Ok(ttl)) if (1..=MAX_TOKEN_TTL).contains(&ttl) => ttl,
+            _ => return Response::text(400, String::new()),
+        };
+        let token = match random_token() {
+            Ok(token) => token,
+            Err(e) => return Response::text(500, e.to_string()),
+        };
+
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, expiry| *expiry > now);
+        tokens.insert(token.clone(), now + Duration::from_secs(ttl));
+
+        let mut response = Response::text(200, token);
+        response.headers.push((TTL_HEADER, ttl.to_string()));
+        response
+    }
+
+    fn token_valid(&self, request: &Request) -> bool {
+        request.header(TOKEN_HEADER).is_some_and(|token| {
+            self.tokens
+                .lock()
+                .unwrap()
+                .get(token)
+                .is_some_and(|expiry| *expiry > Instant::now())
+        })
+    }
+
+    async fn credentials(&self) -> Response {
+        let session = match self.cache.get().await {
+            Ok(session) => session,
+            Err(e) => {
+                tracing::warn!(error = %e, "cannot serve credentials");
+                return Response::text(500, String::new());
+            }
+        };
+        let body = session.expiration_rfc3339().and_then(|expiration| {
+            let last_updated = DateTime::from(SystemTime::now())
+                .fmt(DateTimeFormat::DateTime)
+                .unwrap_or_default();
+            serde_json::to_string(&InstanceCredentials {
+                code: "Success",
+                last_updated,
+                kind: "AWS-HMAC",
+                access_key_id: &session.access_key_id,
+                secret_access_key: session.secret_access_key.expose(),
+                token: session.session_token.expose(),
+                expiration,
+            })
+            .map_err(|e| CliError::ValidationError(e.to_string()))
+        });
+        match body {
+            // EC2 serves this as text/plain too, but the SDKs only parse the body.
+            Ok(body) => Response::json(body),
+            Err(e) => Response::text(500, e.to_string()),
+        }
+    }
+}
+
+impl<S: SessionSource> Handler for ImdsHandler<S> {
+    async fn handle(&self, request: &Request) -> Response {
+        if request.path == TOKEN_PATH {
+            return self.issue_token(request);
+        }
+        if request.method != "GET" {
+            return Response::text(405, String::new());
+        }
+        if !self.token_valid(request) {
+            return Response::text(401, String::new());
+        }
+        match request.path.strip_prefix(CREDENTIALS_PREFIX) {
+            Some("") => Response::text(200, self.role.clone()),
+            Some(role) if role == self.role => self.credentials().await,
+            _ => Response::text(404, String::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::serve_tcp;
+    use crate::session::tests::FakeSource;
+    use aws_credential_types::provider::ProvideCredentials;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn start() -> SocketAddr {
+        let source = FakeSource {
+            minted: AtomicUsize::new(0),
+            lifetime: 3600,
+        };
+        let cache = Arc::new(CredentialCache::new(source, 300));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(ImdsHandler::new(cache, "mfa-role".to_string()));
+        tokio::spawn(serve_tcp(listener, handler));
+        addr
+    }
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, headers: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        (status, response)
+    }
+
+    async fn token(addr: SocketAddr) -> String {
+        let (status, response) =
+            request(addr, "PUT", TOKEN_PATH, &format!("{TTL_HEADER}: 60\r\n")).await;
+        assert_eq!(status, 200);
+        assert!(response.contains(&format!("{TTL_HEADER}: 60\r\n")));
+        response.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_token_required() {
+        let addr = start().await;
+        let (status, _) = request(addr, "GET", CREDENTIALS_PREFIX, "").await;
+        assert_eq!(status, 401);
+        let (status, _) = request(
+            addr,
+            "GET",
+            CREDENTIALS_PREFIX,
+            &format!("{TOKEN_HEADER}: forged\r\n"),
+        )
+        .await;
+        assert_eq!(status, 401);
+
+        let token = token(addr).await;
+        let (status, response) = request(
+            addr,
+            "GET",
+            CREDENTIALS_PREFIX,
+            &format!("{TOKEN_HEADER}: {token}\r\n"),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(response.ends_with("\r\n\r\nmfa-role"));
+    }
+
+    #[tokio::test]
+    async fn test_token_request_validation() {
+        let addr = start().await;
+        assert_eq!(request(addr, "PUT", TOKEN_PATH, "").await.0, 400);
+        assert_eq!(
+            request(addr, "PUT", TOKEN_PATH, &format!("{TTL_HEADER}: 0\r\n"))
+                .await
+                .0,
+            400
+        );
+        assert_eq!(
+            request(addr, "PUT", TOKEN_PATH, &format!("{TTL_HEADER}: 21601\r\n"))
+                .await
+                .0,
+            400
+        );
+        assert_eq!(
+            request(addr, "GET", TOKEN_PATH, &format!("{TTL_HEADER}: 60\r\n"))
+                .await
+                .0,
+            405
+        );
+        let forwarded = format!("{TTL_HEADER}: 60\r\nX-Forwarded-For: 10.0.0.1\r\n");
+        assert_eq!(request(addr, "PUT", TOKEN_PATH, &forwarded).await.0, 403);
+    }
+
+    #[tokio::test]
+    async fn test_serves_role_credentials() {
+        let addr = start().await;
+        let token = token(addr).await;
+        let header = format!("{TOKEN_HEADER}: {token}\r\n");
+
+        let path = format!("{CREDENTIALS_PREFIX}mfa-role");
+        let (status, response) = request(addr, "GET", &path, &header).await;
+        assert_eq!(status, 200);
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["Code"], "Success");
+        assert_eq!(json["Type"], "AWS-HMAC");
+        assert_eq!(json["AccessKeyId"], "ASIA1");
+        assert!(json["Expiration"].as_str().unwrap().ends_with('Z'));
+
+        let path = format!("{CREDENTIALS_PREFIX}other-role");
+        assert_eq!(request(addr, "GET", &path, &header).await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_sdk_imds_provider_reads_credentials() {
+        // The SDK's own IMDS credentials provider is the client legacy tools use.
+        let addr = start().await;
+        let client = aws_config::imds::Client::builder()
+            .endpoint(format!("http://{addr}"))
+            .unwrap()
+            .build();
+        let provider = aws_config::imds::credentials::ImdsCredentialsProvider::builder()
+            .imds_client(client)
+            .build();
+        let credentials = provider.provide_credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "ASIA1");
+        assert_eq!(credentials.secret_access_key(), "secret");
+        assert_eq!(credentials.session_token(), Some("token"));
+        assert!(credentials.expiry().is_some());
+    }
+}
@@ -1,23 +1,49 @@
+#[cfg(unix)]
+mod agent;
+mod alias;
 mod args;
+mod audit;
+mod cache;
+mod cli_cache;
+mod config;
 mod credentials;
 mod error;
+mod identity;
+mod imds;
+pub mod logging;
+mod output;
 mod profile;
+mod prompt;
+mod secret;
+mod server;
+mod session;
 mod shell;
+mod storage;
 
-pub use args::Args;
-use credentials::*;
-use error::CliError;
+#[cfg(unix)]
+use agent::{AWS_MFA_SESSION_AGENT_PID, AWS_MFA_SESSION_AGENT_SOCK, Agent, AgentClient};
+pub use args::{Args, Commands};
+use args::{ask_code, ask_code_interactive};
+use audit::{AuditLog, Auditor};
+use config::Config;
+pub use error::CliError;
+use imds::{AWS_EC2_METADATA_SERVICE_ENDPOINT, ImdsHandler};
+use logging::status;
 pub use profile::get_mfa_serial_from_profile;
-use shell::Shell;
+use prompt::{DEFAULT_PROMPT, Prompt, PromptValues, validate_template};
+pub use secret::Secret;
+use server::{CREDENTIALS_PATH, CredentialCache, EcsHandler, Listen};
+use session::{PromptingSource, Session, SessionMinter};
+use shell::{AWS_MFA_SESSION, AWS_MFA_SESSION_EXPIRES_AT, Shell};
+use storage::{SessionStore, StorageKind};
 
-use std::collections::HashMap;
 use std::env;
 use std::io;
-use std::process::Command;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use aws_config::{BehaviorVersion, Region, meta::credentials::CredentialsProviderChain};
 use aws_sdk_iam::Client;
-use aws_sdk_sts::Client as StsClient;
 
 #[cfg(not(target_os = "windows"))]
 const DEFAULT_SHELL: &str = "/bin/sh";
@@ -26,22 +52,76 @@ const DEFAULT_SHELL: &str = "/bin/sh";
 const DEFAULT_SHELL: &str = "cmd.exe";
 
 const AWS_PROFILE: &str = "AWS_PROFILE";
-const AWS_DEFAULT_REGION: &str = "AWS_DEFAULT_REGION";
 
 const AWS_SHARED_CREDENTIALS_FILE: &str = "AWS_SHARED_CREDENTIALS_FILE";
 
-pub async fn run(opts: Args) -> Result<(), CliError> {
+// Both names are in use: the former by several CLIs and prompt themes, the
+// latter by the AWS SDKs' process/container credential conventions.
+const AWS_SESSION_EXPIRATION: &str = "AWS_SESSION_EXPIRATION";
+const AWS_CREDENTIAL_EXPIRATION: &str = "AWS_CREDENTIAL_EXPIRATION";
+
+// The SDKs refresh process credentials this long before they expire; a stored
+// session with less left would be requested again straight away.
+const STORED_MIN_REMAINING: i64 = 15 * 60;
+
+#[tracing::instrument(skip_all)]
+pub async fn run(mut opts: Args) -> Result<(), CliError> {
+    // The MFA code is redacted in `Args`' Debug output.
+    tracing::debug!(?opts, "arguments");
+    opts.apply_refresh()?;
+    let refresh = opts.command == Some(Commands::Refresh);
+
     // Validate inputs before touching AWS — and before the single-use MFA code
     // is spent on a session token. Bail if there is no output mode to consume
     // the credentials, or if no MFA code is available (a library caller may not
     // have run get_code()); the latter previously panicked via `.expect`.
     opts.ensure_output_mode()?;
-    let token_code = opts
-        .code
-        .ok_or_else(|| CliError::ValidationError("MFA code is required".to_string()))?;
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_owned());
+    // Only output that is shell code needs to know the shell: an unknown one
+    // fails here rather than after the MFA code is spent.
+    let shell_kind = match opts.shell_type {
+        Some(kind) => kind,
+        None if opts.uses_shell_syntax() => Shell::detect(&shell)?,
+        None => Shell::default(),
+    };
+    if opts.shell {
+        shell_kind.ensure_init_script()?;
+    }
+    if let Some(Commands::Agent { socket, foreground }) = opts.command {
+        return run_agent(socket, foreground, shell_kind).await;
+    }
+    if let Some(Commands::History { limit, json }) = opts.command {
+        return history(opts.profile(), limit, json);
+    }
+    if opts.command == Some(Commands::Unset) {
+        let mut names: Vec<_> = CREDENTIAL_VARS.to_vec();
+        names.push(AWS_MFA_SESSION_EXPIRES_AT);
+        return Ok(shell_kind.unset(&mut io::stdout().lock(), &names)?);
+    }
+    // env/exec ask for a code only once they know the agent has no session,
+    // and several profiles ask for one per MFA device.
+    let deferred = opts.command.as_ref().is_some_and(Commands::defers_code);
+    let code = opts.code.take();
+    if code.is_none() && !deferred && !opts.is_multi_profile() {
+        return Err(CliError::ValidationError(
+            "MFA code is required".to_string(),
+        ));
+    }
+
+    if refresh {
+        // Inside a --shell session these hold the expiring session credentials,
+        // which STS refuses for GetSessionToken. Drop them so the default chain
+        // resolves the profile's long-term credentials again.
+        // SAFETY: single-threaded context, before any AWS client is built
+        unsafe {
+            env::remove_var("AWS_ACCESS_KEY_ID");
+            env::remove_var("AWS_SECRET_ACCESS_KEY");
+            env::remove_var("AWS_SESSION_TOKEN");
+        }
+    }
 
     // ProfileProvider is limited, but AWS_PROFILE is used elsewhere
-    if let Some(ref profile) = opts.profile {
+    if let Some(profile) = opts.profile() {
         // SAFETY: Setting AWS_PROFILE environment variable is safe in this single-threaded context
         // and doesn't interfere with other parts of the application
         unsafe {
@@ -49,7 +129,7 @@ pub async fn run(opts: Args) -> Result<(), CliError> {
         }
     }
 
-    if let Some(file) = opts.credentials_file {
+    if let Some(file) = opts.credentials_file.take() {
         // SAFETY: Setting AWS_SHARED_CREDENTIALS_FILE environment variable is safe in this
         // single-threaded context and doesn't interfere with other parts of the application
         unsafe {
@@ -57,111 +137,737 @@ pub async fn run(opts: Args) -> Result<(), CliError> {
         }
     }
 
-    let region_provider =
-        aws_config::meta::region::RegionProviderChain::first_try(opts.region.clone())
-            .or_default_provider()
-            .or_else(env::var(AWS_DEFAULT_REGION).ok().map(Region::new))
-            .or_else(Region::new("us-east-1"));
-
-    let credentials_provider = CredentialsProviderChain::default_provider().await;
-    let shared_config = aws_config::defaults(BehaviorVersion::latest())
-        .region(region_provider)
-        .credentials_provider(credentials_provider)
-        .load()
-        .await;
+    if opts.is_multi_profile() {
+        return login_profiles(&opts, code).await;
+    }
 
-    let iam_client = Client::new(&shared_config);
-    let serial_number = match opts.arn {
-        None => {
-            // First, try to get mfa_serial from profile configuration
-            if let Some(mfa_serial) = get_mfa_serial_from_profile(opts.profile.as_deref()) {
-                mfa_serial
-            } else {
-                // Fallback to automatic MFA device detection
-                let response = iam_client.list_mfa_devices().max_items(1).send().await?;
-                let mfa_devices = response.mfa_devices();
-                let serial = &mfa_devices.first().ok_or(CliError::NoMFA)?.serial_number();
-                (*serial).to_owned()
-            }
-        }
-        Some(other) => other,
-    };
+    let resolve_span = tracing::info_span!("resolve_profile").entered();
+    let profile_name = env::var(AWS_PROFILE).ok();
+    let config = Config::load()?;
+    tracing::info!(
+        profile = profile_name.as_deref().unwrap_or("default"),
+        "resolved profile"
+    );
+    let storage = configured_storage(opts.storage, &config, profile_name.as_deref())?;
+    let store = storage
+        .unwrap_or_default()
+        .store(&config, profile_name.as_deref())?;
+    tracing::debug!(storage = ?storage.unwrap_or_default(), "session storage");
+    drop(resolve_span);
+    // Name of the cached session behind credential-process, env and exec, so
+    // it never replaces the profile's long-term keys.
+    let cached_name = format!(
+        "{}-mfa-session",
+        profile_name.as_deref().unwrap_or("default")
+    );
 
-    let sts_client = StsClient::new(&shared_config);
+    if let Some(Commands::CredentialProcess { ref name }) = opts.command {
+        let name = name.as_deref().unwrap_or(&cached_name);
+        let session = stored_session(&opts, code, store.as_ref(), name).await?;
+        println!("{}", storage::credential_process_json(&session)?);
+        return Ok(());
+    }
+    if deferred {
+        // Only a store chosen explicitly is used as a cache here: by default
+        // env/exec never write sessions to disk.
+        let cache = storage.map(|_| (store.as_ref(), cached_name.as_str()));
+        let session = agent_session(&opts, code, cache).await?;
+        let expiration = session.expiration_rfc3339()?;
+        let vars = credential_vars(&session, &expiration);
+        return match opts.command {
+            Some(Commands::Exec {
+                ref command,
+                docker: true,
+            }) => docker_run(command, &vars),
+            Some(Commands::Exec { ref command, .. }) => exec(command, &vars),
+            _ => Ok(shell_kind.export_vars(&mut io::stdout().lock(), &vars)?),
+        };
+    }
+    let token_code = code.unwrap_or_default();
 
-    let credentials = sts_client
-        .get_session_token()
-        .set_serial_number(Some(serial_number))
-        .token_code(token_code)
-        .duration_seconds(opts.duration)
-        .send()
-        .await?
-        .credentials()
-        .map(ToOwned::to_owned)
-        .ok_or(CliError::NoCredentials)?;
+    match opts.command {
+        Some(Commands::Serve {
+            ref listen,
+            refresh_before,
+        }) => {
+            let cache = session_cache(&opts, token_code, refresh_before).await?;
+            return serve(cache, listen.clone(), shell_kind).await;
+        }
+        Some(Commands::Imds {
+            listen,
+            ref role_name,
+            refresh_before,
+        }) => {
+            let cache = session_cache(&opts, token_code, refresh_before).await?;
+            return imds(cache, listen, role_name.clone(), shell_kind).await;
+        }
+        _ => {}
+    }
 
-    let identity = sts_client.get_caller_identity().send().await?;
+    // Resolve the prompt settings up front so a bad config fails before the
+    // MFA code is spent.
+    let audit = auditor(&opts)?;
+    let template = match opts.prompt.take() {
+        Some(template) => template,
+        None => validate_template(
+            config
+                .get(profile_name.as_deref(), "prompt")
+                .unwrap_or(DEFAULT_PROMPT),
+        )?,
+    };
+    let prompt_prepend = opts.prompt_prepend
+        || config
+            .get_bool(profile_name.as_deref(), "prompt_prepend")?
+            .unwrap_or(false);
+    let lookup_alias = opts.lookup_alias
+        || config
+            .get_bool(profile_name.as_deref(), "lookup_account_alias")?
+            .unwrap_or(false);
 
-    let user = iam_client
-        .get_user()
-        .send()
-        .await?
-        .user()
-        .map(ToOwned::to_owned)
-        .ok_or(CliError::NoAccount)?;
+    let minter = SessionMinter::new(
+        session::load_config(opts.region.clone()).await,
+        opts.arn.clone(),
+        opts.profile(),
+        opts.duration,
+    )
+    .await?
+    .with_audit(audit);
+    let session = minter.mint(token_code.expose()).await?;
+    let identity = minter.identity().await?.clone();
+    let shared_config = minter.config();
+    let account_alias = alias::account_alias(
+        &config,
+        profile_name.as_deref(),
+        &identity.account,
+        lookup_alias,
+        || Client::new(shared_config),
+    )
+    .await;
 
-    let account = identity.account.ok_or(CliError::NoAccount)?;
-    let ps = format!("AWS:{}@{} \\$ ", user.user_name(), account);
-    let shell = std::env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_owned());
+    let region = shared_config.region().map(|r| r.to_string());
+    let values = PromptValues {
+        user: identity.user,
+        account: identity.account,
+        account_alias,
+        profile: profile_name.unwrap_or_else(|| "default".to_string()),
+        region: region.clone(),
+        expires_in: session.expires_in(),
+        role: identity.role,
+    };
+    let marker = values.profile.clone();
+    let prompt = Prompt::new(&template, values, prompt_prepend)?;
+    let expiration = session.expiration_rfc3339()?;
+    let expires_at = session.expiration.secs().to_string();
 
-    if let Some(name) = opts.session_profile {
-        let c = credentials.clone();
-        let profile = Profile {
-            name,
-            access_key_id: c.access_key_id().to_owned(),
-            secret_access_key: c.secret_access_key().to_owned(),
-            session_token: Some(c.session_token().to_owned()),
-            // Record the region the session was actually minted under (resolved
-            // from --region, env, profile, or the default) so the written
-            // profile is self-contained, not only when --region was passed.
-            region: shared_config.region().map(|r| r.to_string()),
-        };
-        update_credentials(&profile)?;
+    if let Some(name) = &opts.session_profile {
+        let name = name.replace("{profile}", &marker);
+        // Record the region the session was actually minted under (resolved
+        // from --region, env, profile, or the default) so the written profile
+        // is self-contained, not only when --region was passed.
+        store.save(&name, &session, region.as_deref())?;
+    }
+    let output_options = opts.output_options();
+    for output in &opts.outputs {
+        output.write(&credential_vars(&session, &expiration), &output_options)?;
+    }
+    for profile in &opts.cli_cache {
+        let path = cli_cache::store(shared_config, &session, profile).await?;
+        status!("cached {profile} credentials in {}", path.display());
     }
 
     if opts.shell {
-        let c = credentials.clone();
-        let envs: HashMap<&str, String> = [
-            ("AWS_ACCESS_KEY_ID", c.access_key_id().to_owned()),
-            ("AWS_SECRET_ACCESS_KEY", c.secret_access_key().to_owned()),
-            ("AWS_SESSION_TOKEN", c.session_token().to_owned()),
-            ("PS1", ps.clone()),
+        let envs = [
+            credential_vars(&session, &expiration).as_slice(),
+            &[
+                (AWS_MFA_SESSION, &marker),
+                (AWS_MFA_SESSION_EXPIRES_AT, &expires_at),
+            ],
         ]
-        .iter()
-        .cloned()
-        .collect();
+        .concat();
 
-        Command::new(shell.clone()).envs(envs).status()?;
+        let exe = env::current_exe()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "aws-mfa-session".to_string());
+        let init_script =
+            shell_kind.init_script(&prompt, i64::from(opts.warn_before) * 60, &exe)?;
+        // The init script is sourced from a private temp dir that must outlive
+        // the child shell.
+        let init_dir = tempfile::tempdir()?;
+        let _span = tracing::info_span!("shell", program = %shell).entered();
+        let status = shell_kind
+            .command(&shell, &init_script, init_dir.path())?
+            .envs(envs)
+            .status()?;
+        tracing::info!(%status, "shell exited");
     }
 
-    if opts.export {
+    if refresh {
+        // Only the variables: the prompt is already set (and, with the expiry
+        // hook, redrawn) in the running shell.
+        let mut stdout = io::stdout().lock();
+        shell_kind.export_vars(
+            &mut stdout,
+            &[
+                credential_vars(&session, &expiration).as_slice(),
+                &[(AWS_MFA_SESSION_EXPIRES_AT, &expires_at)],
+            ]
+            .concat(),
+        )?;
+    } else if opts.export {
         let mut stdout = io::stdout().lock();
-        Shell::from(shell.as_str()).export(
+        shell_kind.export_vars(
             &mut stdout,
-            credentials.access_key_id(),
-            credentials.secret_access_key(),
-            credentials.session_token(),
-            &ps,
+            &[
+                (AWS_SESSION_EXPIRATION, &expiration),
+                (AWS_CREDENTIAL_EXPIRATION, &expiration),
+            ],
+        )?;
+        shell_kind.export(
+            &mut stdout,
+            &session.access_key_id,
+            session.secret_access_key.expose(),
+            session.session_token.expose(),
+            &prompt,
         )?;
     }
 
     Ok(())
 }
 
+// The variables `credential_vars` sets, in order.
+const CREDENTIAL_VARS: [&str; 5] = [
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+    AWS_SESSION_EXPIRATION,
+    AWS_CREDENTIAL_EXPIRATION,
+];
+
+/// The session's credentials and expiration as environment variables.
+fn credential_vars<'a>(session: &'a Session, expiration: &'a str) -> Vec<(&'static str, &'a str)> {
+    CREDENTIAL_VARS
+        .into_iter()
+        .zip([
+            session.access_key_id.as_str(),
+            session.secret_access_key.expose(),
+            session.session_token.expose(),
+            expiration,
+            expiration,
+        ])
+        .collect()
+}
+
+/// Session for env/exec: the agent's, if one is running and holds a session
+/// for the profile; else the one in `cache`, if given and live; otherwise a
+/// new one, handed to the agent (and stored in `cache`) for next time.
+#[tracing::instrument(skip_all)]
+async fn agent_session(
+    opts: &Args,
+    code: Option<Secret>,
+    cache: Option<(&dyn SessionStore, &str)>,
+) -> Result<Session, CliError> {
+    let profile = env::var(AWS_PROFILE).unwrap_or_else(|_| "default".to_string());
+    #[cfg(unix)]
+    let mut client = match agent::socket_path() {
+        Some(path) => AgentClient::connect(&path)
+            .await
+            .inspect_err(|e| tracing::debug!(error = %e, "no agent"))
+            .ok(),
+        None => None,
+    };
+    #[cfg(unix)]
+    if let Some(client) = client.as_mut()
+        && let Some(session) = client.get(&profile).await?
+    {
+        tracing::info!(%profile, "session from the agent");
+        return Ok(session);
+    }
+
+    let session = match cache {
+        Some((store, name)) => stored_session(opts, code, store, name).await?,
+        None => mint_session(opts, code).await?,
+    };
+
+    #[cfg(unix)]
+    if let Some(client) = client.as_mut()
+        && let Err(e) = client.put(&profile, &session).await
+    {
+        tracing::warn!(error = %e, "cannot hand the session to the agent");
+    }
+    Ok(session)
+}
+
+/// Session for credential-process: the stored one while it is live, else a
+/// new one, stored for the next call.
+#[tracing::instrument(skip_all, fields(entry = %name))]
+async fn stored_session(
+    opts: &Args,
+    code: Option<Secret>,
+    store: &dyn SessionStore,
+    name: &str,
+) -> Result<Session, CliError> {
+    reuse_or_mint(store, name, mint_session(opts, code)).await
+}
+
+/// The stored session `name` while it is live, else the one `mint` makes,
+/// stored in its place. An entry that cannot be read (a wrong key, a key
+/// derivation mismatch, a corrupt file) is a miss, so it gets replaced rather
+/// than failing every later run.
+async fn reuse_or_mint(
+    store: &dyn SessionStore,
+    name: &str,
+    mint: impl Future<Output = Result<Session, CliError>>,
+) -> Result<Session, CliError> {
+    match store.load(name) {
+        Ok(Some(session)) if session.expires_in() > STORED_MIN_REMAINING => {
+            tracing::info!(expires_in = session.expires_in(), "reusing stored session");
+            return Ok(session);
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!(error = %e, "cannot read the stored session, minting a new one"),
+    }
+    let session = mint.await?;
+    store.save(name, &session, None)?;
+    Ok(session)
+}
+
+/// The audit context for this run's sessions, when the config enables the
+/// audit log (`audit_log = true`).
+fn auditor(opts: &Args) -> Result<Option<Auditor>, CliError> {
+    let profile = env::var(AWS_PROFILE).ok();
+    let log = AuditLog::from_config(&Config::load()?, profile.as_deref())?;
+    Ok(log.map(|log| {
+        Auditor::new(
+            log,
+            profile.unwrap_or_else(|| "default".to_string()),
+            opts.output_modes(),
+        )
+    }))
+}
+
+/// Print the last `limit` audit entries, optionally for one profile only.
+fn history(profile: Option<&str>, limit: usize, json: bool) -> Result<(), CliError> {
+    use std::io::Write;
+
+    let path = AuditLog::default_path().ok_or_else(|| {
+        CliError::ValidationError("cannot determine the audit log path".to_string())
+    })?;
+    let log = AuditLog::new(path);
+    let entries: Vec<_> = log
+        .read()?
+        .into_iter()
+        .filter(|entry| profile.is_none_or(|profile| entry.profile == profile))
+        .collect();
+    if entries.is_empty() {
+        status!(
+            "no sessions recorded in {} (enable with audit_log = true)",
+            log.path().display()
+        );
+        return Ok(());
+    }
+
+    let mut stdout = io::stdout().lock();
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        if json {
+            let line = serde_json::to_string(entry)
+                .map_err(|e| CliError::ValidationError(e.to_string()))?;
+            writeln!(stdout, "{line}")?;
+        } else {
+            writeln!(stdout, "{}", audit::format_entry(entry))?;
+        }
+    }
+    Ok(())
+}
+
+/// The profiles to log in to: each `--profile`, then the `--group` members,
+/// in order and without repeats.
+fn profile_list(opts: &Args, config: &Config) -> Result<Vec<String>, CliError> {
+    let mut profiles = opts.profiles.clone();
+    if let Some(name) = &opts.group {
+        let members = config.group(name).ok_or_else(|| {
+            CliError::ValidationError(format!("no group '{name}' in the [groups] config section"))
+        })?;
+        profiles.extend(members);
+    }
+    let mut seen = std::collections::HashSet::new();
+    profiles.retain(|profile| seen.insert(profile.clone()));
+    if profiles.is_empty() {
+        return Err(CliError::ValidationError(format!(
+            "group '{}' has no profiles",
+            opts.group.as_deref().unwrap_or_default()
+        )));
+    }
+    Ok(profiles)
+}
+
+/// Log in to several profiles and write each one's session under the
+/// `--update-profile` template. Profiles that share an MFA device and base
+/// credentials share a session, so each distinct pair needs one code; every
+/// section is written in a single update of the store.
+#[tracing::instrument(skip_all)]
+async fn login_profiles(opts: &Args, code: Option<Secret>) -> Result<(), CliError> {
+    let template = match &opts.session_profile {
+        Some(template) if template.contains("{profile}") => template,
+        Some(_) => {
+            return Err(CliError::ValidationError(
+                "--update-profile needs a {profile} placeholder to name each profile's session"
+                    .to_string(),
+            ));
+        }
+        None => {
+            return Err(CliError::ValidationError(
+                "several profiles can only be written with --update-profile".to_string(),
+            ));
+        }
+    };
+    if opts.shell
+        || opts.export
+        || !opts.outputs.is_empty()
+        || !opts.cli_cache.is_empty()
+        || opts.command.is_some()
+    {
+        return Err(CliError::ValidationError(
+            "several profiles can only be written with --update-profile, \
+             not with --shell, --export, --output, --cli-cache or a command"
+                .to_string(),
+        ));
+    }
+    let config = Config::load()?;
+    let profiles = profile_list(opts, &config)?;
+    // Each profile's session goes where its own `storage` says, so a profile
+    // kept in the keyring never lands in the credentials file.
+    let storages = profiles
+        .iter()
+        .map(|profile| {
+            Ok::<_, CliError>(
+                configured_storage(opts.storage, &config, Some(profile))?.unwrap_or_default(),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Profiles grouped by MFA device and base access key, in order.
+    let mut groups: Vec<(_, Vec<_>)> = Vec::new();
+    for (profile, storage) in profiles.into_iter().zip(storages) {
+        let minter = SessionMinter::new(
+            session::load_profile_config(opts.region.clone(), &profile).await,
+            opts.arn.clone(),
+            Some(&profile),
+            opts.duration,
+        )
+        .await?;
+        let key = (
+            minter.serial_number().to_string(),
+            minter.source_key_id().await?,
+        );
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, members)) => members.push((profile, storage, minter)),
+            None => groups.push((key, vec![(profile, storage, minter)])),
+        }
+    }
+
+    let logins: Vec<String> = groups
+        .iter()
+        .map(|((serial, _), members)| {
+            let names: Vec<&str> = members.iter().map(|(name, ..)| name.as_str()).collect();
+            format!("{serial} ({})", names.join(","))
+        })
+        .collect();
+    ensure_code_fits(code.is_some(), &logins)?;
+
+    let mut code = code;
+    // Sessions by store: the encrypted store's key may differ per profile,
+    // the others are shared.
+    let mut stores: Vec<((StorageKind, Option<&str>), Vec<_>)> = Vec::new();
+    for (((serial, _), members), login) in groups.iter().zip(&logins) {
+        let code = match code.take() {
+            Some(code) => code,
+            None => ask_code(&format!("MFA code for {login}"))?,
+        };
+        let (_, _, first) = &members[0];
+        let session = first.mint(code.expose()).await?;
+        // One entry per profile, so `history -p PROFILE` finds it.
+        for (name, ..) in members {
+            if let Some(log) = AuditLog::from_config(&config, Some(name))? {
+                Auditor::new(log, name.clone(), opts.output_modes()).record(
+                    serial,
+                    opts.duration,
+                    first.identity().await?,
+                )?;
+            }
+        }
+        for (name, storage, minter) in members {
+            let region = minter.config().region().map(|r| r.to_string());
+            let entry = (template.replace("{profile}", name), session.clone(), region);
+            let key = match storage {
+                StorageKind::Encrypted => (*storage, Some(name.as_str())),
+                StorageKind::File | StorageKind::Keyring => (*storage, None),
+            };
+            match stores.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, entries)) => entries.push(entry),
+                None => stores.push((key, vec![entry])),
+            }
+        }
+    }
+
+    let mut names = Vec::new();
+    for ((storage, profile), entries) in &stores {
+        storage.store(&config, *profile)?.save_all(entries)?;
+        names.extend(entries.iter().map(|(name, ..)| name.as_str()));
+    }
+    status!("updated {}", names.join(", "));
+    Ok(())
+}
+
+/// The storage chosen with `--storage`, else by the profile's `storage`
+/// config key; `None` when neither sets one.
+fn configured_storage(
+    flag: Option<StorageKind>,
+    config: &Config,
+    profile: Option<&str>,
+) -> Result<Option<StorageKind>, CliError> {
+    match flag {
+        Some(kind) => Ok(Some(kind)),
+        None => config
+            .get(profile, "storage")
+            .map(StorageKind::parse)
+            .transpose(),
+    }
+}
+
+/// `--code` is the code of a single login: with profiles that need several,
+/// it would be spent on the first and the others asked for anyway.
+fn ensure_code_fits(has_code: bool, logins: &[String]) -> Result<(), CliError> {
+    if has_code && logins.len() > 1 {
+        return Err(CliError::ValidationError(format!(
+            "--code can only be used for one MFA login, but these profiles need {}: {}; \
+             leave it out to be asked for each",
+            logins.len(),
+            logins.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Mint a session for the commands that ask for the MFA code only on demand.
+#[tracing::instrument(skip_all)]
+async fn mint_session(opts: &Args, code: Option<Secret>) -> Result<Session, CliError> {
+    let code = match code {
+        Some(code) => code,
+        None => ask_code_interactive()?,
+    };
+    let minter = SessionMinter::new(
+        session::load_config(opts.region.clone()).await,
+        opts.arn.clone(),
+        opts.profile(),
+        opts.duration,
+    )
+    .await?
+    .with_audit(auditor(opts)?);
+    minter.mint(code.expose()).await
+}
+
+fn exec(command: &[String], vars: &[(&str, &str)]) -> Result<(), CliError> {
+    let mut child = std::process::Command::new(&command[0]);
+    child.args(&command[1..]).envs(vars.iter().copied());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Replace this process, so the command owns the terminal and signals
+        // and its exit status is the one the caller sees. exec only returns on
+        // failure.
+        Err(child.exec().into())
+    }
+    #[cfg(not(unix))]
+    {
+        exit_status(child.status()?)
+    }
+}
+
+/// A command's exit status as the result `run` hands the binary to exit with.
+fn exit_status(status: std::process::ExitStatus) -> Result<(), CliError> {
+    match status.code() {
+        Some(0) => Ok(()),
+        code => Err(CliError::CommandFailed(code.unwrap_or(1))),
+    }
+}
+
+/// `docker run` with the session in an env-file, so the secrets appear in
+/// neither the command line nor this process's environment. The file lives
+/// in a private temp dir that is removed once the container exits.
+fn docker_run(args: &[String], vars: &[(&str, &str)]) -> Result<(), CliError> {
+    let env_dir = tempfile::tempdir()?;
+    let env_file = env_dir.path().join("session.env");
+    credentials::write_private_file(&env_file, &output::docker_env(vars)?)?;
+    let _span = tracing::info_span!("docker").entered();
+    let status = std::process::Command::new("docker")
+        .arg("run")
+        .arg("--env-file")
+        .arg(&env_file)
+        .args(args)
+        .status()?;
+    tracing::info!(%status, "container exited");
+    exit_status(status)
+}
+
+/// Print the exports for the agent, then serve in the foreground, or leave
+/// a detached agent running as `eval "$(aws-mfa-session agent)"` expects.
+#[cfg(unix)]
+async fn run_agent(
+    socket: Option<PathBuf>,
+    foreground: bool,
+    shell_kind: Shell,
+) -> Result<(), CliError> {
+    let path = socket.or_else(agent::socket_path).ok_or_else(|| {
+        CliError::ValidationError("cannot determine the agent socket path".to_string())
+    })?;
+    let path = std::path::absolute(path)?;
+    let exports = |pid: u32| {
+        shell_kind.export_vars(
+            &mut io::stdout().lock(),
+            &[
+                (AWS_MFA_SESSION_AGENT_SOCK, &path.to_string_lossy()),
+                (AWS_MFA_SESSION_AGENT_PID, &pid.to_string()),
+            ],
+        )
+    };
+    if !foreground {
+        exports(agent::spawn(&path).await?)?;
+        return Ok(());
+    }
+    let listener = agent::bind(&path).await?;
+    exports(std::process::id())?;
+    Arc::new(Agent::default()).serve(listener).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn run_agent(
+    _socket: Option<PathBuf>,
+    _foreground: bool,
+    _shell_kind: Shell,
+) -> Result<(), CliError> {
+    Err(CliError::ValidationError(
+        "the agent needs Unix domain sockets, which this platform lacks".to_string(),
+    ))
+}
+
+/// Session cache for the server modes: mints the first session (so a wrong
+/// MFA code fails right away) and keeps it renewed in the background.
+#[tracing::instrument(skip_all)]
+async fn session_cache(
+    opts: &Args,
+    token_code: Secret,
+    refresh_before: u32,
+) -> Result<Arc<CredentialCache<PromptingSource>>, CliError> {
+    let refresh_before = i64::from(refresh_before) * 60;
+    if refresh_before >= i64::from(opts.duration) {
+        return Err(CliError::ValidationError(
+            "--refresh-before must be shorter than the session --duration".to_string(),
+        ));
+    }
+    let config = session::load_config(opts.region.clone()).await;
+    let minter = SessionMinter::new(config, opts.arn.clone(), opts.profile(), opts.duration)
+        .await?
+        .with_audit(auditor(opts)?);
+    let cache = Arc::new(CredentialCache::new(
+        PromptingSource::new(minter, token_code),
+        refresh_before,
+    ));
+    cache.get().await?;
+    let renewer = cache.clone();
+    tokio::spawn(async move { renewer.keep_fresh().await });
+    Ok(cache)
+}
+
+/// Print the variables a client needs, then serve until interrupted.
+async fn serve(
+    cache: Arc<CredentialCache<PromptingSource>>,
+    listen: Listen,
+    shell_kind: Shell,
+) -> Result<(), CliError> {
+    let token = server::random_token()?;
+    let handler = Arc::new(EcsHandler::new(cache, token.clone()));
+
+    match listen {
+        Listen::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            let uri = format!("http://{}{CREDENTIALS_PATH}", listener.local_addr()?);
+            shell_kind.export_vars(
+                &mut io::stdout().lock(),
+                &[
+                    ("AWS_CONTAINER_CREDENTIALS_FULL_URI", &uri),
+                    ("AWS_CONTAINER_AUTHORIZATION_TOKEN", &token),
+                ],
+            )?;
+            server::serve_tcp(listener, handler).await?;
+        }
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            let listener = server::bind_unix(&path)?;
+            status!("serving {CREDENTIALS_PATH} on {}", path.display());
+            shell_kind.export_vars(
+                &mut io::stdout().lock(),
+                &[("AWS_CONTAINER_AUTHORIZATION_TOKEN", &token)],
+            )?;
+            server::serve_unix(listener, handler).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn imds(
+    cache: Arc<CredentialCache<PromptingSource>>,
+    listen: SocketAddr,
+    role_name: String,
+    shell_kind: Shell,
+) -> Result<(), CliError> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    shell_kind.export_vars(
+        &mut io::stdout().lock(),
+        &[(AWS_EC2_METADATA_SERVICE_ENDPOINT, &endpoint)],
+    )?;
+    let handler = Arc::new(ImdsHandler::new(cache, role_name));
+    server::serve_tcp(listener, handler).await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use clap::Parser;
+    use serial_test::serial;
+
+    #[tokio::test]
+    async fn test_unreadable_stored_session_is_minted_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = cache::EncryptedStore::new(
+            dir.path().join("sessions"),
+            cache::CacheKey::File(dir.path().join("cache.key")),
+        );
+        store
+            .save("dev", &session::tests::session_expiring_in(3600), None)
+            .unwrap();
+        std::fs::write(dir.path().join("sessions").join("dev.enc"), "{corrupt").unwrap();
+        assert!(store.load("dev").is_err());
+
+        let minted = reuse_or_mint(&store, "dev", async {
+            let mut session = session::tests::session_expiring_in(3600);
+            session.access_key_id = "ASIAMINTED".to_string();
+            Ok(session)
+        })
+        .await
+        .unwrap();
+        assert_eq!(minted.access_key_id, "ASIAMINTED");
+        let stored = store.load("dev").unwrap().unwrap();
+        assert_eq!(stored.access_key_id, "ASIAMINTED");
+
+        // The replaced entry is reused without minting.
+        let reused = reuse_or_mint(&store, "dev", async { Err(CliError::NoCredentials) })
+            .await
+            .unwrap();
+        assert_eq!(reused.access_key_id, "ASIAMINTED");
+    }
 
     #[tokio::test]
     async fn test_run_rejects_missing_output_mode() {
@@ -180,6 +886,317 @@ mod tests {
         assert!(matches!(run(opts).await, Err(CliError::ValidationError(_))));
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_run_refresh_requires_shell_marker() {
+        // Outside a --shell session there is nothing to refresh; fail before
+        // touching AWS or the environment.
+        unsafe { env::remove_var(AWS_MFA_SESSION) };
+        let opts = Args::try_parse_from(["aws-mfa-session", "refresh", "-c", "123456"]).unwrap();
+        assert!(matches!(run(opts).await, Err(CliError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_run_serve_rejects_refresh_beyond_duration() {
+        // Renewing more often than sessions last would prompt endlessly.
+        let opts = Args::try_parse_from([
+            "aws-mfa-session",
+            "serve",
+            "-c",
+            "123456",
+            "-d",
+            "900",
+            "--refresh-before",
+            "15",
+        ])
+        .unwrap();
+        assert!(matches!(run(opts).await, Err(CliError::ValidationError(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    #[serial]
+    async fn test_run_env_uses_agent_session_without_code() {
+        // With a session in the agent, env needs neither an MFA code nor AWS.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let listener = agent::bind(&path).await.unwrap();
+        tokio::spawn(Arc::new(Agent::default()).serve(listener));
+        AgentClient::connect(&path)
+            .await
+            .unwrap()
+            .put("agent-test", &session::tests::session_expiring_in(3600))
+            .await
+            .unwrap();
+
+        let previous = env::var(AWS_PROFILE).ok();
+        unsafe { env::set_var(AWS_MFA_SESSION_AGENT_SOCK, &path) };
+        let opts = Args::try_parse_from(["aws-mfa-session", "env", "-p", "agent-test"]).unwrap();
+        let result = run(opts).await;
+        unsafe {
+            env::remove_var(AWS_MFA_SESSION_AGENT_SOCK);
+            match previous {
+                Some(profile) => env::set_var(AWS_PROFILE, profile),
+                None => env::remove_var(AWS_PROFILE),
+            }
+        }
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_run_credential_process_reuses_stored_session() {
+        // A live stored session is printed without an MFA code or AWS calls.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials");
+        let previous = env::var(AWS_SHARED_CREDENTIALS_FILE).ok();
+        unsafe { env::set_var(AWS_SHARED_CREDENTIALS_FILE, &path) };
+        let stored = storage::FileStore.save(
+            "cp-test-mfa-session",
+            &session::tests::session_expiring_in(3600),
+            None,
+        );
+        let opts = Args::try_parse_from([
+            "aws-mfa-session",
+            "credential-process",
+            "--name",
+            "cp-test-mfa-session",
+        ])
+        .unwrap();
+        let result = run(opts).await;
+        unsafe {
+            match previous {
+                Some(file) => env::set_var(AWS_SHARED_CREDENTIALS_FILE, file),
+                None => env::remove_var(AWS_SHARED_CREDENTIALS_FILE),
+            }
+        }
+        stored.unwrap();
+        result.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    #[serial]
+    async fn test_run_env_reads_encrypted_cache() {
+        // With no agent running, env falls back to the configured cache.
+        let dir = tempfile::tempdir().unwrap();
+        let store = cache::EncryptedStore::new(
+            dir.path().join("aws-mfa-session").join("sessions"),
+            cache::CacheKey::Passphrase(Secret::from("test passphrase").into()),
+        );
+        store
+            .save(
+                "cache-test-mfa-session",
+                &session::tests::session_expiring_in(3600),
+                None,
+            )
+            .unwrap();
+
+        let previous = env::var(AWS_PROFILE).ok();
+        unsafe {
+            env::set_var("XDG_CACHE_HOME", dir.path());
+            env::set_var(AWS_MFA_SESSION_AGENT_SOCK, dir.path().join("none.sock"));
+            env::set_var(cache::AWS_MFA_SESSION_PASSPHRASE, "test passphrase");
+        }
+        let opts = Args::try_parse_from([
+            "aws-mfa-session",
+            "--storage",
+            "encrypted",
+            "env",
+            "-p",
+            "cache-test",
+        ])
+        .unwrap();
+        let result = run(opts).await;
+        unsafe {
+            env::remove_var("XDG_CACHE_HOME");
+            env::remove_var(AWS_MFA_SESSION_AGENT_SOCK);
+            env::remove_var(cache::AWS_MFA_SESSION_PASSPHRASE);
+            match previous {
+                Some(profile) => env::set_var(AWS_PROFILE, profile),
+                None => env::remove_var(AWS_PROFILE),
+            }
+        }
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_run_logs_no_secrets() {
+        // Everything run logs at trace level, from every crate, is free of the
+        // session's secrets and of the MFA code.
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl io::Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter("trace")
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dir = tempfile::tempdir().unwrap();
+        let previous = env::var(AWS_SHARED_CREDENTIALS_FILE).ok();
+        unsafe { env::set_var(AWS_SHARED_CREDENTIALS_FILE, dir.path().join("credentials")) };
+        let mut session = session::tests::session_expiring_in(3600);
+        session.secret_access_key = "wJalrXUtnFEMIsecretKEY".into();
+        session.session_token = "FwoGZXIvYXdzsessionTOKEN".into();
+        let stored = storage::FileStore.save("log-test-mfa-session", &session, None);
+        let opts = Args::try_parse_from([
+            "aws-mfa-session",
+            "credential-process",
+            "--name",
+            "log-test-mfa-session",
+            "--code",
+            "246810",
+            "-vvv",
+        ])
+        .unwrap();
+        let result = run(opts).await;
+        unsafe {
+            match previous {
+                Some(file) => env::set_var(AWS_SHARED_CREDENTIALS_FILE, file),
+                None => env::remove_var(AWS_SHARED_CREDENTIALS_FILE),
+            }
+        }
+        stored.unwrap();
+        result.unwrap();
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("reusing stored session"), "{logs}");
+        assert!(logs.contains("[REDACTED]"), "{logs}");
+        for secret in [
+            "wJalrXUtnFEMIsecretKEY",
+            "FwoGZXIvYXdzsessionTOKEN",
+            "246810",
+        ] {
+            assert!(!logs.contains(secret), "{secret} logged: {logs}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_unset_needs_no_code() {
+        // Ending a session touches neither AWS nor the MFA device.
+        let opts =
+            Args::try_parse_from(["aws-mfa-session", "unset", "--shell-type", "fish"]).unwrap();
+        run(opts).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_multiple_profiles_need_profile_template() {
+        // Each profile needs its own section name, and nothing but -u can take
+        // several sessions; both are checked before any code is asked for.
+        for argv in [
+            vec!["aws-mfa-session", "-p", "dev", "-p", "prod", "-e"],
+            vec!["aws-mfa-session", "-p", "dev", "-p", "prod", "-u", "shared"],
+            vec![
+                "aws-mfa-session",
+                "-p",
+                "dev",
+                "-p",
+                "prod",
+                "-u",
+                "{profile}-s",
+                "-s",
+            ],
+        ] {
+            let opts = Args::try_parse_from(&argv).unwrap();
+            assert!(run(opts).await.is_err(), "{argv:?} should be rejected");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_status_is_returned() {
+        use std::os::unix::process::ExitStatusExt;
+        assert!(exit_status(std::process::ExitStatus::from_raw(0)).is_ok());
+        assert!(matches!(
+            exit_status(std::process::ExitStatus::from_raw(125 << 8)),
+            Err(CliError::CommandFailed(125))
+        ));
+    }
+
+    #[test]
+    fn test_code_only_fits_one_login() {
+        let one = ["arn:aws:iam::111111111111:mfa/me (dev,prod)".to_string()];
+        let two = [
+            one[0].clone(),
+            "arn:aws:iam::222222222222:mfa/me (ops)".to_string(),
+        ];
+        assert!(ensure_code_fits(true, &one).is_ok());
+        assert!(ensure_code_fits(false, &two).is_ok());
+        let err = ensure_code_fits(true, &two).unwrap_err().to_string();
+        assert!(err.contains("need 2"), "{err}");
+        assert!(err.contains("mfa/me (ops)"), "{err}");
+    }
+
+    #[test]
+    fn test_storage_is_resolved_per_profile() {
+        let config = Config::from_str(
+            "[default]\nstorage = encrypted\n[profile dev]\nstorage = keyring\n[profile prod]\n",
+        );
+        let storage = |flag, profile| configured_storage(flag, &config, profile).unwrap();
+        assert_eq!(storage(None, Some("dev")), Some(StorageKind::Keyring));
+        assert_eq!(storage(None, Some("prod")), Some(StorageKind::Encrypted));
+        assert_eq!(
+            storage(Some(StorageKind::File), Some("dev")),
+            Some(StorageKind::File)
+        );
+        assert_eq!(
+            configured_storage(None, &Config::from_str(""), Some("dev")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_profile_list_expands_group() {
+        let config = Config::from_str("[groups]\nwork = dev, prod staging\n");
+
+        let opts =
+            Args::try_parse_from(["aws-mfa-session", "-p", "prod", "-p", "ops", "-g", "work"])
+                .unwrap();
+        assert_eq!(
+            profile_list(&opts, &config).unwrap(),
+            ["prod", "ops", "dev", "staging"]
+        );
+        let opts = Args::try_parse_from(["aws-mfa-session", "-g", "missing"]).unwrap();
+        assert!(profile_list(&opts, &config).is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_run_history_reads_audit_log() {
+        // history needs no MFA code, no AWS and no audit_log setting to read.
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path().join("audit.jsonl"));
+        log.append(&audit::AuditEntry {
+            timestamp: "2024-01-01T12:00:00Z".to_string(),
+            profile: "dev".to_string(),
+            mfa_serial: "GAHT12345678".to_string(),
+            account: "123456789012".to_string(),
+            role: None,
+            duration: 3600,
+            outputs: vec!["export".to_string()],
+            hostname: None,
+        })
+        .unwrap();
+
+        unsafe { env::set_var(config::AWS_MFA_SESSION_CONFIG, dir.path().join("config")) };
+        let opts = Args::try_parse_from(["aws-mfa-session", "history", "-p", "dev"]).unwrap();
+        let result = run(opts).await;
+        unsafe { env::remove_var(config::AWS_MFA_SESSION_CONFIG) };
+        result.unwrap();
+    }
+
     #[test]
     fn test_env_var_setting_logic() {
         // Test the logic for setting environment variables based on Args
@@ -0,0 +1,142 @@
+use clap::ValueEnum;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+// This crate's log target, the only one any filter enables.
+const TARGET: &str = "aws_mfa_session";
+
+// Set by `init` when `-q` outweighs `-v`.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// How log lines are written to stderr.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for collecting and searching logs
+    Json,
+}
+
+/// Log filter for `-v` repeated `verbose` times and `-q` repeated `quiet`
+/// times: warnings by default, then info, debug and trace; nothing when quiet.
+///
+/// Only this crate's own targets are enabled. The AWS SDK logs requests at
+/// debug and trace level, and a `GetSessionToken` request carries the MFA
+/// code; events and spans here never record credentials or codes, which
+/// `Secret` redacts even where a value is formatted.
+pub fn filter(verbose: u8, quiet: u8) -> EnvFilter {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        ..0 => return EnvFilter::new("off"),
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    EnvFilter::new(format!("{TARGET}={level},off"))
+}
+
+/// `RUST_LOG` narrowed to this crate: a bare level or span applies to its
+/// targets only, directives for other targets are dropped and everything
+/// else stays off, so no `RUST_LOG` can turn on the SDK's wire logs.
+fn own_targets(rust_log: &str) -> String {
+    let mut directives: Vec<String> = rust_log
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .filter_map(|directive| {
+            if directive.parse::<LevelFilter>().is_ok() {
+                Some(format!("{TARGET}={directive}"))
+            } else if directive.starts_with('[') {
+                Some(format!("{TARGET}{directive}"))
+            } else if directive
+                .strip_prefix(TARGET)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['=', '[', ':']))
+            {
+                Some(directive.to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+    directives.push("off".to_string());
+    directives.join(",")
+}
+
+/// Install the global subscriber, logging to stderr since stdout carries the
+/// exports and JSON that callers evaluate or parse. A valid `RUST_LOG` takes
+/// precedence over the flags, for this crate's targets only. Closed spans
+/// report their duration.
+pub fn init(verbose: u8, quiet: u8, format: LogFormat) {
+    QUIET.store(quiet > verbose, Ordering::Relaxed);
+    let filter = std::env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .and_then(|rust_log| EnvFilter::try_new(own_targets(&rust_log)).ok())
+        .unwrap_or_else(|| filter(verbose, quiet));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Print a status line to stderr, such as the file an output was written to,
+/// unless `-q` silenced it.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::logging::print_status(format_args!($($arg)*))
+    };
+}
+pub(crate) use status;
+
+pub(crate) fn print_status(message: fmt::Arguments) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("aws-mfa-session: {message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_levels() {
+        assert_eq!(filter(0, 0).to_string(), "aws_mfa_session=warn,off");
+        assert_eq!(filter(1, 0).to_string(), "aws_mfa_session=info,off");
+        assert_eq!(filter(2, 0).to_string(), "aws_mfa_session=debug,off");
+        assert_eq!(filter(3, 0).to_string(), "aws_mfa_session=trace,off");
+        assert_eq!(filter(9, 0).to_string(), "aws_mfa_session=trace,off");
+    }
+
+    #[test]
+    fn test_filter_quiet() {
+        assert_eq!(filter(0, 1).to_string(), "off");
+        assert_eq!(filter(0, 3).to_string(), "off");
+        // -q and -v cancel out.
+        assert_eq!(filter(1, 1).to_string(), "aws_mfa_session=warn,off");
+    }
+
+    #[test]
+    fn test_rust_log_only_enables_own_targets() {
+        assert_eq!(own_targets("trace"), "aws_mfa_session=trace,off");
+        assert_eq!(
+            own_targets(
+                "aws_smithy_runtime=trace, aws_mfa_session::cache=debug,hyper,aws_mfa_sessionx"
+            ),
+            "aws_mfa_session::cache=debug,off"
+        );
+        assert_eq!(
+            own_targets("[assume_role]=trace"),
+            "aws_mfa_session[assume_role]=trace,off"
+        );
+        assert_eq!(own_targets(""), "off");
+        let filter = EnvFilter::try_new(own_targets("debug,aws_smithy_http=trace")).unwrap();
+        assert_eq!(filter.to_string(), "aws_mfa_session=debug,off");
+    }
+}
@@ -0,0 +1,886 @@
+use crate::AWS_SESSION_EXPIRATION;
+use crate::credentials::write_private_file;
+use crate::error::CliError;
+use crate::logging::status;
+use crate::secret::{Secret, escape};
+use crate::server::random_token;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::ValueEnum;
+use zeroize::Zeroizing;
+
+/// File formats the session can be written in with `--output`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// `KEY=value` lines, as read by Docker Compose and dotenv libraries
+    Dotenv,
+    /// `--env KEY=value` arguments for `docker run`
+    DockerArgs,
+    /// An env-file for `docker run --env-file`: `KEY=value` lines, taken literally
+    DockerEnv,
+    /// A Kubernetes `Secret` manifest for `kubectl apply -f -`
+    K8sSecret,
+    /// Appended to a GitHub Actions env file, GITHUB_ENV by default, with the secrets masked
+    GithubEnv,
+    /// github-env on GitHub Actions, dotenv on GitLab CI
+    Ci,
+    /// Terraform variable definitions in HCL (.tfvars)
+    Tfvars,
+    /// Terraform variable definitions in JSON (.tfvars.json)
+    TfvarsJson,
+}
+
+// The variables GitHub Actions is told to mask in the job's logs.
+const MASKED_VARS: [&str; 2] = ["AWS_SECRET_ACCESS_KEY", "AWS_SESSION_TOKEN"];
+
+// The variables tfvars writes, in the order of `OutputOptions::tfvars_names`.
+const TFVARS_VARS: [&str; 3] = [
+    "AWS_ACCESS_KEY_ID",
+    "AWS_SECRET_ACCESS_KEY",
+    "AWS_SESSION_TOKEN",
+];
+
+/// Settings of the formats that need more than the variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputOptions {
+    pub k8s_name: String,
+    pub k8s_namespace: Option<String>,
+    /// Terraform variables for the access key, secret key and token
+    pub tfvars_names: [String; 3],
+}
+
+/// One `--output FORMAT[=PATH]`: stdout without a path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub format: OutputFormat,
+    pub path: Option<PathBuf>,
+}
+
+pub fn parse_output(s: &str) -> Result<Output, CliError> {
+    let (format, path) = match s.split_once('=') {
+        Some((format, path)) if !path.is_empty() => (format, Some(PathBuf::from(path))),
+        Some(_) => {
+            return Err(CliError::ValidationError(format!(
+                "--output {s}: the path after '=' is empty"
+            )));
+        }
+        None => (s, None),
+    };
+    let format = OutputFormat::from_str(format, true).map_err(|_| {
+        CliError::ValidationError(format!(
+            "--output {s}: unknown format {format:?}, \
+             expected dotenv, docker-args, docker-env, k8s-secret, github-env, ci, \
+             tfvars or tfvars-json"
+        ))
+    })?;
+    if format == OutputFormat::DockerArgs && path.is_some() {
+        return Err(CliError::ValidationError(format!(
+            "--output {s}: docker-args can only be printed"
+        )));
+    }
+    Ok(Output { format, path })
+}
+
+/// A Kubernetes object name: a DNS subdomain (RFC 1123), at most 253
+/// lowercase letters, digits, `-` and `.`, starting and ending alphanumeric.
+pub fn parse_k8s_name(s: &str) -> Result<String, CliError> {
+    parse_dns_name(s, 253, ".-")
+}
+
+/// A Kubernetes namespace: a DNS label (RFC 1123), at most 63 lowercase
+/// letters, digits and `-`, starting and ending alphanumeric.
+pub fn parse_k8s_namespace(s: &str) -> Result<String, CliError> {
+    parse_dns_name(s, 63, "-")
+}
+
+/// Three comma-separated Terraform variable names, each a letter or `_`
+/// followed by letters, digits, `_` and `-`.
+pub fn parse_tfvars_names(s: &str) -> Result<[String; 3], CliError> {
+    let valid = |name: &str| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    let names: Vec<String> = s.split(',').map(|name| name.trim().to_string()).collect();
+    if let Some(name) = names.iter().find(|name| !valid(name)) {
+        return Err(CliError::ValidationError(format!(
+            "{name:?} is not a valid Terraform variable name"
+        )));
+    }
+    names.try_into().map_err(|_| {
+        CliError::ValidationError(format!(
+            "--tfvars-names {s}: expected three names, for the access key, secret key and token"
+        ))
+    })
+}
+
+fn parse_dns_name(s: &str, max_len: usize, separators: &str) -> Result<String, CliError> {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    let valid = s.len() <= max_len
+        && s.starts_with(alphanumeric)
+        && s.ends_with(alphanumeric)
+        && s.chars().all(|c| alphanumeric(c) || separators.contains(c));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(CliError::ValidationError(format!(
+            "{s:?} is not a valid Kubernetes name: use at most {max_len} lowercase letters, \
+             digits and {separators:?}, starting and ending with a letter or digit"
+        )))
+    }
+}
+
+impl Output {
+    /// The format's name, as given on the command line.
+    pub fn name(&self) -> String {
+        self.format
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Write the variables `vars` to stdout, or atomically to the path with
+    /// owner-only permissions. An existing dotenv or Docker env-file keeps its
+    /// other lines; manifests and variable files are replaced as a whole.
+    #[tracing::instrument(skip_all, fields(format = %self.name()))]
+    pub fn write(&self, vars: &[(&str, &str)], options: &OutputOptions) -> Result<(), CliError> {
+        let output = self.resolve(|name| env::var(name).ok())?;
+        let Some(path) = &output.path else {
+            let contents = output.render(vars, options, "")?;
+            io::stdout().lock().write_all(contents.as_bytes())?;
+            return Ok(());
+        };
+        if output.format == OutputFormat::GithubEnv {
+            return append_github_env(path, vars);
+        }
+        let existing = match output.format {
+            OutputFormat::Dotenv | OutputFormat::DockerEnv => match fs::read_to_string(path) {
+                Ok(contents) => Zeroizing::new(contents),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Zeroizing::new(String::new()),
+                Err(e) => return Err(e.into()),
+            },
+            OutputFormat::DockerArgs
+            | OutputFormat::K8sSecret
+            | OutputFormat::GithubEnv
+            | OutputFormat::Ci
+            | OutputFormat::Tfvars
+            | OutputFormat::TfvarsJson => Zeroizing::new(String::new()),
+        };
+        let contents = output.render(vars, options, &existing)?;
+        write_private_file(path, &contents)?;
+        status!("wrote {}", path.display());
+        Ok(())
+    }
+
+    /// The output `ci` stands for in the CI system `var` shows, and
+    /// github-env with its path filled in from GITHUB_ENV.
+    fn resolve(&self, var: impl Fn(&str) -> Option<String>) -> Result<Output, CliError> {
+        let is_true = |name: &str| var(name).is_some_and(|value| value == "true");
+        let format = match self.format {
+            OutputFormat::Ci if is_true("GITHUB_ACTIONS") => OutputFormat::GithubEnv,
+            OutputFormat::Ci if is_true("GITLAB_CI") => OutputFormat::Dotenv,
+            OutputFormat::Ci => {
+                return Err(CliError::ValidationError(
+                    "--output ci: neither GitHub Actions (GITHUB_ACTIONS) \
+                     nor GitLab CI (GITLAB_CI) detected"
+                        .to_string(),
+                ));
+            }
+            format => format,
+        };
+        let path = match (&self.path, format) {
+            (None, OutputFormat::GithubEnv) => Some(
+                var("GITHUB_ENV")
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from)
+                    .ok_or_else(|| {
+                        CliError::ValidationError(
+                            "--output github-env needs a path when GITHUB_ENV is not set"
+                                .to_string(),
+                        )
+                    })?,
+            ),
+            (path, _) => path.clone(),
+        };
+        Ok(Output { format, path })
+    }
+
+    fn render(
+        &self,
+        vars: &[(&str, &str)],
+        options: &OutputOptions,
+        existing: &str,
+    ) -> Result<Zeroizing<String>, CliError> {
+        match self.format {
+            OutputFormat::Dotenv => merge_lines(existing, vars, |value| Ok(dotenv_quote(value))),
+            OutputFormat::DockerArgs => docker_args(vars),
+            OutputFormat::DockerEnv => merge_lines(existing, vars, docker_env_value),
+            OutputFormat::GithubEnv => github_env(vars),
+            // Manifests and variable files are replaced as a whole.
+            OutputFormat::K8sSecret => Ok(k8s_secret(vars, options)),
+            OutputFormat::Tfvars | OutputFormat::TfvarsJson => tfvars(
+                vars,
+                &options.tfvars_names,
+                self.format == OutputFormat::TfvarsJson,
+            ),
+            OutputFormat::Ci => unreachable!("ci is resolved before rendering"),
+        }
+    }
+}
+
+/// Quote `value` for a dotenv file: bare when it is plain, in single quotes
+/// (taken literally) when it holds no `'` or newline, else in double quotes
+/// with `\`, `"`, `$` and newlines escaped.
+pub fn dotenv_quote(value: &str) -> Secret {
+    Secret::new(if is_plain(value) {
+        value.to_string()
+    } else if !value.contains(['\'', '\n', '\r']) {
+        escape(value, "'", |_, _| None)
+    } else {
+        escape(value, "\"", |c, _| match c {
+            '\\' => Some("\\\\"),
+            '"' => Some("\\\""),
+            '$' => Some("\\$"),
+            '\n' => Some("\\n"),
+            '\r' => Some("\\r"),
+            _ => None,
+        })
+    })
+}
+
+/// Whether `value` needs no quoting in a dotenv file or a POSIX shell.
+fn is_plain(value: &str) -> bool {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-.,/+=:@%".contains(c);
+    !value.is_empty() && value.chars().all(plain)
+}
+
+/// Docker reads an env-file value up to the end of the line, without any
+/// unquoting, so a value with a line break cannot be written at all.
+fn docker_env_value(value: &str) -> Result<Secret, CliError> {
+    if value.contains(['\n', '\r']) {
+        return Err(CliError::ValidationError(
+            "a value with a line break cannot be written to a Docker env-file".to_string(),
+        ));
+    }
+    Ok(Secret::from(value))
+}
+
+/// `--env NAME=value` for each variable on one line, quoted for a POSIX
+/// shell only where needed: credentials stay bare, so the line also works
+/// unquoted in `docker run $(aws-mfa-session -o docker-args) IMAGE`.
+fn docker_args(vars: &[(&str, &str)]) -> Result<Zeroizing<String>, CliError> {
+    let mut args = Zeroizing::new(String::new());
+    for (name, value) in vars {
+        let arg = Zeroizing::new(format!("{name}={value}"));
+        if !args.is_empty() {
+            args.push(' ');
+        }
+        args.push_str("--env ");
+        if is_plain(&arg) {
+            args.push_str(&arg);
+        } else {
+            let quoted = Secret::new(escape(&arg, "'", |c, _| (c == '\'').then_some("'\\''")));
+            args.push_str(quoted.expose());
+        }
+    }
+    args.push('\n');
+    Ok(args)
+}
+
+/// An env-file for `docker run --env-file` with the variables `vars`.
+pub fn docker_env(vars: &[(&str, &str)]) -> Result<Zeroizing<String>, CliError> {
+    merge_lines("", vars, docker_env_value)
+}
+
+/// An Opaque `Secret` with each variable base64-encoded under `data`, and
+/// the session's expiration in an annotation.
+fn k8s_secret(vars: &[(&str, &str)], options: &OutputOptions) -> Zeroizing<String> {
+    let mut yaml = Zeroizing::new(String::from("apiVersion: v1\nkind: Secret\nmetadata:\n"));
+    yaml.push_str(&format!("  name: {}\n", options.k8s_name));
+    if let Some(namespace) = &options.k8s_namespace {
+        yaml.push_str(&format!("  namespace: {namespace}\n"));
+    }
+    if let Some((_, expiration)) = vars
+        .iter()
+        .find(|(name, _)| *name == AWS_SESSION_EXPIRATION)
+    {
+        yaml.push_str(&format!(
+            "  annotations:\n    aws-mfa-session/expiration: \"{expiration}\"\n"
+        ));
+    }
+    yaml.push_str("type: Opaque\ndata:\n");
+    // Sized before the secrets go in, so the buffer never moves with them.
+    yaml.reserve(
+        vars.iter()
+            .map(|(name, value)| {
+                name.len() + base64::encoded_len(value.len(), true).unwrap_or(0) + 5
+            })
+            .sum(),
+    );
+    for (name, value) in vars {
+        yaml.push_str("  ");
+        yaml.push_str(name);
+        yaml.push_str(": ");
+        BASE64.encode_string(value, &mut yaml);
+        yaml.push('\n');
+    }
+    yaml
+}
+
+/// The credentials as the Terraform variables `names`, in HCL or JSON.
+fn tfvars(
+    vars: &[(&str, &str)],
+    names: &[String],
+    json: bool,
+) -> Result<Zeroizing<String>, CliError> {
+    // Each variable's name part and quoted value.
+    let mut lines = Vec::new();
+    for (var, name) in TFVARS_VARS.iter().zip(names) {
+        let Some((_, value)) = vars.iter().find(|(n, _)| n == var) else {
+            continue;
+        };
+        lines.push(if json {
+            (
+                format!(
+                    "  {}: ",
+                    serde_json::to_string(name).map_err(io::Error::other)?
+                ),
+                json_quote(value),
+            )
+        } else {
+            (format!("{name} = "), hcl_quote(value))
+        });
+    }
+    let (open, separator, close) = if json {
+        ("{\n", ",\n", "\n}\n")
+    } else {
+        ("", "\n", "\n")
+    };
+    // Sized before the secrets go in, so the buffer never moves with them.
+    let len = open.len()
+        + close.len()
+        + lines
+            .iter()
+            .map(|(name, value)| name.len() + value.expose().len() + separator.len())
+            .sum::<usize>();
+    let mut contents = Zeroizing::new(String::with_capacity(len));
+    contents.push_str(open);
+    for (i, (name, value)) in lines.iter().enumerate() {
+        if i > 0 {
+            contents.push_str(separator);
+        }
+        contents.push_str(name);
+        contents.push_str(value.expose());
+    }
+    if !lines.is_empty() || json {
+        contents.push_str(close);
+    }
+    Ok(contents)
+}
+
+// JSON escapes of the control characters, by code.
+const JSON_CONTROL: [&str; 32] = [
+    "\\u0000", "\\u0001", "\\u0002", "\\u0003", "\\u0004", "\\u0005", "\\u0006", "\\u0007", "\\b",
+    "\\t", "\\n", "\\u000b", "\\f", "\\r", "\\u000e", "\\u000f", "\\u0010", "\\u0011", "\\u0012",
+    "\\u0013", "\\u0014", "\\u0015", "\\u0016", "\\u0017", "\\u0018", "\\u0019", "\\u001a",
+    "\\u001b", "\\u001c", "\\u001d", "\\u001e", "\\u001f",
+];
+
+/// `value` as a JSON string literal, as `serde_json` writes it.
+fn json_quote(value: &str) -> Secret {
+    Secret::new(escape(value, "\"", |c, _| match c {
+        '"' => Some("\\\""),
+        '\\' => Some("\\\\"),
+        c if u32::from(c) < 0x20 => Some(JSON_CONTROL[c as usize]),
+        _ => None,
+    }))
+}
+
+/// `value` as an HCL string literal, with template sequences escaped so it is
+/// taken literally.
+fn hcl_quote(value: &str) -> Secret {
+    Secret::new(escape(value, "\"", |c, next| match (c, next) {
+        ('\\', _) => Some("\\\\"),
+        ('"', _) => Some("\\\""),
+        ('\n', _) => Some("\\n"),
+        ('\r', _) => Some("\\r"),
+        ('\t', _) => Some("\\t"),
+        ('$', Some('{')) => Some("$$"),
+        ('%', Some('{')) => Some("%%"),
+        _ => None,
+    }))
+}
+
+/// `NAME=value` lines for a GitHub Actions env file, with a multiline value
+/// written as `NAME<<DELIMITER` ... `DELIMITER` and a random delimiter.
+fn github_env(vars: &[(&str, &str)]) -> Result<Zeroizing<String>, CliError> {
+    let mut delimiters = Vec::new();
+    for (_, value) in vars {
+        delimiters.push(if value.contains(['\n', '\r']) {
+            Some(loop {
+                let delimiter = format!("ghadelimiter_{}", random_token()?);
+                if !value.contains(&delimiter) {
+                    break delimiter;
+                }
+            })
+        } else {
+            None
+        });
+    }
+    // Sized before the secrets go in, so the buffer never moves with them.
+    let len = vars
+        .iter()
+        .zip(&delimiters)
+        .map(|((name, value), delimiter)| {
+            name.len() + value.len() + delimiter.as_ref().map_or(2, |d| 2 * d.len() + 5)
+        })
+        .sum();
+    let mut contents = Zeroizing::new(String::with_capacity(len));
+    for ((name, value), delimiter) in vars.iter().zip(&delimiters) {
+        contents.push_str(name);
+        match delimiter {
+            Some(delimiter) => {
+                contents.push_str("<<");
+                contents.push_str(delimiter);
+                contents.push('\n');
+                contents.push_str(value);
+                contents.push('\n');
+                contents.push_str(delimiter);
+            }
+            None => {
+                contents.push('=');
+                contents.push_str(value);
+            }
+        }
+        contents.push('\n');
+    }
+    Ok(contents)
+}
+
+/// Tell GitHub Actions to mask the secrets, then append the variables to
+/// the env file the runner reads after the step.
+fn append_github_env(path: &Path, vars: &[(&str, &str)]) -> Result<(), CliError> {
+    let mut stdout = io::stdout().lock();
+    for (name, value) in vars {
+        if MASKED_VARS.contains(name) {
+            for line in value.lines() {
+                writeln!(stdout, "::add-mask::{line}")?;
+            }
+        }
+    }
+    stdout.flush()?;
+
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)?
+        .write_all(github_env(vars)?.as_bytes())?;
+    status!("appended to {}", path.display());
+    Ok(())
+}
+
+/// The key a dotenv line assigns, if any, ignoring an `export ` prefix.
+fn dotenv_key(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    if line.starts_with('#') {
+        return None;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    line.split_once('=').map(|(key, _)| key.trim())
+}
+
+/// `existing` with each of `vars` set, its value formatted by `quote`: a
+/// key's first line is replaced in place and its repeats dropped, keys not
+/// yet present are appended, and every other line is kept as it is.
+fn merge_lines(
+    existing: &str,
+    vars: &[(&str, &str)],
+    quote: impl Fn(&str) -> Result<Secret, CliError>,
+) -> Result<Zeroizing<String>, CliError> {
+    let mut merged = Zeroizing::new(String::with_capacity(existing.len()));
+    let line = |merged: &mut String, name: &str, value: &str| {
+        merged.push_str(name);
+        merged.push('=');
+        merged.push_str(quote(value)?.expose());
+        merged.push('\n');
+        Ok::<_, CliError>(())
+    };
+    let mut written = Vec::new();
+    for existing_line in existing.split_inclusive('\n') {
+        let var =
+            dotenv_key(existing_line).and_then(|key| vars.iter().find(|(name, _)| *name == key));
+        match var {
+            Some((name, value)) => {
+                if !written.contains(name) {
+                    line(&mut merged, name, value)?;
+                    written.push(*name);
+                }
+            }
+            None => merged.push_str(existing_line),
+        }
+    }
+    if !merged.is_empty() && !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+    for (name, value) in vars {
+        if !written.contains(name) {
+            line(&mut merged, name, value)?;
+        }
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARS: [(&str, &str); 3] = [
+        ("AWS_ACCESS_KEY_ID", "AKIATEST"),
+        ("AWS_SECRET_ACCESS_KEY", "se/cr+et="),
+        ("AWS_SESSION_TOKEN", "to ken"),
+    ];
+
+    fn options() -> OutputOptions {
+        OutputOptions {
+            k8s_name: "aws-credentials".to_string(),
+            k8s_namespace: None,
+            tfvars_names: parse_tfvars_names("aws_access_key,aws_secret_key,aws_token").unwrap(),
+        }
+    }
+
+    fn render(format: OutputFormat, existing: &str, vars: &[(&str, &str)]) -> String {
+        let output = Output { format, path: None };
+        output
+            .render(vars, &options(), existing)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(
+            parse_output("dotenv").unwrap(),
+            Output {
+                format: OutputFormat::Dotenv,
+                path: None
+            }
+        );
+        assert_eq!(
+            parse_output("dotenv=.env").unwrap().path,
+            Some(PathBuf::from(".env"))
+        );
+        assert_eq!(
+            parse_output("dotenv=a=b").unwrap().path,
+            Some(PathBuf::from("a=b"))
+        );
+        assert_eq!(
+            parse_output("docker-env=session.env").unwrap().format,
+            OutputFormat::DockerEnv
+        );
+        assert!(parse_output("docker-args=args").is_err());
+        assert!(parse_output("dotenv=").is_err());
+        assert!(parse_output("yaml").is_err());
+    }
+
+    #[test]
+    fn test_dotenv_quote() {
+        assert_eq!(dotenv_quote("AKIA/x+y==").expose(), "AKIA/x+y==");
+        assert_eq!(dotenv_quote("").expose(), "''");
+        assert_eq!(dotenv_quote("a b#c$d\"e").expose(), "'a b#c$d\"e'");
+        assert_eq!(dotenv_quote("it's $x").expose(), "\"it's \\$x\"");
+        assert_eq!(dotenv_quote("a\\b\nc\"d'").expose(), "\"a\\\\b\\nc\\\"d'\"");
+    }
+
+    #[test]
+    fn test_dotenv_new_file() {
+        assert_eq!(
+            render(OutputFormat::Dotenv, "", &VARS),
+            "AWS_ACCESS_KEY_ID=AKIATEST\n\
+             AWS_SECRET_ACCESS_KEY=se/cr+et=\n\
+             AWS_SESSION_TOKEN='to ken'\n"
+        );
+    }
+
+    #[test]
+    fn test_dotenv_merge_keeps_other_lines() {
+        let existing = "# app settings\n\
+                        DATABASE_URL=postgres://localhost/app\n\
+                        export AWS_ACCESS_KEY_ID=OLD\n\
+                        \n\
+                        AWS_SESSION_TOKEN = old\n\
+                        AWS_ACCESS_KEY_ID=DUPLICATE\n\
+                        #AWS_SECRET_ACCESS_KEY=commented\n\
+                        DEBUG=1";
+        assert_eq!(
+            render(OutputFormat::Dotenv, existing, &VARS),
+            "# app settings\n\
+             DATABASE_URL=postgres://localhost/app\n\
+             AWS_ACCESS_KEY_ID=AKIATEST\n\
+             \n\
+             AWS_SESSION_TOKEN='to ken'\n\
+             #AWS_SECRET_ACCESS_KEY=commented\n\
+             DEBUG=1\n\
+             AWS_SECRET_ACCESS_KEY=se/cr+et=\n"
+        );
+    }
+
+    #[test]
+    fn test_docker_args() {
+        assert_eq!(
+            render(OutputFormat::DockerArgs, "", &VARS),
+            "--env AWS_ACCESS_KEY_ID=AKIATEST --env AWS_SECRET_ACCESS_KEY=se/cr+et= \
+             --env 'AWS_SESSION_TOKEN=to ken'\n"
+        );
+        assert_eq!(
+            render(OutputFormat::DockerArgs, "", &[("A", "it's")]),
+            "--env 'A=it'\\''s'\n"
+        );
+    }
+
+    #[test]
+    fn test_docker_env() {
+        assert_eq!(
+            docker_env(&VARS).unwrap().as_str(),
+            "AWS_ACCESS_KEY_ID=AKIATEST\n\
+             AWS_SECRET_ACCESS_KEY=se/cr+et=\n\
+             AWS_SESSION_TOKEN=to ken\n"
+        );
+        assert_eq!(
+            render(
+                OutputFormat::DockerEnv,
+                "# keep\nAWS_SESSION_TOKEN=old\n",
+                &VARS[2..]
+            ),
+            "# keep\nAWS_SESSION_TOKEN=to ken\n"
+        );
+        assert!(docker_env(&[("A", "a\nb")]).is_err());
+    }
+
+    #[test]
+    fn test_k8s_secret() {
+        let output = Output {
+            format: OutputFormat::K8sSecret,
+            path: None,
+        };
+        let options = OutputOptions {
+            k8s_name: "aws-credentials".to_string(),
+            k8s_namespace: Some("dev".to_string()),
+            ..options()
+        };
+        let vars = [
+            ("AWS_ACCESS_KEY_ID", "AKIATEST"),
+            (AWS_SESSION_EXPIRATION, "2024-01-01T13:00:00Z"),
+        ];
+        assert_eq!(
+            output.render(&vars, &options, "ignored").unwrap().as_str(),
+            "apiVersion: v1\n\
+             kind: Secret\n\
+             metadata:\n  \
+               name: aws-credentials\n  \
+               namespace: dev\n  \
+               annotations:\n    \
+                 aws-mfa-session/expiration: \"2024-01-01T13:00:00Z\"\n\
+             type: Opaque\n\
+             data:\n  \
+               AWS_ACCESS_KEY_ID: QUtJQVRFU1Q=\n  \
+               AWS_SESSION_EXPIRATION: MjAyNC0wMS0wMVQxMzowMDowMFo=\n"
+        );
+        assert!(!render(OutputFormat::K8sSecret, "", &VARS).contains("namespace"));
+    }
+
+    #[test]
+    fn test_parse_k8s_names() {
+        assert_eq!(parse_k8s_name("aws.creds-1").unwrap(), "aws.creds-1");
+        assert!(parse_k8s_name("AWS").is_err());
+        assert!(parse_k8s_name("-aws").is_err());
+        assert!(parse_k8s_name(&"a".repeat(254)).is_err());
+        assert_eq!(parse_k8s_namespace("team-a").unwrap(), "team-a");
+        assert!(parse_k8s_namespace("team.a").is_err());
+        assert!(parse_k8s_namespace("").is_err());
+    }
+
+    #[test]
+    fn test_json_quote_matches_serde_json() {
+        let value: String = (0..0x80u8).map(char::from).chain(['é', '😀']).collect();
+        assert_eq!(
+            json_quote(&value).expose(),
+            serde_json::to_string(&value).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tfvars() {
+        let vars = [
+            ("AWS_ACCESS_KEY_ID", "AKIATEST"),
+            ("AWS_SECRET_ACCESS_KEY", "se\"cr\\et"),
+            ("AWS_SESSION_TOKEN", "${tok}%{en}\n"),
+            (AWS_SESSION_EXPIRATION, "2024-01-01T13:00:00Z"),
+        ];
+        assert_eq!(
+            render(OutputFormat::Tfvars, "ignored", &vars),
+            "aws_access_key = \"AKIATEST\"\n\
+             aws_secret_key = \"se\\\"cr\\\\et\"\n\
+             aws_token = \"$${tok}%%{en}\\n\"\n"
+        );
+        assert_eq!(
+            render(OutputFormat::TfvarsJson, "", &vars),
+            "{\n  \
+               \"aws_access_key\": \"AKIATEST\",\n  \
+               \"aws_secret_key\": \"se\\\"cr\\\\et\",\n  \
+               \"aws_token\": \"${tok}%{en}\\n\"\n\
+             }\n"
+        );
+
+        let output = Output {
+            format: OutputFormat::Tfvars,
+            path: None,
+        };
+        let options = OutputOptions {
+            tfvars_names: parse_tfvars_names("key, secret, token").unwrap(),
+            ..options()
+        };
+        let contents = output.render(&VARS, &options, "").unwrap();
+        assert!(contents.starts_with("key = \"AKIATEST\"\nsecret = "));
+    }
+
+    #[test]
+    fn test_parse_tfvars_names() {
+        assert_eq!(parse_tfvars_names("_a,b-1,c").unwrap(), ["_a", "b-1", "c"]);
+        assert!(parse_tfvars_names("a,b").is_err());
+        assert!(parse_tfvars_names("a,b,c,d").is_err());
+        assert!(parse_tfvars_names("a,1b,c").is_err());
+        assert!(parse_tfvars_names("a,b.c,d").is_err());
+        assert!(parse_tfvars_names("a,,c").is_err());
+    }
+
+    #[test]
+    fn test_github_env() {
+        assert_eq!(
+            render(OutputFormat::GithubEnv, "", &VARS),
+            "AWS_ACCESS_KEY_ID=AKIATEST\n\
+             AWS_SECRET_ACCESS_KEY=se/cr+et=\n\
+             AWS_SESSION_TOKEN=to ken\n"
+        );
+        let contents = render(OutputFormat::GithubEnv, "", &[("A", "one\ntwo")]);
+        let (head, rest) = contents.split_once('\n').unwrap();
+        let delimiter = head.strip_prefix("A<<").unwrap();
+        assert!(delimiter.starts_with("ghadelimiter_"));
+        assert_eq!(rest, format!("one\ntwo\n{delimiter}\n"));
+        for vars in [&VARS[..], &[("A", "one\ntwo")]] {
+            let contents = github_env(vars).unwrap();
+            assert_eq!(contents.capacity(), contents.len());
+        }
+    }
+
+    #[test]
+    fn test_resolve_ci() {
+        let ci = parse_output("ci").unwrap();
+        let github = |name: &str| match name {
+            "GITHUB_ACTIONS" => Some("true".to_string()),
+            "GITHUB_ENV" => Some("/runner/env".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            ci.resolve(github).unwrap(),
+            Output {
+                format: OutputFormat::GithubEnv,
+                path: Some(PathBuf::from("/runner/env"))
+            }
+        );
+        let gitlab = |name: &str| (name == "GITLAB_CI").then(|| "true".to_string());
+        assert_eq!(
+            ci.resolve(gitlab).unwrap(),
+            Output {
+                format: OutputFormat::Dotenv,
+                path: None
+            }
+        );
+        assert!(ci.resolve(|_| None).is_err());
+        assert!(
+            parse_output("github-env")
+                .unwrap()
+                .resolve(|_| None)
+                .is_err()
+        );
+        assert_eq!(
+            parse_output("github-env=env")
+                .unwrap()
+                .resolve(github)
+                .unwrap()
+                .path,
+            Some(PathBuf::from("env"))
+        );
+    }
+
+    #[test]
+    fn test_github_env_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("github_env");
+        fs::write(&path, "EARLIER=1\n").unwrap();
+        let output = parse_output(&format!("github-env={}", path.display())).unwrap();
+        output.write(&VARS, &options()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "EARLIER=1\n\
+             AWS_ACCESS_KEY_ID=AKIATEST\n\
+             AWS_SECRET_ACCESS_KEY=se/cr+et=\n\
+             AWS_SESSION_TOKEN=to ken\n"
+        );
+    }
+
+    #[test]
+    fn test_dotenv_write_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".env");
+        fs::write(&path, "APP=1\n").unwrap();
+        let output = Output {
+            format: OutputFormat::Dotenv,
+            path: Some(path.clone()),
+        };
+        output.write(&VARS, &options()).unwrap();
+        output
+            .write(&[("AWS_ACCESS_KEY_ID", "AKIANEW")], &options())
+            .unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("APP=1\nAWS_ACCESS_KEY_ID=AKIANEW\n"));
+        assert_eq!(contents.matches("AWS_ACCESS_KEY_ID").count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_whole_file_formats_ignore_existing_file() {
+        // Not read at all, so even a file that is not UTF-8 is replaced.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.auto.tfvars");
+        fs::write(&path, [0xff, 0xfe, b'\n']).unwrap();
+        let output = Output {
+            format: OutputFormat::Tfvars,
+            path: Some(path.clone()),
+        };
+        output.write(&VARS, &options()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "aws_access_key = \"AKIATEST\"\n\
+             aws_secret_key = \"se/cr+et=\"\n\
+             aws_token = \"to ken\"\n"
+        );
+    }
+}
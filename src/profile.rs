@@ -1,19 +1,25 @@
 use ini::Ini;
 
-/// Read MFA serial from AWS profile configuration using INI parsing
-pub fn get_mfa_serial_from_profile(profile_name: Option<&str>) -> Option<String> {
-    let profile_name = profile_name.unwrap_or("default");
-
-    // Use the same environment variable logic as AWS SDK for file paths
-    let config_path = match std::env::var("AWS_CONFIG_FILE") {
-        Ok(path) => path,
+/// The AWS config file: AWS_CONFIG_FILE, else ~/.aws/config, as the AWS SDK
+/// resolves it.
+pub(crate) fn aws_config_path() -> Option<String> {
+    match std::env::var("AWS_CONFIG_FILE") {
+        Ok(path) => Some(path),
         Err(_) => {
             let mut home = dirs::home_dir()?;
             home.push(".aws");
             home.push("config");
-            home.to_string_lossy().to_string()
+            Some(home.to_string_lossy().to_string())
         }
-    };
+    }
+}
+
+/// Read MFA serial from AWS profile configuration using INI parsing
+pub fn get_mfa_serial_from_profile(profile_name: Option<&str>) -> Option<String> {
+    let profile_name = profile_name.unwrap_or("default");
+
+    // Use the same environment variable logic as AWS SDK for file paths
+    let config_path = aws_config_path()?;
 
     let credentials_path = match std::env::var("AWS_SHARED_CREDENTIALS_FILE") {
         Ok(path) => path,
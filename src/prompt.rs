@@ -0,0 +1,237 @@
+use crate::error::CliError;
+
+/// Prompt used when neither `--prompt` nor the config file sets one.
+pub const DEFAULT_PROMPT: &str = "AWS:{user}@{account} \\$ ";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    User,
+    Account,
+    AccountAlias,
+    Profile,
+    Region,
+    ExpiresIn,
+    Role,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "user" => Some(Field::User),
+            "account" => Some(Field::Account),
+            "account_alias" => Some(Field::AccountAlias),
+            "profile" => Some(Field::Profile),
+            "region" => Some(Field::Region),
+            "expires_in" => Some(Field::ExpiresIn),
+            "role" => Some(Field::Role),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// Split a template into literal text and `{placeholder}`s. `{{` and `}}` are
+/// literal braces; an unknown or unterminated placeholder is an error.
+fn parse(template: &str) -> Result<Vec<Segment>, CliError> {
+    let invalid = |msg: String| CliError::ValidationError(format!("prompt template: {msg}"));
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(invalid(format!("unterminated placeholder {{{name}"))),
+                    }
+                }
+                let field = Field::parse(&name)
+                    .ok_or_else(|| invalid(format!("unknown placeholder {{{name}}}")))?;
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Field(field));
+            }
+            '}' => {
+                return Err(invalid(
+                    "unmatched '}' (use '}}' for a literal brace)".to_string(),
+                ));
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Check a template without rendering it, for argument validation.
+pub fn validate_template(template: &str) -> Result<String, CliError> {
+    parse(template).map(|_| template.to_string())
+}
+
+/// Format a remaining lifetime compactly, e.g. `1h30m`, `12h`, `45m`.
+pub fn format_duration(secs: i64) -> String {
+    let minutes = secs.max(0) / 60;
+    let (h, m) = (minutes / 60, minutes % 60);
+    match (h, m) {
+        (0, m) => format!("{m}m"),
+        (h, 0) => format!("{h}h"),
+        (h, m) => format!("{h}h{m}m"),
+    }
+}
+
+/// Values substituted into the prompt template.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PromptValues {
+    pub user: String,
+    pub account: String,
+    pub account_alias: Option<String>,
+    pub profile: String,
+    pub region: Option<String>,
+    /// Session lifetime in seconds at the time it was issued.
+    pub expires_in: i64,
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Prompt {
+    segments: Vec<Segment>,
+    values: PromptValues,
+    /// Put the rendered prompt in front of the existing one instead of replacing it.
+    pub prepend: bool,
+}
+
+impl Prompt {
+    pub fn new(template: &str, values: PromptValues, prepend: bool) -> Result<Self, CliError> {
+        Ok(Prompt {
+            segments: parse(template)?,
+            values,
+            prepend,
+        })
+    }
+
+    /// Render the template. Literal template text is emitted verbatim (the user
+    /// wrote it for their shell); substituted values go through `escape` so that
+    /// e.g. a `$` in a profile name is not expanded by the shell's prompt.
+    pub fn render(&self, escape: impl Fn(&str) -> String) -> String {
+        let v = &self.values;
+        let mut out = String::new();
+        for segment in &self.segments {
+            let value = match segment {
+                Segment::Literal(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Segment::Field(Field::User) => v.user.clone(),
+                Segment::Field(Field::Account) => v.account.clone(),
+                // Fall back to the account id so the placeholder is never blank.
+                Segment::Field(Field::AccountAlias) => {
+                    v.account_alias.clone().unwrap_or_else(|| v.account.clone())
+                }
+                Segment::Field(Field::Profile) => v.profile.clone(),
+                Segment::Field(Field::Region) => v.region.clone().unwrap_or_default(),
+                Segment::Field(Field::ExpiresIn) => format_duration(v.expires_in),
+                Segment::Field(Field::Role) => v.role.clone().unwrap_or_default(),
+            };
+            out.push_str(&escape(&value));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> PromptValues {
+        PromptValues {
+            user: "alice".to_string(),
+            account: "123456789012".to_string(),
+            account_alias: None,
+            profile: "dev".to_string(),
+            region: Some("eu-west-1".to_string()),
+            expires_in: 5400,
+            role: Some("Admin".to_string()),
+        }
+    }
+
+    fn render(template: &str) -> String {
+        Prompt::new(template, values(), false)
+            .unwrap()
+            .render(|v| v.to_string())
+    }
+
+    #[test]
+    fn test_default_prompt() {
+        assert_eq!(render(DEFAULT_PROMPT), "AWS:alice@123456789012 \\$ ");
+    }
+
+    #[test]
+    fn test_all_placeholders() {
+        assert_eq!(
+            render("{user}|{account}|{account_alias}|{profile}|{region}|{expires_in}|{role}"),
+            "alice|123456789012|123456789012|dev|eu-west-1|1h30m|Admin"
+        );
+    }
+
+    #[test]
+    fn test_account_alias_when_known() {
+        let mut v = values();
+        v.account_alias = Some("acme-dev".to_string());
+        let prompt = Prompt::new("{account_alias}", v, false).unwrap();
+        assert_eq!(prompt.render(|v| v.to_string()), "acme-dev");
+    }
+
+    #[test]
+    fn test_literal_braces() {
+        assert_eq!(render("{{{user}}}"), "{alice}");
+        assert_eq!(render("no placeholders"), "no placeholders");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(validate_template("{nope}").is_err());
+        assert!(validate_template("{user").is_err());
+        assert!(validate_template("user}").is_err());
+        assert!(validate_template("{}").is_err());
+        assert!(validate_template("[{profile}] ").is_ok());
+    }
+
+    #[test]
+    fn test_escape_applies_only_to_values() {
+        let mut v = values();
+        v.profile = "a$b".to_string();
+        let prompt = Prompt::new("$ {profile} $", v, false).unwrap();
+        assert_eq!(prompt.render(|v| v.replace('$', "\\$")), "$ a\\$b $");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(3600), "1h");
+        assert_eq!(format_duration(5400), "1h30m");
+        assert_eq!(format_duration(2700), "45m");
+        assert_eq!(format_duration(59), "0m");
+        assert_eq!(format_duration(-10), "0m");
+        assert_eq!(format_duration(129600), "36h");
+    }
+}
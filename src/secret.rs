@@ -0,0 +1,126 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+/// Secret material: a secret access key, a session token or an MFA code.
+///
+/// `Debug` and `Display` print `[REDACTED]`, so a secret never ends up in a
+/// log line or an error message by accident, and the buffer is overwritten
+/// when the value is dropped. The value itself is only reachable through
+/// [`Secret::expose`].
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Secret(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret)
+    }
+}
+
+/// `value` between two `quote`s, with each character that `replace` maps
+/// (given the character after it) replaced. The result is built in one pass
+/// into a buffer of exactly its size: chained `str::replace` calls, or a
+/// growing buffer, would leave copies of a secret behind that nothing wipes.
+pub(crate) fn escape(
+    value: &str,
+    quote: &str,
+    replace: impl Fn(char, Option<char>) -> Option<&'static str>,
+) -> String {
+    let replaced = |i: usize, c: char| replace(c, value[i + c.len_utf8()..].chars().next());
+    let len = value
+        .char_indices()
+        .map(|(i, c)| replaced(i, c).map_or(c.len_utf8(), str::len))
+        .sum::<usize>()
+        + 2 * quote.len();
+    let mut escaped = String::with_capacity(len);
+    escaped.push_str(quote);
+    for (i, c) in value.char_indices() {
+        match replaced(i, c) {
+            Some(replacement) => escaped.push_str(replacement),
+            None => escaped.push(c),
+        }
+    }
+    escaped.push_str(quote);
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::from("wJalrXUtnFEMI");
+        assert_eq!(format!("{secret}"), "[REDACTED]");
+        assert_eq!(format!("{secret:?}"), "[REDACTED]");
+        assert_eq!(format!("{:?}", Some(secret.clone())), "Some([REDACTED])");
+        assert_eq!(secret.expose(), "wJalrXUtnFEMI");
+    }
+
+    #[test]
+    fn test_escape_fills_exact_buffer() {
+        let escaped = escape("a'b${c", "\"", |c, next| match (c, next) {
+            ('\'', _) => Some("\\'"),
+            ('$', Some('{')) => Some("$$"),
+            _ => None,
+        });
+        assert_eq!(escaped, "\"a\\'b$${c\"");
+        assert_eq!(escaped.capacity(), escaped.len());
+        assert_eq!(escape("", "", |_, _| None), "");
+    }
+
+    #[test]
+    fn test_secret_serde_is_transparent() {
+        let json = serde_json::to_string(&Secret::from("token")).unwrap();
+        assert_eq!(json, r#""token""#);
+        let secret: Secret = serde_json::from_str(&json).unwrap();
+        assert_eq!(secret.expose(), "token");
+    }
+}
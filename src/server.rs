@@ -0,0 +1,514 @@
+use crate::error::CliError;
+use crate::session::{Session, SessionSource};
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+/// Path the credentials are served at.
+pub const CREDENTIALS_PATH: &str = "/credentials";
+
+// A credentials request is a bare GET; anything larger is not one.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+/// Where `serve` listens: a loopback `HOST:PORT`, or `unix:PATH`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Parse `--listen`. TCP addresses must be loopback: the endpoint hands out
+/// credentials to anyone holding the token, so it is never exposed off-host.
+pub fn parse_listen(s: &str) -> Result<Listen, CliError> {
+    if let Some(path) = s.strip_prefix("unix:") {
+        #[cfg(unix)]
+        return Ok(Listen::Unix(PathBuf::from(path)));
+        #[cfg(not(unix))]
+        return Err(CliError::ValidationError(format!(
+            "unix sockets are not supported on this platform: {path}"
+        )));
+    }
+    parse_loopback(s).map(Listen::Tcp)
+}
+
+/// Parse a `HOST:PORT` that must be on a loopback interface.
+pub fn parse_loopback(s: &str) -> Result<SocketAddr, CliError> {
+    let addr: SocketAddr = s
+        .parse()
+        .map_err(|_| CliError::ValidationError(format!("invalid listen address: {s}")))?;
+    if !addr.ip().is_loopback() {
+        return Err(CliError::ValidationError(format!(
+            "listen address must be loopback (e.g. 127.0.0.1), got {}",
+            addr.ip()
+        )));
+    }
+    Ok(addr)
+}
+
+/// Random bearer token clients must send in the `Authorization` header.
+pub fn random_token() -> Result<String, CliError> {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes)
+        .map_err(|e| CliError::ValidationError(format!("cannot generate token: {e}")))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// The current session, re-minted from `source` once it has less than
+/// `refresh_before` seconds left.
+pub struct CredentialCache<S> {
+    source: S,
+    session: Mutex<Option<Session>>,
+    refresh_before: i64,
+}
+
+impl<S: SessionSource> CredentialCache<S> {
+    pub fn new(source: S, refresh_before: i64) -> Self {
+        CredentialCache {
+            source,
+            session: Mutex::new(None),
+            refresh_before,
+        }
+    }
+
+    /// The lock is held while minting, so concurrent requests wait for one new
+    /// session (and one MFA prompt) instead of each starting their own.
+    pub async fn get(&self) -> Result<Session, CliError> {
+        let mut session = self.session.lock().await;
+        if let Some(current) = session.as_ref()
+            && current.expires_in() > self.refresh_before
+        {
+            return Ok(current.clone());
+        }
+        let fresh = self.source.next_session().await?;
+        *session = Some(fresh.clone());
+        Ok(fresh)
+    }
+
+    /// Renew the session ahead of expiry, so that the MFA prompt happens in the
+    /// background rather than while a client waits on a request.
+    pub async fn keep_fresh(&self) {
+        loop {
+            let wait = match self.get().await {
+                Ok(session) => session.expires_in() - self.refresh_before,
+                Err(e) => {
+                    tracing::warn!(error = %e, "cannot renew session");
+                    30
+                }
+            };
+            tokio::time::sleep(Duration::from_secs(wait.max(1) as u64)).await;
+        }
+    }
+}
+
+/// Body of the container credentials endpoint, as the SDKs expect it.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct EcsCredentials<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    token: &'a str,
+    expiration: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(body: String) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn text(status: u16, body: String) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            headers: Vec::new(),
+            body: serde_json::json!({ "message": message }).to_string(),
+        }
+    }
+}
+
+/// Answers one HTTP request; the server handles connections and framing.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request) -> impl Future<Output = Response> + Send;
+}
+
+async fn read_request<R: AsyncRead + Unpin>(reader: R) -> io::Result<Request> {
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST));
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed request");
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().ok_or_else(invalid)?.to_string();
+    let target = parts.next().ok_or_else(invalid)?;
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(invalid());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(Request {
+        method,
+        path,
+        headers,
+    })
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    response: &Response,
+) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(response.body.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+// Compare without an early exit, so response timing does not reveal how much
+// of a guessed token was right.
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Serves the container credentials endpoint (`AWS_CONTAINER_CREDENTIALS_FULL_URI`).
+pub struct EcsHandler<S> {
+    cache: Arc<CredentialCache<S>>,
+    token: String,
+}
+
+impl<S: SessionSource> EcsHandler<S> {
+    pub fn new(cache: Arc<CredentialCache<S>>, token: String) -> Self {
+        EcsHandler { cache, token }
+    }
+}
+
+impl<S: SessionSource> Handler for EcsHandler<S> {
+    async fn handle(&self, request: &Request) -> Response {
+        if request.path != CREDENTIALS_PATH {
+            return Response::error(404, "not found");
+        }
+        if request.method != "GET" {
+            return Response::error(405, "method not allowed");
+        }
+        match request.header("Authorization") {
+            Some(token) if token_matches(token, &self.token) => {}
+            _ => return Response::error(401, "invalid authorization token"),
+        }
+
+        let session = match self.cache.get().await {
+            Ok(session) => session,
+            Err(e) => {
+                tracing::warn!(error = %e, "cannot serve credentials");
+                return Response::error(500, "cannot mint session");
+            }
+        };
+        let body = session.expiration_rfc3339().and_then(|expiration| {
+            serde_json::to_string(&EcsCredentials {
+                access_key_id: &session.access_key_id,
+                secret_access_key: session.secret_access_key.expose(),
+                token: session.session_token.expose(),
+                expiration,
+            })
+            .map_err(|e| CliError::ValidationError(e.to_string()))
+        });
+        match body {
+            Ok(body) => Response::json(body),
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+}
+
+async fn connection<H: Handler, T: AsyncRead + AsyncWrite + Unpin>(
+    handler: &H,
+    stream: T,
+) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let response = match read_request(reader).await {
+        Ok(request) => handler.handle(&request).await,
+        Err(_) => Response::error(400, "bad request"),
+    };
+    write_response(writer, &response).await
+}
+
+/// Accept connections until the listener fails, one task per connection.
+pub async fn serve_tcp<H: Handler>(listener: TcpListener, handler: Arc<H>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection(&*handler, stream).await {
+                tracing::debug!(error = %e, "connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+pub async fn serve_unix<H: Handler>(
+    listener: tokio::net::UnixListener,
+    handler: Arc<H>,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection(&*handler, stream).await {
+                tracing::debug!(error = %e, "connection failed");
+            }
+        });
+    }
+}
+
+/// Bind a Unix socket only the current user can connect to. A stale socket
+/// left by a previous run is replaced; any other file at `path` is not.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    // Create the socket 0600 rather than tightening it after bind, which
+    // leaves a window for other users in a shared directory. The umask is
+    // process-wide, but restricting it briefly only makes files created
+    // meanwhile more private.
+    // SAFETY: umask has no preconditions and cannot fail
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = tokio::net::UnixListener::bind(path);
+    // SAFETY: as above
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::tests::FakeSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    fn cache(lifetime: i64, refresh_before: i64) -> Arc<CredentialCache<FakeSource>> {
+        let source = FakeSource {
+            minted: AtomicUsize::new(0),
+            lifetime,
+        };
+        Arc::new(CredentialCache::new(source, refresh_before))
+    }
+
+    async fn start(cache: Arc<CredentialCache<FakeSource>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = Arc::new(EcsHandler::new(cache, "secret-token".to_string()));
+        tokio::spawn(serve_tcp(listener, handler));
+        addr
+    }
+
+    async fn get(addr: SocketAddr, path: &str, token: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let auth = token
+            .map(|t| format!("Authorization: {t}\r\n"))
+            .unwrap_or_default();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n{auth}\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(
+            parse_listen("127.0.0.1:9911").unwrap(),
+            Listen::Tcp("127.0.0.1:9911".parse().unwrap())
+        );
+        assert!(parse_listen("[::1]:0").is_ok());
+        assert!(parse_listen("0.0.0.0:9911").is_err());
+        assert!(parse_listen("192.168.1.10:9911").is_err());
+        assert!(parse_listen("localhost").is_err());
+        #[cfg(unix)]
+        assert_eq!(
+            parse_listen("unix:/tmp/creds.sock").unwrap(),
+            Listen::Unix(PathBuf::from("/tmp/creds.sock"))
+        );
+    }
+
+    #[test]
+    fn test_random_token() {
+        let token = random_token().unwrap();
+        assert_eq!(token.len(), 64);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, random_token().unwrap());
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("abc", "abc"));
+        assert!(!token_matches("abd", "abc"));
+        assert!(!token_matches("ab", "abc"));
+        assert!(!token_matches("", "abc"));
+    }
+
+    #[tokio::test]
+    async fn test_serves_ecs_credentials() {
+        let addr = start(cache(3600, 300)).await;
+        let (status, body) = get(addr, "/credentials", Some("secret-token")).await;
+        assert_eq!(status, 200);
+
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["AccessKeyId"], "ASIA1");
+        assert_eq!(json["SecretAccessKey"], "secret");
+        assert_eq!(json["Token"], "token");
+        assert!(json["Expiration"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_requests() {
+        let addr = start(cache(3600, 300)).await;
+        assert_eq!(get(addr, "/credentials", None).await.0, 401);
+        assert_eq!(get(addr, "/credentials", Some("wrong")).await.0, 401);
+        assert_eq!(get(addr, "/other", Some("secret-token")).await.0, 404);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn test_reuses_session_until_refresh_window() {
+        let cache = cache(3600, 300);
+        let addr = start(cache.clone()).await;
+        for _ in 0..3 {
+            let (_, body) = get(addr, "/credentials", Some("secret-token")).await;
+            assert!(body.contains("ASIA1"));
+        }
+        assert_eq!(cache.source.minted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_renews_session_near_expiry() {
+        // Every session is already inside the refresh window, so each request
+        // mints a new one.
+        let cache = cache(200, 300);
+        let addr = start(cache.clone()).await;
+        let (_, first) = get(addr, "/credentials", Some("secret-token")).await;
+        let (_, second) = get(addr, "/credentials", Some("secret-token")).await;
+        assert!(first.contains("ASIA1"));
+        assert!(second.contains("ASIA2"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serves_over_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("creds.sock");
+        let listener = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let handler = Arc::new(EcsHandler::new(cache(3600, 300), "t".to_string()));
+        tokio::spawn(serve_unix(listener, handler));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /credentials HTTP/1.1\r\nauthorization: t\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("\"AccessKeyId\":\"ASIA1\""));
+
+        // A stale socket from an earlier run is replaced.
+        assert!(bind_unix(&path).is_ok());
+    }
+}
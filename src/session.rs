@@ -0,0 +1,336 @@
+use crate::args::ask_code_interactive;
+use crate::audit::Auditor;
+use crate::error::CliError;
+use crate::identity::Identity;
+use crate::profile::get_mfa_serial_from_profile;
+use crate::secret::Secret;
+
+use std::env;
+use std::future::Future;
+use std::time::SystemTime;
+
+use aws_config::{BehaviorVersion, Region, SdkConfig, meta::credentials::CredentialsProviderChain};
+use aws_sdk_iam::Client;
+use aws_sdk_sts::Client as StsClient;
+use aws_sdk_sts::config::ProvideCredentials;
+use aws_sdk_sts::primitives::{DateTime, DateTimeFormat};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OnceCell};
+
+const AWS_DEFAULT_REGION: &str = "AWS_DEFAULT_REGION";
+
+/// Temporary credentials returned by `GetSessionToken`.
+#[derive(Clone)]
+pub struct Session {
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+    pub session_token: Secret,
+    pub expiration: DateTime,
+}
+
+impl Session {
+    /// Expiration as an RFC 3339 timestamp, e.g. `2024-01-01T13:00:00Z`.
+    pub fn expiration_rfc3339(&self) -> Result<String, CliError> {
+        self.expiration
+            .fmt(DateTimeFormat::DateTime)
+            .map_err(|e| CliError::ValidationError(format!("invalid session expiration: {e}")))
+    }
+
+    /// Seconds until the session expires; negative once it has.
+    pub fn expires_in(&self) -> i64 {
+        self.expiration.secs() - DateTime::from(SystemTime::now()).secs()
+    }
+}
+
+/// A session in serialized form, for the agent protocol and the keyring,
+/// with the expiration in epoch seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireSession {
+    pub access_key_id: String,
+    pub secret_access_key: Secret,
+    pub session_token: Secret,
+    pub expiration: i64,
+}
+
+impl From<&Session> for WireSession {
+    fn from(session: &Session) -> Self {
+        WireSession {
+            access_key_id: session.access_key_id.clone(),
+            secret_access_key: session.secret_access_key.clone(),
+            session_token: session.session_token.clone(),
+            expiration: session.expiration.secs(),
+        }
+    }
+}
+
+impl From<WireSession> for Session {
+    fn from(wire: WireSession) -> Self {
+        Session {
+            access_key_id: wire.access_key_id,
+            secret_access_key: wire.secret_access_key,
+            session_token: wire.session_token,
+            expiration: DateTime::from_secs(wire.expiration),
+        }
+    }
+}
+
+/// Load the SDK configuration the session is minted with: the region from
+/// `region`, the environment/profile, `AWS_DEFAULT_REGION` or `us-east-1`, and
+/// the default credentials chain (which honours `AWS_PROFILE`).
+#[tracing::instrument(skip_all)]
+pub async fn load_config(region: Option<Region>) -> SdkConfig {
+    let region_provider = aws_config::meta::region::RegionProviderChain::first_try(region)
+        .or_default_provider()
+        .or_else(env::var(AWS_DEFAULT_REGION).ok().map(Region::new))
+        .or_else(Region::new("us-east-1"));
+
+    let credentials_provider = CredentialsProviderChain::default_provider().await;
+    aws_config::defaults(BehaviorVersion::latest())
+        .region(region_provider)
+        .credentials_provider(credentials_provider)
+        .load()
+        .await
+}
+
+/// Load the SDK configuration for one named profile: its credentials and
+/// region come from the shared config files only, whatever `AWS_PROFILE` or
+/// the credential environment variables say. `region` overrides the profile's.
+#[tracing::instrument(skip_all, fields(%profile))]
+pub async fn load_profile_config(region: Option<Region>, profile: &str) -> SdkConfig {
+    let region_provider = aws_config::meta::region::RegionProviderChain::first_try(region)
+        .or_else(
+            aws_config::profile::ProfileFileRegionProvider::builder()
+                .profile_name(profile)
+                .build(),
+        )
+        .or_else(env::var(AWS_DEFAULT_REGION).ok().map(Region::new))
+        .or_else(Region::new("us-east-1"));
+    let credentials_provider = aws_config::profile::ProfileFileCredentialsProvider::builder()
+        .profile_name(profile)
+        .build();
+    aws_config::defaults(BehaviorVersion::latest())
+        .region(region_provider)
+        .credentials_provider(credentials_provider)
+        .load()
+        .await
+}
+
+/// Mints MFA sessions for one device from one set of base credentials.
+pub struct SessionMinter {
+    config: SdkConfig,
+    serial_number: String,
+    duration: i32,
+    identity: OnceCell<Identity>,
+    audit: Option<Auditor>,
+}
+
+impl SessionMinter {
+    /// Resolve the MFA device: `arn` if given, else the profile's `mfa_serial`,
+    /// else the caller's first IAM MFA device. That last fallback is the only
+    /// IAM call, so callers that configure the device need no IAM access.
+    #[tracing::instrument(name = "mfa_device", skip_all, fields(profile = profile.unwrap_or("default")))]
+    pub async fn new(
+        config: SdkConfig,
+        arn: Option<String>,
+        profile: Option<&str>,
+        duration: i32,
+    ) -> Result<Self, CliError> {
+        let serial_number = match arn {
+            Some(arn) => {
+                tracing::debug!(serial = %arn, "MFA device from --arn");
+                arn
+            }
+            None => match get_mfa_serial_from_profile(profile) {
+                Some(mfa_serial) => {
+                    tracing::debug!(serial = %mfa_serial, "MFA device from the profile");
+                    mfa_serial
+                }
+                None => {
+                    let iam_client = Client::new(&config);
+                    let response = iam_client.list_mfa_devices().max_items(1).send().await?;
+                    let mfa_devices = response.mfa_devices();
+                    let serial = mfa_devices
+                        .first()
+                        .ok_or(CliError::NoMFA)?
+                        .serial_number()
+                        .to_owned();
+                    tracing::debug!(%serial, "MFA device from iam:ListMFADevices");
+                    serial
+                }
+            },
+        };
+        Ok(SessionMinter {
+            config,
+            serial_number,
+            duration,
+            identity: OnceCell::new(),
+            audit: None,
+        })
+    }
+
+    /// Record every session minted from now on in `audit`, if given.
+    pub fn with_audit(mut self, audit: Option<Auditor>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn config(&self) -> &SdkConfig {
+        &self.config
+    }
+
+    pub fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+
+    /// Access key id of the base credentials the sessions are minted from.
+    pub async fn source_key_id(&self) -> Result<String, CliError> {
+        let provider = self
+            .config
+            .credentials_provider()
+            .ok_or(CliError::NoCredentials)?;
+        let credentials = provider
+            .provide_credentials()
+            .await
+            .map_err(|e| CliError::ValidationError(format!("base credentials: {e}")))?;
+        Ok(credentials.access_key_id().to_string())
+    }
+
+    #[tracing::instrument(
+        name = "get_session_token",
+        skip_all,
+        fields(serial = %self.serial_number, duration = self.duration)
+    )]
+    pub async fn mint(&self, code: &str) -> Result<Session, CliError> {
+        let credentials = StsClient::new(&self.config)
+            .get_session_token()
+            .serial_number(&self.serial_number)
+            .token_code(code)
+            .duration_seconds(self.duration)
+            .send()
+            .await?
+            .credentials()
+            .map(ToOwned::to_owned)
+            .ok_or(CliError::NoCredentials)?;
+
+        tracing::info!(expiration = %credentials.expiration(), "minted session");
+        let session = Session {
+            access_key_id: credentials.access_key_id().to_owned(),
+            secret_access_key: credentials.secret_access_key().into(),
+            session_token: credentials.session_token().into(),
+            expiration: *credentials.expiration(),
+        };
+        // A session that cannot be recorded is not handed out.
+        if let Some(audit) = &self.audit {
+            audit.record(&self.serial_number, self.duration, self.identity().await?)?;
+        }
+        Ok(session)
+    }
+
+    /// The caller behind the base credentials, from `GetCallerIdentity` rather
+    /// than iam:GetUser, which needs an extra permission and fails for roles
+    /// and federated users. Looked up once per minter.
+    #[tracing::instrument(name = "get_caller_identity", skip_all)]
+    pub async fn identity(&self) -> Result<&Identity, CliError> {
+        self.identity
+            .get_or_try_init(|| async {
+                let caller = StsClient::new(&self.config)
+                    .get_caller_identity()
+                    .send()
+                    .await?;
+                Ok(Identity::from_arn(
+                    caller.account().ok_or(CliError::NoAccount)?,
+                    caller.arn().ok_or(CliError::NoAccount)?,
+                ))
+            })
+            .await
+    }
+}
+
+/// Produces a fresh session on demand, for the long-running modes that outlive
+/// a single session. Abstracted so those modes are testable without AWS.
+pub trait SessionSource: Send + Sync + 'static {
+    fn next_session(&self) -> impl Future<Output = Result<Session, CliError>> + Send;
+}
+
+/// The interactive source: the first session uses the code given up front,
+/// every later one prompts for a new code on the terminal.
+pub struct PromptingSource {
+    minter: SessionMinter,
+    code: Mutex<Option<Secret>>,
+}
+
+impl PromptingSource {
+    pub fn new(minter: SessionMinter, code: Secret) -> Self {
+        PromptingSource {
+            minter,
+            code: Mutex::new(Some(code)),
+        }
+    }
+}
+
+impl SessionSource for PromptingSource {
+    async fn next_session(&self) -> Result<Session, CliError> {
+        let code = match self.code.lock().await.take() {
+            Some(code) => code,
+            None => {
+                eprintln!("aws-mfa-session: session is about to expire");
+                tokio::task::spawn_blocking(ask_code_interactive)
+                    .await
+                    .map_err(|e| CliError::ValidationError(e.to_string()))??
+            }
+        };
+        self.minter.mint(code.expose()).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Mints numbered sessions with a fixed lifetime, without AWS.
+    pub struct FakeSource {
+        pub minted: AtomicUsize,
+        pub lifetime: i64,
+    }
+
+    impl SessionSource for FakeSource {
+        async fn next_session(&self) -> Result<Session, CliError> {
+            let n = self.minted.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(Session {
+                access_key_id: format!("ASIA{n}"),
+                secret_access_key: "secret".into(),
+                session_token: "token".into(),
+                expiration: DateTime::from_secs(
+                    DateTime::from(SystemTime::now()).secs() + self.lifetime,
+                ),
+            })
+        }
+    }
+
+    pub fn session_expiring_in(secs: i64) -> Session {
+        Session {
+            access_key_id: "ASIATEST".to_string(),
+            secret_access_key: "secret".into(),
+            session_token: "token".into(),
+            expiration: DateTime::from_secs(DateTime::from(SystemTime::now()).secs() + secs),
+        }
+    }
+
+    #[test]
+    fn test_session_expires_in() {
+        let session = session_expiring_in(600);
+        assert!((599..=600).contains(&session.expires_in()));
+        assert!(session_expiring_in(-5).expires_in() < 0);
+    }
+
+    #[test]
+    fn test_session_expiration_rfc3339() {
+        let mut session = session_expiring_in(0);
+        session.expiration = DateTime::from_secs(1_700_000_000);
+        assert_eq!(
+            session.expiration_rfc3339().unwrap(),
+            "2023-11-14T22:13:20Z"
+        );
+    }
+}
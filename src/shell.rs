@@ -1,6 +1,33 @@
+use crate::error::CliError;
+use crate::prompt::Prompt;
+use crate::secret::{Secret, escape};
+
+/// Marks a shell started by `--shell`; holds the profile the session was minted from.
+pub const AWS_MFA_SESSION: &str = "AWS_MFA_SESSION";
+/// Session expiry in Unix seconds, read by the expiry hook.
+pub const AWS_MFA_SESSION_EXPIRES_AT: &str = "AWS_MFA_SESSION_EXPIRES_AT";
+
+// Where an export saves the prompt it replaces, for `unset` to restore: a
+// shell variable, or an environment variable where that is all that persists
+// (cmd, Nushell, xonsh) and a global in PowerShell.
+const SAVED_PROMPT: &str = "__aws_mfa_session_saved_prompt";
+const SAVED_PROMPT_ENV: &str = "__AWS_MFA_SESSION_SAVED_PROMPT";
+const SAVED_PROMPT_PS: &str = "AwsMfaSessionSavedPrompt";
+// The fish right prompt the expiry hook shows the time left in front of.
+const SAVED_RIGHT_PROMPT: &str = "__aws_mfa_session_saved_right_prompt";
+// The exported prompt text in Elvish, and whether it is prepended.
+const ELVISH_PROMPT: &str = "AWS_MFA_SESSION_PROMPT";
+const ELVISH_PROMPT_PREPEND: &str = "AWS_MFA_SESSION_PROMPT_PREPEND";
+use clap::ValueEnum;
+use std::path::Path;
+use std::process::Command;
+use std::{env, fs};
+
+/// Syntax of the exports, prompt and init script, detected from `$SHELL` or
+/// chosen with `--shell-type`.
 // `PowerShell` is a proper product name, not redundant naming against `Shell`.
 #[allow(clippy::enum_variant_names)]
-#[derive(Default, PartialEq, Debug)]
+#[derive(ValueEnum, Default, PartialEq, Debug, Clone, Copy)]
 pub enum Shell {
     #[default]
     Bash,
@@ -8,109 +35,702 @@ pub enum Shell {
     Zsh,
     Fish,
     Cmd,
+    #[value(name = "powershell", alias = "pwsh")]
     PowerShell,
+    #[value(name = "nu", alias = "nushell")]
+    Nu,
+    Elvish,
+    Xonsh,
+    /// tcsh, and csh with tcsh's prompt sequences
+    #[value(alias = "csh")]
+    Tcsh,
 }
 
 impl Shell {
     fn escape_unix_quotes(value: &str) -> String {
-        value.replace('\'', "'\\''").replace('"', "\\\"")
+        escape(value, "", |c, _| match c {
+            '\'' => Some("'\\''"),
+            '"' => Some("\\\""),
+            _ => None,
+        })
     }
 
     fn escape_cmd_quotes(value: &str) -> String {
-        value.replace('"', "\"\"")
+        escape(value, "", |c, _| (c == '"').then_some("\"\""))
     }
 
+    /// For a PowerShell single-quoted string, which expands nothing: quotes
+    /// are doubled, including the typographic ones PowerShell also accepts.
     fn escape_powershell_quotes(value: &str) -> String {
-        value
-            .replace('`', "``")
-            .replace('"', "`\"")
-            .replace('$', "`$")
+        escape(value, "", |c, _| match c {
+            '\'' => Some("''"),
+            '\u{2018}' => Some("\u{2018}\u{2018}"),
+            '\u{2019}' => Some("\u{2019}\u{2019}"),
+            '\u{201A}' => Some("\u{201A}\u{201A}"),
+            '\u{201B}' => Some("\u{201B}\u{201B}"),
+            _ => None,
+        })
     }
 
-    pub fn export(
-        self,
+    /// For a fish single-quoted string, where only `\\` and `\'` are escapes.
+    fn escape_fish_quotes(value: &str) -> String {
+        escape(value, "", |c, _| match c {
+            '\\' => Some("\\\\"),
+            '\'' => Some("\\'"),
+            _ => None,
+        })
+    }
+
+    /// For a Nushell double-quoted string, which only decodes backslash escapes
+    /// (interpolation needs `$"..."`).
+    fn escape_nu_quotes(value: &str) -> String {
+        escape(value, "", |c, _| match c {
+            '\\' => Some("\\\\"),
+            '"' => Some("\\\""),
+            '\n' => Some("\\n"),
+            _ => None,
+        })
+    }
+
+    /// For an Elvish single-quoted string, where only `'` is special.
+    fn escape_elvish_quotes(value: &str) -> String {
+        escape(value, "", |c, _| (c == '\'').then_some("''"))
+    }
+
+    /// For a Python string literal in single quotes, as xonsh reads them.
+    fn escape_xonsh_quotes(value: &str) -> String {
+        escape(value, "", |c, _| match c {
+            '\\' => Some("\\\\"),
+            '\'' => Some("\\'"),
+            '\n' => Some("\\n"),
+            _ => None,
+        })
+    }
+
+    /// For a csh single-quoted string: `'` ends it and a newline must be
+    /// escaped. `!` is left alone, as history substitution only applies to
+    /// typed lines, not to `eval` or `source`.
+    fn escape_csh_quotes(value: &str) -> String {
+        escape(value, "", |c, _| match c {
+            '\'' => Some("'\\''"),
+            '\n' => Some("\\\n"),
+            _ => None,
+        })
+    }
+
+    /// Escape a value substituted into the prompt so the shell's own prompt
+    /// expansion shows it literally.
+    fn escape_prompt_value(&self, value: &str) -> String {
+        match self {
+            // Bash decodes backslash escapes and then runs parameter expansion,
+            // so each special character needs escaping for both passes.
+            Shell::Bash => value
+                .replace('\\', "\\\\\\\\")
+                .replace('$', "\\\\$")
+                .replace('`', "\\\\`"),
+            Shell::Sh => value
+                .replace('\\', "\\\\")
+                .replace('$', "\\$")
+                .replace('`', "\\`"),
+            // Without PROMPT_SUBST only `%` sequences are expanded.
+            Shell::Zsh => value.replace('%', "%%"),
+            Shell::Cmd => value.replace('$', "$$"),
+            // `$PROMPT` is a format string with `{field}`s.
+            Shell::Xonsh => value.replace('{', "{{").replace('}', "}}"),
+            // `%` sequences, and `!` for the history event number.
+            Shell::Tcsh => value.replace('%', "%%").replace('!', "\\!"),
+            // The prompt is a quoted string literal; quoting handles escaping.
+            Shell::Fish | Shell::PowerShell | Shell::Nu | Shell::Elvish => value.to_string(),
+        }
+    }
+
+    /// Render `prompt` with values escaped for this shell's prompt expansion.
+    pub fn render_prompt(&self, prompt: &Prompt) -> String {
+        prompt.render(|value| self.escape_prompt_value(value))
+    }
+
+    fn export_var(
+        &self,
         stdout: &mut dyn std::io::Write,
-        id: &str,
-        secret: &str,
-        token: &str,
-        ps: &str,
+        name: &str,
+        value: &str,
     ) -> Result<(), std::io::Error> {
+        // The escaped value is a copy of the credential, escaped in one pass;
+        // it is wiped on drop like the original.
         match self {
             Shell::Bash | Shell::Sh | Shell::Zsh => {
-                let escaped_id = Self::escape_unix_quotes(id);
-                let escaped_secret = Self::escape_unix_quotes(secret);
-                let escaped_token = Self::escape_unix_quotes(token);
-                let escaped_ps = Self::escape_unix_quotes(ps);
-
-                writeln!(stdout, "export AWS_ACCESS_KEY_ID='{escaped_id}'")?;
-                writeln!(stdout, "export AWS_SECRET_ACCESS_KEY='{escaped_secret}'")?;
-                writeln!(stdout, "export AWS_SESSION_TOKEN='{escaped_token}'")?;
-                writeln!(stdout, "export PS1='{escaped_ps}'")?;
+                let escaped = Secret::new(Self::escape_unix_quotes(value));
+                writeln!(stdout, "export {name}='{}'", escaped.expose())
             }
+            // Global, so variables set by `source` inside a function (such as
+            // `awsmfa-refresh`) outlive it.
             Shell::Fish => {
-                let escaped_id = Self::escape_unix_quotes(id);
-                let escaped_secret = Self::escape_unix_quotes(secret);
-                let escaped_token = Self::escape_unix_quotes(token);
-                let escaped_ps = Self::escape_unix_quotes(ps);
-
-                writeln!(stdout, "set -x AWS_ACCESS_KEY_ID \"{escaped_id}\"")?;
-                writeln!(stdout, "set -x AWS_SECRET_ACCESS_KEY \"{escaped_secret}\"")?;
-                writeln!(stdout, "set -x AWS_SESSION_TOKEN \"{escaped_token}\"")?;
-                writeln!(stdout, "set -x PS1 \"{escaped_ps}\"")?;
+                let escaped = Secret::new(Self::escape_fish_quotes(value));
+                writeln!(stdout, "set -gx {name} '{}'", escaped.expose())
             }
             Shell::Cmd => {
-                let escaped_id = Self::escape_cmd_quotes(id);
-                let escaped_secret = Self::escape_cmd_quotes(secret);
-                let escaped_token = Self::escape_cmd_quotes(token);
-                let escaped_ps = Self::escape_cmd_quotes(ps);
+                let escaped = Secret::new(Self::escape_cmd_quotes(value));
+                writeln!(stdout, "set \"{name}={}\"", escaped.expose())
+            }
+            // The environment, not a PowerShell variable, so child processes
+            // see it.
+            Shell::PowerShell => {
+                let escaped = Secret::new(Self::escape_powershell_quotes(value));
+                writeln!(stdout, "$env:{name} = '{}'", escaped.expose())
+            }
+            Shell::Nu => {
+                let escaped = Secret::new(Self::escape_nu_quotes(value));
+                writeln!(stdout, "$env.{name} = \"{}\"", escaped.expose())
+            }
+            Shell::Elvish => {
+                let escaped = Secret::new(Self::escape_elvish_quotes(value));
+                writeln!(stdout, "set-env {name} '{}'", escaped.expose())
+            }
+            Shell::Xonsh => {
+                let escaped = Secret::new(Self::escape_xonsh_quotes(value));
+                writeln!(stdout, "${name} = '{}'", escaped.expose())
+            }
+            // Each statement ends with `;`, as `eval \`...\`` joins the lines.
+            Shell::Tcsh => {
+                let escaped = Secret::new(Self::escape_csh_quotes(value));
+                writeln!(stdout, "setenv {name} '{}';", escaped.expose())
+            }
+        }
+    }
+
+    /// Export additional variables (e.g. the session expiration) in this
+    /// shell's syntax.
+    pub fn export_vars(
+        &self,
+        stdout: &mut dyn std::io::Write,
+        vars: &[(&str, &str)],
+    ) -> Result<(), std::io::Error> {
+        for (name, value) in vars {
+            self.export_var(stdout, name, value)?;
+        }
+        Ok(())
+    }
 
-                writeln!(stdout, "set \"AWS_ACCESS_KEY_ID={escaped_id}\"")?;
-                writeln!(stdout, "set \"AWS_SECRET_ACCESS_KEY={escaped_secret}\"")?;
-                writeln!(stdout, "set \"AWS_SESSION_TOKEN={escaped_token}\"")?;
-                writeln!(stdout, "set \"PROMPT={escaped_ps}\"")?;
+    /// Set the prompt, first saving the current one unless an earlier export
+    /// already did, so `unset` can restore it and prepending never stacks.
+    fn export_prompt(
+        &self,
+        stdout: &mut dyn std::io::Write,
+        prompt: &Prompt,
+    ) -> Result<(), std::io::Error> {
+        let ps = self.render_prompt(prompt);
+        match self {
+            Shell::Bash | Shell::Sh | Shell::Zsh => {
+                let escaped_ps = Self::escape_unix_quotes(&ps);
+                writeln!(stdout, "{SAVED_PROMPT}=\"${{{SAVED_PROMPT}-$PS1}}\"")?;
+                if prompt.prepend {
+                    writeln!(stdout, "export PS1='{escaped_ps}'\"${SAVED_PROMPT}\"")?;
+                } else {
+                    writeln!(stdout, "export PS1='{escaped_ps}'")?;
+                }
+            }
+            // fish ignores PS1 and calls `fish_prompt`; the saved prompt is a
+            // copy of that function.
+            Shell::Fish => {
+                let escaped_ps = Self::escape_fish_quotes(&ps);
+                writeln!(
+                    stdout,
+                    "if not functions -q {SAVED_PROMPT}; and functions -q fish_prompt; functions -c fish_prompt {SAVED_PROMPT}; end"
+                )?;
+                if prompt.prepend {
+                    writeln!(
+                        stdout,
+                        "function fish_prompt; printf '%s' '{escaped_ps}'; functions -q {SAVED_PROMPT}; and {SAVED_PROMPT}; end"
+                    )?;
+                } else {
+                    writeln!(
+                        stdout,
+                        "function fish_prompt; printf '%s' '{escaped_ps}'; end"
+                    )?;
+                }
+            }
+            Shell::Cmd => {
+                let escaped_ps = Self::escape_cmd_quotes(&ps);
+                writeln!(
+                    stdout,
+                    "if not defined {SAVED_PROMPT_ENV} set \"{SAVED_PROMPT_ENV}=%PROMPT%\""
+                )?;
+                if prompt.prepend {
+                    writeln!(stdout, "set \"PROMPT={escaped_ps}%{SAVED_PROMPT_ENV}%\"")?;
+                } else {
+                    writeln!(stdout, "set \"PROMPT={escaped_ps}\"")?;
+                }
             }
             Shell::PowerShell => {
-                let escaped_id = Self::escape_powershell_quotes(id);
-                let escaped_secret = Self::escape_powershell_quotes(secret);
-                let escaped_token = Self::escape_powershell_quotes(token);
-                let escaped_ps = Self::escape_powershell_quotes(ps);
+                let escaped_ps = Self::escape_powershell_quotes(&ps);
+                writeln!(
+                    stdout,
+                    "if (-not (Test-Path Variable:global:{SAVED_PROMPT_PS})) {{ $global:{SAVED_PROMPT_PS} = $function:prompt }}"
+                )?;
+                // Global, as Invoke-Expression may run in a function's scope.
+                if prompt.prepend {
+                    // Call the saved prompt function after ours.
+                    writeln!(
+                        stdout,
+                        "function global:prompt {{ '{escaped_ps}' + (& $global:{SAVED_PROMPT_PS}) }}"
+                    )?;
+                } else {
+                    writeln!(stdout, "function global:prompt {{ '{escaped_ps}' }}")?;
+                }
+            }
+            Shell::Nu => {
+                let escaped_ps = Self::escape_nu_quotes(&ps);
+                writeln!(
+                    stdout,
+                    "$env.{SAVED_PROMPT_ENV} = ($env.{SAVED_PROMPT_ENV}? | default ($env.PROMPT_COMMAND? | default ''))"
+                )?;
+                if prompt.prepend {
+                    // The saved prompt may be a closure or a plain string.
+                    writeln!(
+                        stdout,
+                        "$env.PROMPT_COMMAND = {{|| \"{escaped_ps}\" + (if ($env.{SAVED_PROMPT_ENV} | describe) starts-with 'closure' {{ do $env.{SAVED_PROMPT_ENV} }} else {{ $env.{SAVED_PROMPT_ENV} }}) }}"
+                    )?;
+                } else {
+                    writeln!(stdout, "$env.PROMPT_COMMAND = {{|| \"{escaped_ps}\" }}")?;
+                }
+            }
+            Shell::Elvish => {
+                // Variables declared in `eval`ed code do not outlive it, so the
+                // prompt text lives in the environment and the prompt function
+                // is wrapped once; unsetting the variables reverts it.
+                let escaped_ps = Self::escape_elvish_quotes(&ps);
+                writeln!(
+                    stdout,
+                    "if (not (has-env {ELVISH_PROMPT})) {{ var prompt = $edit:prompt; set edit:prompt = {{ if (has-env {ELVISH_PROMPT}) {{ put $E:{ELVISH_PROMPT}; if (has-env {ELVISH_PROMPT_PREPEND}) {{ $prompt }} }} else {{ $prompt }} }} }}"
+                )?;
+                writeln!(stdout, "set-env {ELVISH_PROMPT} '{escaped_ps}'")?;
+                if prompt.prepend {
+                    writeln!(stdout, "set-env {ELVISH_PROMPT_PREPEND} 1")?;
+                } else {
+                    writeln!(stdout, "unset-env {ELVISH_PROMPT_PREPEND}")?;
+                }
+            }
+            Shell::Xonsh => {
+                let escaped_ps = Self::escape_xonsh_quotes(&ps);
+                writeln!(stdout, "${{...}}.setdefault('{SAVED_PROMPT_ENV}', $PROMPT)")?;
+                if prompt.prepend {
+                    writeln!(
+                        stdout,
+                        "$PROMPT = '{escaped_ps}' + ${{...}}['{SAVED_PROMPT_ENV}']"
+                    )?;
+                } else {
+                    writeln!(stdout, "$PROMPT = '{escaped_ps}'")?;
+                }
+            }
+            Shell::Tcsh => {
+                let escaped_ps = Self::escape_csh_quotes(&ps);
+                writeln!(
+                    stdout,
+                    "if (! $?{SAVED_PROMPT}) set {SAVED_PROMPT} = \"$prompt\";"
+                )?;
+                if prompt.prepend {
+                    writeln!(stdout, "set prompt = '{escaped_ps}'\"${SAVED_PROMPT}\";")?;
+                } else {
+                    writeln!(stdout, "set prompt = '{escaped_ps}';")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn export(
+        self,
+        stdout: &mut dyn std::io::Write,
+        id: &str,
+        secret: &str,
+        token: &str,
+        prompt: &Prompt,
+    ) -> Result<(), std::io::Error> {
+        self.export_vars(
+            stdout,
+            &[
+                ("AWS_ACCESS_KEY_ID", id),
+                ("AWS_SECRET_ACCESS_KEY", secret),
+                ("AWS_SESSION_TOKEN", token),
+            ],
+        )?;
+        self.export_prompt(stdout, prompt)
+    }
 
+    fn unset_var(&self, stdout: &mut dyn std::io::Write, name: &str) -> Result<(), std::io::Error> {
+        match self {
+            Shell::Bash | Shell::Sh | Shell::Zsh => writeln!(stdout, "unset {name}"),
+            Shell::Fish => writeln!(stdout, "set -e -g {name}"),
+            Shell::Cmd => writeln!(stdout, "set \"{name}=\""),
+            Shell::PowerShell => writeln!(
+                stdout,
+                "Remove-Item -Path Env:{name} -ErrorAction SilentlyContinue"
+            ),
+            Shell::Nu => writeln!(stdout, "hide-env -i {name}"),
+            Shell::Elvish => writeln!(stdout, "unset-env {name}"),
+            Shell::Xonsh => writeln!(stdout, "${{...}}.pop('{name}', None)"),
+            Shell::Tcsh => writeln!(stdout, "unsetenv {name};"),
+        }
+    }
+
+    /// Restore the prompt saved by the first export, if any.
+    fn restore_prompt(&self, stdout: &mut dyn std::io::Write) -> Result<(), std::io::Error> {
+        match self {
+            Shell::Bash | Shell::Sh | Shell::Zsh => writeln!(
+                stdout,
+                "if [ -n \"${{{SAVED_PROMPT}+x}}\" ]; then PS1=${SAVED_PROMPT}; unset {SAVED_PROMPT}; fi"
+            ),
+            Shell::Fish => writeln!(
+                stdout,
+                "if functions -q {SAVED_PROMPT}; functions -e fish_prompt; functions -c {SAVED_PROMPT} fish_prompt; functions -e {SAVED_PROMPT}; end"
+            ),
+            Shell::Cmd => {
                 writeln!(
                     stdout,
-                    "Set-Variable -Name \"AWS_ACCESS_KEY_ID\" -Value \"{escaped_id}\""
+                    "if defined {SAVED_PROMPT_ENV} set \"PROMPT=%{SAVED_PROMPT_ENV}%\""
                 )?;
+                self.unset_var(stdout, SAVED_PROMPT_ENV)
+            }
+            Shell::PowerShell => writeln!(
+                stdout,
+                "if (Test-Path Variable:global:{SAVED_PROMPT_PS}) {{ Set-Item -Path Function:global:prompt -Value $global:{SAVED_PROMPT_PS}; Remove-Variable -Name {SAVED_PROMPT_PS} -Scope Global }}"
+            ),
+            Shell::Nu => {
                 writeln!(
                     stdout,
-                    "Set-Variable -Name \"AWS_SECRET_ACCESS_KEY\" -Value \"{escaped_secret}\""
+                    "$env.PROMPT_COMMAND = ($env.{SAVED_PROMPT_ENV}? | default $env.PROMPT_COMMAND?)"
                 )?;
+                self.unset_var(stdout, SAVED_PROMPT_ENV)
+            }
+            // The wrapped prompt function falls back to the original.
+            Shell::Elvish => {
+                self.unset_var(stdout, ELVISH_PROMPT)?;
+                self.unset_var(stdout, ELVISH_PROMPT_PREPEND)
+            }
+            Shell::Xonsh => writeln!(
+                stdout,
+                "if '{SAVED_PROMPT_ENV}' in ${{...}}: $PROMPT = ${{...}}.pop('{SAVED_PROMPT_ENV}')"
+            ),
+            Shell::Tcsh => {
                 writeln!(
                     stdout,
-                    "Set-Variable -Name \"AWS_SESSION_TOKEN\" -Value \"{escaped_token}\""
+                    "if ($?{SAVED_PROMPT}) set prompt = \"${SAVED_PROMPT}\";"
                 )?;
-                writeln!(stdout, "function prompt {{ \"{escaped_ps}\" }}")?;
+                writeln!(stdout, "unset {SAVED_PROMPT};")
             }
         }
+    }
+
+    /// End a session in the current shell: remove the variables `names` and
+    /// restore the prompt the export replaced.
+    pub fn unset(
+        self,
+        stdout: &mut dyn std::io::Write,
+        names: &[&str],
+    ) -> Result<(), std::io::Error> {
+        for name in names {
+            self.unset_var(stdout, name)?;
+        }
+        self.restore_prompt(stdout)
+    }
+
+    /// Fail unless `--shell` can hand this shell an init script to run after
+    /// the user's startup files. Without one the session would start with no
+    /// prompt, expiry hook or `awsmfa-refresh`.
+    pub fn ensure_init_script(&self) -> Result<(), CliError> {
+        match self {
+            // Nushell only sources files known when parsing, elvish's and
+            // tcsh's startup files cannot be chained to another one.
+            Shell::Nu | Shell::Elvish | Shell::Tcsh => Err(CliError::ValidationError(format!(
+                "--shell cannot set up a {} session: evaluate the --export output in your shell instead",
+                self.to_possible_value()
+                    .map(|value| value.get_name().to_string())
+                    .unwrap_or_default()
+            ))),
+            Shell::Bash
+            | Shell::Sh
+            | Shell::Zsh
+            | Shell::Fish
+            | Shell::Cmd
+            | Shell::PowerShell
+            | Shell::Xonsh => Ok(()),
+        }
+    }
+
+    /// The `--shell` init script: the prompt, then the expiry hook, which
+    /// keeps the prompt it finds, then the `awsmfa-refresh` command.
+    pub fn init_script(
+        &self,
+        prompt: &Prompt,
+        warn_secs: i64,
+        exe: &str,
+    ) -> Result<String, std::io::Error> {
+        let mut script = Vec::new();
+        self.init_prompt(&mut script, prompt)?;
+        self.expiry_hook(&mut script, warn_secs)?;
+        self.refresh_alias(&mut script, exe)?;
+        Ok(String::from_utf8_lossy(&script).into_owned())
+    }
+
+    /// Write a snippet that, before each prompt, shows the time left until
+    /// `$AWS_MFA_SESSION_EXPIRES_AT` (Unix seconds) in front of the prompt and
+    /// warns on stderr once fewer than `warn_secs` remain. The expiry is read
+    /// from the environment so `refresh` can move it. Only shells with a
+    /// pre-prompt hook (bash `PROMPT_COMMAND`, zsh `precmd`, fish `fish_prompt`
+    /// event) get one; for the others nothing is written.
+    pub fn expiry_hook(
+        &self,
+        stdout: &mut dyn std::io::Write,
+        warn_secs: i64,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Shell::Bash | Shell::Zsh => {
+                write!(
+                    stdout,
+                    r#"__aws_mfa_session_ps1=$PS1
+__aws_mfa_session_hook() {{
+  local left=$(( ${{{AWS_MFA_SESSION_EXPIRES_AT}:-0}} - $(date +%s) ))
+  if [ "$left" -le 0 ]; then
+    PS1="[expired] $__aws_mfa_session_ps1"
+    echo "aws-mfa-session: AWS session credentials have expired" >&2
+  else
+    PS1="[$(( left / 3600 ))h$(( left % 3600 / 60 ))m] $__aws_mfa_session_ps1"
+    if [ "$left" -le {warn_secs} ]; then
+      echo "aws-mfa-session: AWS session credentials expire in $(( (left + 59) / 60 )) min" >&2
+    fi
+  fi
+}}
+"#
+                )?;
+                if *self == Shell::Bash {
+                    writeln!(
+                        stdout,
+                        "PROMPT_COMMAND=\"__aws_mfa_session_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}\""
+                    )?;
+                } else {
+                    writeln!(stdout, "autoload -Uz add-zsh-hook")?;
+                    writeln!(stdout, "add-zsh-hook precmd __aws_mfa_session_hook")?;
+                }
+            }
+            Shell::Fish => {
+                // fish ignores PS1, so the time left goes in the right prompt,
+                // in front of the user's own.
+                write!(
+                    stdout,
+                    r#"if not functions -q {SAVED_RIGHT_PROMPT}; and functions -q fish_right_prompt
+    functions -c fish_right_prompt {SAVED_RIGHT_PROMPT}
+end
+function __aws_mfa_session_hook --on-event fish_prompt
+    set -l left (math ${AWS_MFA_SESSION_EXPIRES_AT} - (date +%s))
+    if test $left -le 0
+        set -g __aws_mfa_session_left expired
+        echo "aws-mfa-session: AWS session credentials have expired" >&2
+    else
+        set -g __aws_mfa_session_left (math "floor($left / 3600)")h(math "floor($left % 3600 / 60)")m
+        if test $left -le {warn_secs}
+            echo "aws-mfa-session: AWS session credentials expire in "(math "ceil($left / 60)")" min" >&2
+        end
+    end
+end
+function fish_right_prompt
+    echo -n "[$__aws_mfa_session_left]"
+    functions -q {SAVED_RIGHT_PROMPT}; and {SAVED_RIGHT_PROMPT}
+end
+"#
+                )?;
+            }
+            Shell::Sh
+            | Shell::Cmd
+            | Shell::PowerShell
+            | Shell::Nu
+            | Shell::Elvish
+            | Shell::Xonsh
+            | Shell::Tcsh => {}
+        }
         Ok(())
     }
-}
 
-impl<'a> From<&'a str> for Shell {
-    fn from(s: &'a str) -> Self {
-        match s {
-            s if s.ends_with("/bin/bash") => Shell::Bash,
-            s if s.ends_with("/bin/zsh") => Shell::Zsh,
-            s if s.ends_with("/bin/sh") => Shell::Sh,
-            s if s.ends_with("/bin/fish") => Shell::Fish,
-            s => {
-                let s_lower = s.to_ascii_lowercase();
-                match s_lower.as_str() {
-                    s if s.ends_with("cmd.exe") => Shell::Cmd,
-                    s if s.ends_with("powershell.exe") || s.ends_with("pwsh.exe") => {
-                        Shell::PowerShell
+    /// Set the prompt from the `--shell` init script. It runs after the
+    /// user's startup files, so a `PS1` they set does not override it.
+    pub fn init_prompt(
+        &self,
+        stdout: &mut dyn std::io::Write,
+        prompt: &Prompt,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Shell::Bash
+            | Shell::Sh
+            | Shell::Zsh
+            | Shell::Fish
+            | Shell::PowerShell
+            | Shell::Xonsh => self.export_prompt(stdout, prompt),
+            Shell::Cmd | Shell::Nu | Shell::Elvish | Shell::Tcsh => Ok(()),
+        }
+    }
+
+    /// Write a one-step `awsmfa-refresh` command that runs `exe refresh` and
+    /// applies its output to the current shell.
+    pub fn refresh_alias(
+        &self,
+        stdout: &mut dyn std::io::Write,
+        exe: &str,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            Shell::Bash | Shell::Sh | Shell::Zsh => {
+                // Quoted once for the alias body, which is itself single-quoted.
+                let exe = Self::escape_unix_quotes(&format!("'{}'", Self::escape_unix_quotes(exe)));
+                writeln!(stdout, "alias awsmfa-refresh='eval \"$({exe} refresh)\"'")
+            }
+            Shell::Fish => {
+                let exe = Self::escape_fish_quotes(exe);
+                writeln!(
+                    stdout,
+                    "function awsmfa-refresh; '{exe}' refresh | source; end"
+                )
+            }
+            Shell::PowerShell => {
+                let exe = Self::escape_powershell_quotes(exe);
+                writeln!(
+                    stdout,
+                    "function awsmfa-refresh {{ & '{exe}' refresh | Out-String | Invoke-Expression }}"
+                )
+            }
+            Shell::Xonsh => {
+                let exe = Self::escape_xonsh_quotes(exe);
+                writeln!(
+                    stdout,
+                    "aliases['awsmfa-refresh'] = lambda: execx($(@('{exe}') refresh))"
+                )
+            }
+            // cmd has no way to evaluate a command's output in the current
+            // session short of a `for /f` loop the user can type themselves.
+            // `--shell` refuses the others (see `ensure_init_script`).
+            Shell::Cmd | Shell::Nu | Shell::Elvish | Shell::Tcsh => Ok(()),
+        }
+    }
+
+    /// Build the command that starts `program` (a shell of this kind) with
+    /// `init_script` run after the user's own startup files. The script is
+    /// written into `dir`, which must outlive the child (a private temp dir).
+    pub fn command(
+        &self,
+        program: &str,
+        init_script: &str,
+        dir: &Path,
+    ) -> Result<Command, std::io::Error> {
+        let mut command = Command::new(program);
+        if init_script.is_empty() {
+            return Ok(command);
+        }
+        match self {
+            Shell::Bash => {
+                let rc = dir.join("bashrc");
+                fs::write(
+                    &rc,
+                    format!("[ -f ~/.bashrc ] && . ~/.bashrc\n{init_script}"),
+                )?;
+                command.arg("--rcfile").arg(rc);
+            }
+            Shell::Zsh => {
+                // zsh reads its startup files from ZDOTDIR: point it at `dir`
+                // and have those files chain to the user's real ones.
+                let home = env::var("ZDOTDIR")
+                    .or_else(|_| env::var("HOME"))
+                    .unwrap_or_default();
+                let home = Self::escape_unix_quotes(&home);
+                fs::write(
+                    dir.join(".zshenv"),
+                    format!("[ -f '{home}/.zshenv' ] && . '{home}/.zshenv'\n"),
+                )?;
+                fs::write(
+                    dir.join(".zshrc"),
+                    format!(
+                        "ZDOTDIR='{home}'\n[ -f \"$ZDOTDIR/.zshrc\" ] && . \"$ZDOTDIR/.zshrc\"\n{init_script}"
+                    ),
+                )?;
+                command.env("ZDOTDIR", dir);
+            }
+            Shell::Fish => {
+                let init = dir.join("init.fish");
+                fs::write(&init, init_script)?;
+                let path = Self::escape_fish_quotes(&init.to_string_lossy());
+                command
+                    .arg("--init-command")
+                    .arg(format!("source '{path}'"));
+            }
+            Shell::Sh => {
+                // Interactive POSIX shells source the file named by $ENV.
+                let rc = dir.join("shrc");
+                let user_env = match env::var("ENV") {
+                    Ok(path) => {
+                        let path = Self::escape_unix_quotes(&path);
+                        format!("[ -f '{path}' ] && . '{path}'\n")
+                    }
+                    Err(_) => String::new(),
+                };
+                fs::write(&rc, format!("{user_env}{init_script}"))?;
+                command.env("ENV", rc);
+            }
+            Shell::PowerShell => {
+                let init = dir.join("init.ps1");
+                fs::write(&init, init_script)?;
+                let path = Self::escape_powershell_quotes(&init.to_string_lossy());
+                command
+                    .arg("-NoExit")
+                    .arg("-Command")
+                    .arg(format!(". '{path}'"));
+            }
+            Shell::Xonsh => {
+                // --rc replaces the default run control files, so pass the
+                // user's own first.
+                let home = env::var("HOME").unwrap_or_default();
+                let config =
+                    env::var("XDG_CONFIG_HOME").unwrap_or_else(|_| format!("{home}/.config"));
+                let init = dir.join("init.xsh");
+                fs::write(&init, init_script)?;
+                command.arg("--rc");
+                for rc in [format!("{home}/.xonshrc"), format!("{config}/xonsh/rc.xsh")] {
+                    if Path::new(&rc).is_file() {
+                        command.arg(rc);
                     }
-                    _ => Default::default(),
                 }
+                command.arg(init);
             }
+            Shell::Cmd | Shell::Nu | Shell::Elvish | Shell::Tcsh => {}
+        }
+        Ok(command)
+    }
+}
+
+impl Shell {
+    /// The shell of an executable path such as `$SHELL`, by its file name:
+    /// `/nix/store/...-zsh-5.9/bin/zsh`, `-bash` (a login shell) and `PWSH.EXE`
+    /// are all recognised. Unknown shells are an error, as exports in the wrong
+    /// syntax would be evaluated by the wrong interpreter.
+    pub fn detect(path: &str) -> Result<Self, CliError> {
+        let name = path
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_start_matches('-')
+            .to_ascii_lowercase();
+        let name = name.strip_suffix(".exe").unwrap_or(&name);
+        match name {
+            "bash" => Ok(Shell::Bash),
+            "sh" | "dash" | "ash" => Ok(Shell::Sh),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "cmd" => Ok(Shell::Cmd),
+            "powershell" | "pwsh" => Ok(Shell::PowerShell),
+            "nu" => Ok(Shell::Nu),
+            "elvish" => Ok(Shell::Elvish),
+            "xonsh" => Ok(Shell::Xonsh),
+            "tcsh" | "csh" => Ok(Shell::Tcsh),
+            _ => Err(CliError::ValidationError(format!(
+                "unsupported shell {path:?}: choose the output syntax with --shell-type \
+                 (bash, sh, zsh, fish, cmd, powershell, nu, elvish, xonsh or tcsh)"
+            ))),
         }
     }
 }
@@ -118,59 +738,67 @@ impl<'a> From<&'a str> for Shell {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::prompt::PromptValues;
+
+    fn prompt(template: &str) -> Prompt {
+        Prompt::new(template, PromptValues::default(), false).unwrap()
+    }
 
     #[test]
     fn test_shell_from_bash_paths() {
-        assert_eq!(Shell::from("/bin/bash"), Shell::Bash);
-        assert_eq!(Shell::from("/usr/bin/bash"), Shell::Bash);
-        assert_eq!(Shell::from("/usr/local/bin/bash"), Shell::Bash);
+        assert_eq!(Shell::detect("/bin/bash").unwrap(), Shell::Bash);
+        assert_eq!(Shell::detect("/usr/bin/bash").unwrap(), Shell::Bash);
+        assert_eq!(Shell::detect("/usr/local/bin/bash").unwrap(), Shell::Bash);
     }
 
     #[test]
     fn test_shell_from_zsh_paths() {
-        assert_eq!(Shell::from("/bin/zsh"), Shell::Zsh);
-        assert_eq!(Shell::from("/usr/bin/zsh"), Shell::Zsh);
-        assert_eq!(Shell::from("/usr/local/bin/zsh"), Shell::Zsh);
+        assert_eq!(Shell::detect("/bin/zsh").unwrap(), Shell::Zsh);
+        assert_eq!(Shell::detect("/usr/bin/zsh").unwrap(), Shell::Zsh);
+        assert_eq!(Shell::detect("/usr/local/bin/zsh").unwrap(), Shell::Zsh);
     }
 
     #[test]
     fn test_shell_from_sh_path() {
-        assert_eq!(Shell::from("/bin/sh"), Shell::Sh);
+        assert_eq!(Shell::detect("/bin/sh").unwrap(), Shell::Sh);
     }
 
     #[test]
     fn test_shell_from_fish_paths() {
-        assert_eq!(Shell::from("/bin/fish"), Shell::Fish);
-        assert_eq!(Shell::from("/usr/local/bin/fish"), Shell::Fish);
+        assert_eq!(Shell::detect("/bin/fish").unwrap(), Shell::Fish);
+        assert_eq!(Shell::detect("/usr/local/bin/fish").unwrap(), Shell::Fish);
     }
 
     #[test]
     fn test_shell_from_cmd_paths() {
-        assert_eq!(Shell::from("cmd.exe"), Shell::Cmd);
-        assert_eq!(Shell::from("C:\\Windows\\System32\\cmd.exe"), Shell::Cmd);
-        assert_eq!(Shell::from("CMD.EXE"), Shell::Cmd);
+        assert_eq!(Shell::detect("cmd.exe").unwrap(), Shell::Cmd);
+        assert_eq!(
+            Shell::detect("C:\\Windows\\System32\\cmd.exe").unwrap(),
+            Shell::Cmd
+        );
+        assert_eq!(Shell::detect("CMD.EXE").unwrap(), Shell::Cmd);
     }
 
     #[test]
     fn test_shell_from_powershell_paths() {
-        assert_eq!(Shell::from("powershell.exe"), Shell::PowerShell);
+        assert_eq!(Shell::detect("powershell.exe").unwrap(), Shell::PowerShell);
         assert_eq!(
-            Shell::from("C:\\Windows\\System32\\powershell.exe"),
+            Shell::detect("C:\\Windows\\System32\\powershell.exe").unwrap(),
             Shell::PowerShell
         );
-        assert_eq!(Shell::from("POWERSHELL.EXE"), Shell::PowerShell);
-        assert_eq!(Shell::from("pwsh.exe"), Shell::PowerShell);
+        assert_eq!(Shell::detect("POWERSHELL.EXE").unwrap(), Shell::PowerShell);
+        assert_eq!(Shell::detect("pwsh.exe").unwrap(), Shell::PowerShell);
         assert_eq!(
-            Shell::from("C:\\Program Files\\PowerShell\\7\\pwsh.exe"),
+            Shell::detect("C:\\Program Files\\PowerShell\\7\\pwsh.exe").unwrap(),
             Shell::PowerShell
         );
     }
 
     #[test]
-    fn test_shell_from_unknown_defaults_to_bash() {
-        assert_eq!(Shell::from("unknown"), Shell::Bash);
-        assert_eq!(Shell::from("/some/unknown/shell"), Shell::Bash);
-        assert_eq!(Shell::from(""), Shell::Bash);
+    fn test_shell_from_unknown_is_an_error() {
+        assert!(Shell::detect("unknown").is_err());
+        assert!(Shell::detect("/some/unknown/shell").is_err());
+        assert!(Shell::detect("").is_err());
     }
 
     #[test]
@@ -190,7 +818,7 @@ mod tests {
                 "test_key",
                 "test_secret",
                 "test_token",
-                "test_prompt",
+                &prompt("test_prompt"),
             )
             .unwrap();
 
@@ -212,15 +840,16 @@ mod tests {
                 "test_key",
                 "test_secret",
                 "test_token",
-                "test_prompt",
+                &prompt("test_prompt"),
             )
             .unwrap();
 
         let output_str = String::from_utf8(output).unwrap();
-        assert!(output_str.contains("set -x AWS_ACCESS_KEY_ID \"test_key\""));
-        assert!(output_str.contains("set -x AWS_SECRET_ACCESS_KEY \"test_secret\""));
-        assert!(output_str.contains("set -x AWS_SESSION_TOKEN \"test_token\""));
-        assert!(output_str.contains("set -x PS1 \"test_prompt\""));
+        assert!(output_str.contains("set -gx AWS_ACCESS_KEY_ID 'test_key'"));
+        assert!(output_str.contains("set -gx AWS_SECRET_ACCESS_KEY 'test_secret'"));
+        assert!(output_str.contains("set -gx AWS_SESSION_TOKEN 'test_token'"));
+        assert!(output_str.contains("function fish_prompt; printf '%s' 'test_prompt'; end"));
+        assert!(!output_str.contains("PS1"));
     }
 
     #[test]
@@ -234,7 +863,7 @@ mod tests {
                 "test_key",
                 "test_secret",
                 "test_token",
-                "test_prompt",
+                &prompt("test_prompt"),
             )
             .unwrap();
 
@@ -256,22 +885,16 @@ mod tests {
                 "test_key",
                 "test_secret",
                 "test_token",
-                "test_prompt",
+                &prompt("test_prompt"),
             )
             .unwrap();
 
         let output_str = String::from_utf8(output).unwrap();
-        assert!(
-            output_str.contains("Set-Variable -Name \"AWS_ACCESS_KEY_ID\" -Value \"test_key\"")
-        );
-        assert!(
-            output_str
-                .contains("Set-Variable -Name \"AWS_SECRET_ACCESS_KEY\" -Value \"test_secret\"")
-        );
-        assert!(
-            output_str.contains("Set-Variable -Name \"AWS_SESSION_TOKEN\" -Value \"test_token\"")
-        );
-        assert!(output_str.contains("function prompt { \"test_prompt\" }"));
+        assert!(output_str.contains("$env:AWS_ACCESS_KEY_ID = 'test_key'"));
+        assert!(output_str.contains("$env:AWS_SECRET_ACCESS_KEY = 'test_secret'"));
+        assert!(output_str.contains("$env:AWS_SESSION_TOKEN = 'test_token'"));
+        assert!(output_str.contains("function global:prompt { 'test_prompt' }"));
+        assert!(!output_str.contains("Set-Variable"));
     }
 
     #[test]
@@ -284,7 +907,7 @@ mod tests {
                 "key_with_$pecial_chars",
                 "secret'with\"quotes",
                 "token with spaces",
-                "prompt;with;semicolons",
+                &prompt("prompt;with;semicolons"),
             )
             .unwrap();
     }
@@ -293,7 +916,7 @@ mod tests {
     fn test_shell_export_with_empty_values() {
         let shell = Shell::Bash;
         let mut output = Vec::new();
-        shell.export(&mut output, "", "", "", "").unwrap();
+        shell.export(&mut output, "", "", "", &prompt("")).unwrap();
     }
 
     #[test]
@@ -305,6 +928,10 @@ mod tests {
             Shell::Fish,
             Shell::Cmd,
             Shell::PowerShell,
+            Shell::Nu,
+            Shell::Elvish,
+            Shell::Xonsh,
+            Shell::Tcsh,
         ];
 
         for shell in shells {
@@ -315,7 +942,7 @@ mod tests {
                     "test_key",
                     "test_secret",
                     "test_token",
-                    "test_prompt",
+                    &prompt("test_prompt"),
                 )
                 .unwrap();
         }
@@ -323,19 +950,37 @@ mod tests {
 
     #[test]
     fn test_shell_from_partial_paths() {
-        assert_eq!(Shell::from("bash"), Shell::Bash);
-        assert_eq!(Shell::from("something_cmd.exe"), Shell::Cmd);
-        assert_eq!(Shell::from("my_powershell.exe"), Shell::PowerShell);
+        assert_eq!(Shell::detect("bash").unwrap(), Shell::Bash);
+        assert_eq!(Shell::detect("-zsh").unwrap(), Shell::Zsh);
+        assert_eq!(Shell::detect("pwsh").unwrap(), Shell::PowerShell);
+        // Only the whole file name counts.
+        assert!(Shell::detect("something_cmd.exe").is_err());
+        assert!(Shell::detect("my_powershell.exe").is_err());
+        assert!(Shell::detect("/usr/bin/notbash").is_err());
+    }
+
+    #[test]
+    fn test_shell_from_basename_anywhere() {
+        assert_eq!(Shell::detect("/usr/local/bin/fish").unwrap(), Shell::Fish);
+        assert_eq!(
+            Shell::detect("/nix/store/abc123-zsh-5.9/bin/zsh").unwrap(),
+            Shell::Zsh
+        );
+        assert_eq!(
+            Shell::detect("/run/current-system/sw/bin/bash").unwrap(),
+            Shell::Bash
+        );
+        assert_eq!(Shell::detect("/usr/bin/dash").unwrap(), Shell::Sh);
+        assert_eq!(Shell::detect("/usr/bin/pwsh").unwrap(), Shell::PowerShell);
     }
 
     #[test]
     fn test_case_sensitivity() {
-        // Unix paths are case sensitive, so uppercase should default to Bash
-        assert_eq!(Shell::from("/BIN/BASH"), Shell::Bash); // defaults to Bash
-        assert_eq!(Shell::from("/USR/BIN/ZSH"), Shell::Bash); // defaults to Bash  
-        // Windows paths are case insensitive
-        assert_eq!(Shell::from("CMD.EXE"), Shell::Cmd);
-        assert_eq!(Shell::from("POWERSHELL.EXE"), Shell::PowerShell);
+        // File names are matched case-insensitively, as on Windows
+        assert_eq!(Shell::detect("/BIN/BASH").unwrap(), Shell::Bash);
+        assert_eq!(Shell::detect("/USR/BIN/ZSH").unwrap(), Shell::Zsh);
+        assert_eq!(Shell::detect("CMD.EXE").unwrap(), Shell::Cmd);
+        assert_eq!(Shell::detect("POWERSHELL.EXE").unwrap(), Shell::PowerShell);
     }
 
     #[test]
@@ -348,7 +993,7 @@ mod tests {
                 "AKIATEST123456",
                 "secretkey123",
                 "sessiontoken456",
-                "AWS:test@123456789 \\$ ",
+                &prompt("AWS:test@123456789 \\$ "),
             )
             .unwrap();
     }
@@ -363,7 +1008,7 @@ mod tests {
                 "AKIATEST123456",
                 "secretkey123",
                 "sessiontoken456",
-                "AWS:test@123456789 \\$ ",
+                &prompt("AWS:test@123456789 \\$ "),
             )
             .unwrap();
     }
@@ -378,7 +1023,7 @@ mod tests {
                 "AKIATEST123456",
                 "secretkey123",
                 "sessiontoken456",
-                "AWS:test@123456789 \\$ ",
+                &prompt("AWS:test@123456789 \\$ "),
             )
             .unwrap();
     }
@@ -393,48 +1038,221 @@ mod tests {
                 "AKIATEST123456",
                 "secretkey123",
                 "sessiontoken456",
-                "AWS:test@123456789 \\$ ",
+                &prompt("AWS:test@123456789 \\$ "),
             )
             .unwrap();
     }
 
     #[test]
     fn test_shell_from_homebrew_paths() {
-        assert_eq!(Shell::from("/opt/homebrew/bin/bash"), Shell::Bash);
-        assert_eq!(Shell::from("/opt/homebrew/bin/zsh"), Shell::Zsh);
-        assert_eq!(Shell::from("/opt/homebrew/bin/fish"), Shell::Fish);
+        assert_eq!(
+            Shell::detect("/opt/homebrew/bin/bash").unwrap(),
+            Shell::Bash
+        );
+        assert_eq!(Shell::detect("/opt/homebrew/bin/zsh").unwrap(), Shell::Zsh);
+        assert_eq!(
+            Shell::detect("/opt/homebrew/bin/fish").unwrap(),
+            Shell::Fish
+        );
     }
 
     #[test]
     fn test_shell_from_windows_paths() {
-        assert_eq!(Shell::from("C:\\Windows\\System32\\cmd.exe"), Shell::Cmd);
-        assert_eq!(Shell::from("C:\\WINDOWS\\SYSTEM32\\CMD.EXE"), Shell::Cmd);
         assert_eq!(
-            Shell::from("C:\\Windows\\System32\\WindowsPowerShell\\v1.0\\powershell.exe"),
+            Shell::detect("C:\\Windows\\System32\\cmd.exe").unwrap(),
+            Shell::Cmd
+        );
+        assert_eq!(
+            Shell::detect("C:\\WINDOWS\\SYSTEM32\\CMD.EXE").unwrap(),
+            Shell::Cmd
+        );
+        assert_eq!(
+            Shell::detect("C:\\Windows\\System32\\WindowsPowerShell\\v1.0\\powershell.exe")
+                .unwrap(),
             Shell::PowerShell
         );
         assert_eq!(
-            Shell::from("C:\\Program Files\\PowerShell\\7\\pwsh.exe"),
+            Shell::detect("C:\\Program Files\\PowerShell\\7\\pwsh.exe").unwrap(),
             Shell::PowerShell
         );
     }
 
     #[test]
     fn test_shell_from_mixed_case() {
-        // Unix paths are case sensitive, so mixed case defaults to Bash
-        assert_eq!(Shell::from("/Usr/Bin/Bash"), Shell::Bash); // defaults to Bash
-        assert_eq!(Shell::from("/USR/LOCAl/BIN/ZSH"), Shell::Bash); // defaults to Bash
-        // Windows executables are case insensitive
-        assert_eq!(Shell::from("Powershell.Exe"), Shell::PowerShell);
-        assert_eq!(Shell::from("PWSH.EXE"), Shell::PowerShell);
+        assert_eq!(Shell::detect("/Usr/Bin/Bash").unwrap(), Shell::Bash);
+        assert_eq!(Shell::detect("/USR/LOCAl/BIN/ZSH").unwrap(), Shell::Zsh);
+        assert_eq!(Shell::detect("Powershell.Exe").unwrap(), Shell::PowerShell);
+        assert_eq!(Shell::detect("PWSH.EXE").unwrap(), Shell::PowerShell);
     }
 
     #[test]
     fn test_shell_from_edge_cases() {
-        assert_eq!(Shell::from(""), Shell::Bash);
-        assert_eq!(Shell::from("   "), Shell::Bash);
-        assert_eq!(Shell::from("notashell"), Shell::Bash);
-        assert_eq!(Shell::from("/usr/bin/python"), Shell::Bash);
+        assert!(Shell::detect("").is_err());
+        assert!(Shell::detect("   ").is_err());
+        assert!(Shell::detect("notashell").is_err());
+        assert!(Shell::detect("/usr/bin/python").is_err());
+        assert!(Shell::detect("/usr/bin/").is_err());
+    }
+
+    #[test]
+    fn test_shell_from_other_shells() {
+        assert_eq!(Shell::detect("/usr/bin/nu").unwrap(), Shell::Nu);
+        assert_eq!(
+            Shell::detect("/usr/local/bin/elvish").unwrap(),
+            Shell::Elvish
+        );
+        assert_eq!(Shell::detect("/usr/bin/xonsh").unwrap(), Shell::Xonsh);
+        assert_eq!(Shell::detect("/bin/tcsh").unwrap(), Shell::Tcsh);
+        assert_eq!(Shell::detect("/bin/csh").unwrap(), Shell::Tcsh);
+        assert_eq!(Shell::detect("nu.exe").unwrap(), Shell::Nu);
+    }
+
+    #[test]
+    fn test_escape_nu_quotes() {
+        assert_eq!(Shell::escape_nu_quotes("test\"value"), "test\\\"value");
+        assert_eq!(Shell::escape_nu_quotes("back\\slash"), "back\\\\slash");
+        assert_eq!(Shell::escape_nu_quotes("line\nbreak"), "line\\nbreak");
+        // No interpolation in plain double quotes.
+        assert_eq!(Shell::escape_nu_quotes("$env.HOME (ls)"), "$env.HOME (ls)");
+        assert_eq!(Shell::escape_nu_quotes("it's"), "it's");
+        assert_eq!(Shell::escape_nu_quotes(""), "");
+    }
+
+    #[test]
+    fn test_escape_elvish_quotes() {
+        assert_eq!(Shell::escape_elvish_quotes("it's"), "it''s");
+        assert_eq!(Shell::escape_elvish_quotes("''"), "''''");
+        assert_eq!(
+            Shell::escape_elvish_quotes("$var \"q\" \\ (cmd)"),
+            "$var \"q\" \\ (cmd)"
+        );
+        assert_eq!(Shell::escape_elvish_quotes(""), "");
+    }
+
+    #[test]
+    fn test_escape_xonsh_quotes() {
+        assert_eq!(Shell::escape_xonsh_quotes("it's"), "it\\'s");
+        assert_eq!(Shell::escape_xonsh_quotes("back\\slash"), "back\\\\slash");
+        assert_eq!(Shell::escape_xonsh_quotes("line\nbreak"), "line\\nbreak");
+        assert_eq!(Shell::escape_xonsh_quotes("$HOME \"q\""), "$HOME \"q\"");
+        assert_eq!(Shell::escape_xonsh_quotes(""), "");
+    }
+
+    #[test]
+    fn test_escape_csh_quotes() {
+        assert_eq!(Shell::escape_csh_quotes("it's"), "it'\\''s");
+        assert_eq!(Shell::escape_csh_quotes("line\nbreak"), "line\\\nbreak");
+        assert_eq!(
+            Shell::escape_csh_quotes("$var `cmd` \"q\" !1"),
+            "$var `cmd` \"q\" !1"
+        );
+        assert_eq!(Shell::escape_csh_quotes(""), "");
+    }
+
+    #[test]
+    fn test_other_shells_export_with_quotes() {
+        let expected = [
+            (
+                Shell::Nu,
+                [
+                    "$env.AWS_ACCESS_KEY_ID = \"key'with\\\"quotes\"",
+                    "$env.AWS_SECRET_ACCESS_KEY = \"secret$(x)\\\\\"",
+                    "$env.PROMPT_COMMAND = {|| \"it's \\\"p\\\"\" }",
+                ],
+            ),
+            (
+                Shell::Elvish,
+                [
+                    "set-env AWS_ACCESS_KEY_ID 'key''with\"quotes'",
+                    "set-env AWS_SECRET_ACCESS_KEY 'secret$(x)\\'",
+                    "set-env AWS_MFA_SESSION_PROMPT 'it''s \"p\"'",
+                ],
+            ),
+            (
+                Shell::Xonsh,
+                [
+                    "$AWS_ACCESS_KEY_ID = 'key\\'with\"quotes'",
+                    "$AWS_SECRET_ACCESS_KEY = 'secret$(x)\\\\'",
+                    "$PROMPT = 'it\\'s \"p\"'",
+                ],
+            ),
+            (
+                Shell::Tcsh,
+                [
+                    "setenv AWS_ACCESS_KEY_ID 'key'\\''with\"quotes';",
+                    "setenv AWS_SECRET_ACCESS_KEY 'secret$(x)\\';",
+                    "set prompt = 'it'\\''s \"p\"';",
+                ],
+            ),
+        ];
+        for (shell, lines) in expected {
+            let mut output = Vec::new();
+            shell
+                .export(
+                    &mut output,
+                    "key'with\"quotes",
+                    "secret$(x)\\",
+                    "token",
+                    &prompt("it's \"p\""),
+                )
+                .unwrap();
+            let output = String::from_utf8(output).unwrap();
+            for line in lines {
+                assert!(output.contains(line), "{shell:?}: {line} not in {output}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_other_shells_export_prompt_prepend() {
+        let p = prompt_with_profile("dev", true);
+        assert!(export_prompt(Shell::Nu, &p).contains("do $env.__AWS_MFA_SESSION_SAVED_PROMPT"));
+        assert!(export_prompt(Shell::Elvish, &p).ends_with(
+            "set-env AWS_MFA_SESSION_PROMPT '[dev] '\nset-env AWS_MFA_SESSION_PROMPT_PREPEND 1\n"
+        ));
+        assert!(export_prompt(Shell::Xonsh, &p).ends_with(
+            "${...}.setdefault('__AWS_MFA_SESSION_SAVED_PROMPT', $PROMPT)\n\
+             $PROMPT = '[dev] ' + ${...}['__AWS_MFA_SESSION_SAVED_PROMPT']\n"
+        ));
+        assert!(export_prompt(Shell::Tcsh, &p).ends_with(
+            "if (! $?__aws_mfa_session_saved_prompt) set __aws_mfa_session_saved_prompt = \"$prompt\";\n\
+             set prompt = '[dev] '\"$__aws_mfa_session_saved_prompt\";\n"
+        ));
+    }
+
+    #[test]
+    fn test_command_xonsh_uses_rc() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::Xonsh
+            .command("xonsh", "echo hi\n", dir.path())
+            .unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args.first().unwrap().to_str(), Some("--rc"));
+        assert_eq!(
+            *args.last().unwrap(),
+            dir.path().join("init.xsh").as_os_str()
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("init.xsh")).unwrap(),
+            "echo hi\n"
+        );
+        let command = Shell::Tcsh
+            .command("tcsh", "echo hi\n", dir.path())
+            .unwrap();
+        assert_eq!(command.get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_shell_type_names() {
+        assert_eq!(
+            Shell::from_str("powershell", true).unwrap(),
+            Shell::PowerShell
+        );
+        assert_eq!(Shell::from_str("pwsh", true).unwrap(), Shell::PowerShell);
+        assert_eq!(Shell::from_str("fish", true).unwrap(), Shell::Fish);
+        assert_eq!(Shell::from_str("nushell", true).unwrap(), Shell::Nu);
+        assert_eq!(Shell::from_str("csh", true).unwrap(), Shell::Tcsh);
+        assert!(Shell::from_str("ksh", true).is_err());
     }
 
     #[test]
@@ -449,7 +1267,7 @@ mod tests {
                     "key_with_quotes",
                     "secret'with\"mixed'quotes",
                     "token\"with'quotes",
-                    "prompt'with\"quotes",
+                    &prompt("prompt'with\"quotes"),
                 )
                 .unwrap();
         }
@@ -467,7 +1285,7 @@ mod tests {
                     "key$with&special*chars",
                     "secret|with;special<chars>",
                     "token(with)special[chars]",
-                    "prompt{with}special%chars",
+                    &prompt("prompt{{with}}special%chars"),
                 )
                 .unwrap();
         }
@@ -522,7 +1340,7 @@ mod tests {
                 "key\"with\"quotes",
                 "secret\"value",
                 "token\"test",
-                "prompt",
+                &prompt("prompt"),
             )
             .unwrap();
 
@@ -534,30 +1352,135 @@ mod tests {
 
     #[test]
     fn test_escape_powershell_quotes() {
+        assert_eq!(Shell::escape_powershell_quotes("it's"), "it''s");
+        assert_eq!(Shell::escape_powershell_quotes("''"), "''''");
+        // Typographic single quotes also end a single-quoted string.
         assert_eq!(
-            Shell::escape_powershell_quotes("test\"value"),
-            "test`\"value"
+            Shell::escape_powershell_quotes("a\u{2018}b\u{2019}c\u{201A}d\u{201B}e"),
+            "a\u{2018}\u{2018}b\u{2019}\u{2019}c\u{201A}\u{201A}d\u{201B}\u{201B}e"
         );
-        assert_eq!(
-            Shell::escape_powershell_quotes("test$variable"),
-            "test`$variable"
-        );
-        assert_eq!(Shell::escape_powershell_quotes("test`value"), "test``value");
-
+        // Nothing expands in single quotes.
         assert_eq!(
             Shell::escape_powershell_quotes("test\"with$var`escape"),
-            "test`\"with`$var``escape"
+            "test\"with$var`escape"
         );
         assert_eq!(Shell::escape_powershell_quotes("testvalue"), "testvalue");
         assert_eq!(Shell::escape_powershell_quotes(""), "");
+    }
+
+    // Values that break naive quoting: quotes, escapes, expansions, newlines.
+    const NASTY: [&str; 6] = [
+        "it's",
+        "back\\slash\\",
+        "\\'",
+        "$HOME $(id) `id` (echo x) *",
+        "\"q\" ; & | ''",
+        "line\nbreak\u{2019}",
+    ];
+
+    /// The value of the single-quoted string `quoted`, the way fish reads it.
+    fn fish_unquote(quoted: &str) -> String {
+        let inner = &quoted[1..quoted.len() - 1];
+        let mut value = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some(next @ ('\\' | '\'')) => value.push(next),
+                    Some(next) => {
+                        value.push(c);
+                        value.push(next);
+                    }
+                    None => value.push(c),
+                }
+            } else {
+                assert_ne!(c, '\'', "unescaped quote in {quoted}");
+                value.push(c);
+            }
+        }
+        value
+    }
+
+    /// The value of the single-quoted string `quoted`, the way PowerShell reads
+    /// it: any of its single quotes doubled stands for itself.
+    fn powershell_unquote(quoted: &str) -> String {
+        let is_quote = |c| matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}');
+        let inner: Vec<char> = quoted[1..quoted.len() - 1].chars().collect();
+        let mut value = String::new();
+        let mut i = 0;
+        while i < inner.len() {
+            if is_quote(inner[i]) {
+                assert!(
+                    i + 1 < inner.len() && is_quote(inner[i + 1]),
+                    "unescaped quote in {quoted}"
+                );
+                i += 1;
+            }
+            value.push(inner[i]);
+            i += 1;
+        }
+        value
+    }
+
+    #[test]
+    fn test_fish_export_round_trips() {
+        for value in NASTY {
+            let mut output = Vec::new();
+            Shell::Fish
+                .export_vars(&mut output, &[("AWS_SESSION_TOKEN", value)])
+                .unwrap();
+            let line = String::from_utf8(output).unwrap();
+            let quoted = line
+                .strip_prefix("set -gx AWS_SESSION_TOKEN ")
+                .and_then(|rest| rest.strip_suffix('\n'))
+                .unwrap();
+            assert_eq!(fish_unquote(quoted), value);
+
+            let script = format!("{line}printf '%s' \"$AWS_SESSION_TOKEN\"");
+            if let Ok(output) = Command::new("fish").arg("-c").arg(script).output() {
+                assert_eq!(String::from_utf8(output.stdout).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_powershell_export_round_trips() {
+        for value in NASTY {
+            let mut output = Vec::new();
+            Shell::PowerShell
+                .export_vars(&mut output, &[("AWS_SESSION_TOKEN", value)])
+                .unwrap();
+            let line = String::from_utf8(output).unwrap();
+            let quoted = line
+                .strip_prefix("$env:AWS_SESSION_TOKEN = ")
+                .and_then(|rest| rest.strip_suffix('\n'))
+                .unwrap();
+            assert_eq!(powershell_unquote(quoted), value);
+
+            // The process environment holds it, which children inherit.
+            let script = format!(
+                "{line}[Console]::Out.Write([Environment]::GetEnvironmentVariable('AWS_SESSION_TOKEN'))"
+            );
+            if let Ok(output) = Command::new("pwsh")
+                .args(["-NoProfile", "-Command", &script])
+                .output()
+            {
+                assert_eq!(String::from_utf8(output.stdout).unwrap(), value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_escape_fish_quotes() {
+        assert_eq!(Shell::escape_fish_quotes("it's"), "it\\'s");
+        assert_eq!(Shell::escape_fish_quotes("back\\slash"), "back\\\\slash");
+        assert_eq!(Shell::escape_fish_quotes("\\'"), "\\\\\\'");
+        // Nothing else is special in single quotes.
         assert_eq!(
-            Shell::escape_powershell_quotes("$test\"value$more\"data"),
-            "`$test`\"value`$more`\"data"
-        );
-        assert_eq!(
-            Shell::escape_powershell_quotes("test\"\"$value"),
-            "test`\"`\"`$value"
+            Shell::escape_fish_quotes("$var (cmd) \"q\" *"),
+            "$var (cmd) \"q\" *"
         );
+        assert_eq!(Shell::escape_fish_quotes(""), "");
     }
 
     #[test]
@@ -571,22 +1494,488 @@ mod tests {
                 "key\"with$quotes",
                 "secret`value",
                 "token$test\"data",
-                "prompt",
+                &prompt("prompt"),
             )
             .unwrap();
 
         let output_str = String::from_utf8(output).unwrap();
+        assert!(output_str.contains("$env:AWS_ACCESS_KEY_ID = 'key\"with$quotes'"));
+        assert!(output_str.contains("$env:AWS_SECRET_ACCESS_KEY = 'secret`value'"));
+        assert!(output_str.contains("$env:AWS_SESSION_TOKEN = 'token$test\"data'"));
+    }
+
+    fn prompt_with_profile(profile: &str, prepend: bool) -> Prompt {
+        let values = PromptValues {
+            profile: profile.to_string(),
+            ..Default::default()
+        };
+        Prompt::new("[{profile}] ", values, prepend).unwrap()
+    }
+
+    fn export_prompt(shell: Shell, prompt: &Prompt) -> String {
+        let mut output = Vec::new();
+        shell
+            .export(&mut output, "id", "secret", "token", prompt)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_render_prompt_escapes_values_per_shell() {
+        let p = prompt_with_profile("a$b%c`d\\e", false);
+        assert_eq!(Shell::Bash.render_prompt(&p), "[a\\\\$b%c\\\\`d\\\\\\\\e] ");
+        assert_eq!(Shell::Sh.render_prompt(&p), "[a\\$b%c\\`d\\\\e] ");
+        assert_eq!(Shell::Zsh.render_prompt(&p), "[a$b%%c`d\\e] ");
+        assert_eq!(Shell::Cmd.render_prompt(&p), "[a$$b%c`d\\e] ");
+        assert_eq!(Shell::PowerShell.render_prompt(&p), "[a$b%c`d\\e] ");
+        assert_eq!(Shell::Nu.render_prompt(&p), "[a$b%c`d\\e] ");
+        assert_eq!(Shell::Elvish.render_prompt(&p), "[a$b%c`d\\e] ");
+        assert_eq!(Shell::Tcsh.render_prompt(&p), "[a$b%%c`d\\e] ");
+
+        let p = prompt_with_profile("{user}!", false);
+        assert_eq!(Shell::Xonsh.render_prompt(&p), "[{{user}}!] ");
+        assert_eq!(Shell::Tcsh.render_prompt(&p), "[{user}\\!] ");
+    }
+
+    #[test]
+    fn test_render_prompt_keeps_template_literal() {
+        // The template is written for the target shell, so `\$` stays as is.
+        let p = prompt("AWS \\$ ");
+        assert_eq!(Shell::Bash.render_prompt(&p), "AWS \\$ ");
+    }
+
+    #[test]
+    fn test_export_prompt_replace() {
+        let p = prompt_with_profile("dev", false);
+        assert!(export_prompt(Shell::Bash, &p).contains("export PS1='[dev] '\n"));
+        assert!(export_prompt(Shell::Fish, &p).contains(
+            "if not functions -q __aws_mfa_session_saved_prompt; and functions -q fish_prompt; functions -c fish_prompt __aws_mfa_session_saved_prompt; end\n\
+             function fish_prompt; printf '%s' '[dev] '; end\n"
+        ));
+        assert!(export_prompt(Shell::Cmd, &p).contains("set \"PROMPT=[dev] \"\n"));
         assert!(
-            output_str
-                .contains("Set-Variable -Name \"AWS_ACCESS_KEY_ID\" -Value \"key`\"with`$quotes\"")
+            export_prompt(Shell::PowerShell, &p).contains("function global:prompt { '[dev] ' }")
         );
-        assert!(
-            output_str
-                .contains("Set-Variable -Name \"AWS_SECRET_ACCESS_KEY\" -Value \"secret``value\"")
+    }
+
+    #[test]
+    fn test_export_prompt_prepend() {
+        let p = prompt_with_profile("dev", true);
+        // The prompt is saved once and prepended to, so exporting again does
+        // not stack.
+        let zsh = export_prompt(Shell::Zsh, &p);
+        assert!(zsh.contains(
+            "__aws_mfa_session_saved_prompt=\"${__aws_mfa_session_saved_prompt-$PS1}\"\n\
+             export PS1='[dev] '\"$__aws_mfa_session_saved_prompt\"\n"
+        ));
+        assert!(export_prompt(Shell::Fish, &p).contains(
+            "function fish_prompt; printf '%s' '[dev] '; functions -q __aws_mfa_session_saved_prompt; and __aws_mfa_session_saved_prompt; end\n"
+        ));
+        assert!(export_prompt(Shell::Cmd, &p).contains(
+            "if not defined __AWS_MFA_SESSION_SAVED_PROMPT set \"__AWS_MFA_SESSION_SAVED_PROMPT=%PROMPT%\"\n\
+             set \"PROMPT=[dev] %__AWS_MFA_SESSION_SAVED_PROMPT%\"\n"
+        ));
+        let ps = export_prompt(Shell::PowerShell, &p);
+        assert!(ps.contains(
+            "if (-not (Test-Path Variable:global:AwsMfaSessionSavedPrompt)) { $global:AwsMfaSessionSavedPrompt = $function:prompt }"
+        ));
+        assert!(ps.contains(
+            "function global:prompt { '[dev] ' + (& $global:AwsMfaSessionSavedPrompt) }"
+        ));
+    }
+
+    fn expiry_hook(shell: Shell) -> String {
+        let mut output = Vec::new();
+        shell.expiry_hook(&mut output, 300).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_export_vars() {
+        let vars = [("AWS_SESSION_EXPIRATION", "2030-01-01T00:00:00Z")];
+        let expected = [
+            (
+                Shell::Bash,
+                "export AWS_SESSION_EXPIRATION='2030-01-01T00:00:00Z'\n",
+            ),
+            (
+                Shell::Fish,
+                "set -gx AWS_SESSION_EXPIRATION '2030-01-01T00:00:00Z'\n",
+            ),
+            (
+                Shell::Cmd,
+                "set \"AWS_SESSION_EXPIRATION=2030-01-01T00:00:00Z\"\n",
+            ),
+            (
+                Shell::PowerShell,
+                "$env:AWS_SESSION_EXPIRATION = '2030-01-01T00:00:00Z'\n",
+            ),
+            (
+                Shell::Nu,
+                "$env.AWS_SESSION_EXPIRATION = \"2030-01-01T00:00:00Z\"\n",
+            ),
+            (
+                Shell::Elvish,
+                "set-env AWS_SESSION_EXPIRATION '2030-01-01T00:00:00Z'\n",
+            ),
+            (
+                Shell::Xonsh,
+                "$AWS_SESSION_EXPIRATION = '2030-01-01T00:00:00Z'\n",
+            ),
+            (
+                Shell::Tcsh,
+                "setenv AWS_SESSION_EXPIRATION '2030-01-01T00:00:00Z';\n",
+            ),
+        ];
+        for (shell, line) in expected {
+            let mut output = Vec::new();
+            shell.export_vars(&mut output, &vars).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), line);
+        }
+    }
+
+    #[test]
+    fn test_expiry_hook_bash() {
+        let hook = expiry_hook(Shell::Bash);
+        assert!(hook.contains("local left=$(( ${AWS_MFA_SESSION_EXPIRES_AT:-0} - $(date +%s) ))"));
+        assert!(hook.contains("if [ \"$left\" -le 300 ]"));
+        assert!(hook.contains(
+            "PROMPT_COMMAND=\"__aws_mfa_session_hook${PROMPT_COMMAND:+;$PROMPT_COMMAND}\""
+        ));
+    }
+
+    #[test]
+    fn test_expiry_hook_zsh() {
+        let hook = expiry_hook(Shell::Zsh);
+        assert!(hook.contains("__aws_mfa_session_hook() {"));
+        assert!(hook.contains("add-zsh-hook precmd __aws_mfa_session_hook"));
+        assert!(!hook.contains("PROMPT_COMMAND"));
+    }
+
+    #[test]
+    fn test_expiry_hook_fish() {
+        let hook = expiry_hook(Shell::Fish);
+        assert!(hook.contains("function __aws_mfa_session_hook --on-event fish_prompt"));
+        assert!(hook.contains("set -l left (math $AWS_MFA_SESSION_EXPIRES_AT - (date +%s))"));
+        assert!(hook.contains("if test $left -le 300"));
+        assert!(hook.contains(
+            "if not functions -q __aws_mfa_session_saved_right_prompt; and functions -q fish_right_prompt\n    \
+             functions -c fish_right_prompt __aws_mfa_session_saved_right_prompt\nend"
+        ));
+        assert!(hook.contains("function fish_right_prompt"));
+        assert!(hook.contains(
+            "functions -q __aws_mfa_session_saved_right_prompt; and __aws_mfa_session_saved_right_prompt"
+        ));
+    }
+
+    fn refresh_alias(shell: Shell, exe: &str) -> String {
+        let mut output = Vec::new();
+        shell.refresh_alias(&mut output, exe).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_refresh_alias() {
+        assert_eq!(
+            refresh_alias(Shell::Bash, "/usr/bin/aws-mfa-session"),
+            "alias awsmfa-refresh='eval \"$('\\''/usr/bin/aws-mfa-session'\\'' refresh)\"'\n"
+        );
+        assert_eq!(
+            refresh_alias(Shell::Fish, "/usr/bin/aws-mfa-session"),
+            "function awsmfa-refresh; '/usr/bin/aws-mfa-session' refresh | source; end\n"
+        );
+        assert_eq!(
+            refresh_alias(Shell::PowerShell, "C:\\it's\\aws-mfa-session.exe"),
+            "function awsmfa-refresh { & 'C:\\it''s\\aws-mfa-session.exe' refresh | Out-String | Invoke-Expression }\n"
+        );
+        assert_eq!(
+            refresh_alias(Shell::Xonsh, "/opt/it's/aws-mfa-session"),
+            "aliases['awsmfa-refresh'] = lambda: execx($(@('/opt/it\\'s/aws-mfa-session') refresh))\n"
+        );
+        assert_eq!(refresh_alias(Shell::Cmd, "aws-mfa-session.exe"), "");
+        for shell in [Shell::Nu, Shell::Elvish, Shell::Tcsh] {
+            assert_eq!(refresh_alias(shell, "aws-mfa-session"), "");
+        }
+    }
+
+    fn unset(shell: Shell) -> String {
+        let mut output = Vec::new();
+        shell
+            .unset(&mut output, &["AWS_ACCESS_KEY_ID", "AWS_SESSION_TOKEN"])
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_unset() {
+        let expected = [
+            (
+                Shell::Bash,
+                "unset AWS_ACCESS_KEY_ID\nunset AWS_SESSION_TOKEN\n\
+                 if [ -n \"${__aws_mfa_session_saved_prompt+x}\" ]; then PS1=$__aws_mfa_session_saved_prompt; unset __aws_mfa_session_saved_prompt; fi\n",
+            ),
+            (
+                Shell::Fish,
+                "set -e -g AWS_ACCESS_KEY_ID\nset -e -g AWS_SESSION_TOKEN\n\
+                 if functions -q __aws_mfa_session_saved_prompt; functions -e fish_prompt; functions -c __aws_mfa_session_saved_prompt fish_prompt; functions -e __aws_mfa_session_saved_prompt; end\n",
+            ),
+            (
+                Shell::Cmd,
+                "set \"AWS_ACCESS_KEY_ID=\"\nset \"AWS_SESSION_TOKEN=\"\n\
+                 if defined __AWS_MFA_SESSION_SAVED_PROMPT set \"PROMPT=%__AWS_MFA_SESSION_SAVED_PROMPT%\"\n\
+                 set \"__AWS_MFA_SESSION_SAVED_PROMPT=\"\n",
+            ),
+            (
+                Shell::PowerShell,
+                "Remove-Item -Path Env:AWS_ACCESS_KEY_ID -ErrorAction SilentlyContinue\n\
+                 Remove-Item -Path Env:AWS_SESSION_TOKEN -ErrorAction SilentlyContinue\n\
+                 if (Test-Path Variable:global:AwsMfaSessionSavedPrompt) { Set-Item -Path Function:global:prompt -Value $global:AwsMfaSessionSavedPrompt; Remove-Variable -Name AwsMfaSessionSavedPrompt -Scope Global }\n",
+            ),
+            (
+                Shell::Nu,
+                "hide-env -i AWS_ACCESS_KEY_ID\nhide-env -i AWS_SESSION_TOKEN\n\
+                 $env.PROMPT_COMMAND = ($env.__AWS_MFA_SESSION_SAVED_PROMPT? | default $env.PROMPT_COMMAND?)\n\
+                 hide-env -i __AWS_MFA_SESSION_SAVED_PROMPT\n",
+            ),
+            (
+                Shell::Elvish,
+                "unset-env AWS_ACCESS_KEY_ID\nunset-env AWS_SESSION_TOKEN\n\
+                 unset-env AWS_MFA_SESSION_PROMPT\nunset-env AWS_MFA_SESSION_PROMPT_PREPEND\n",
+            ),
+            (
+                Shell::Xonsh,
+                "${...}.pop('AWS_ACCESS_KEY_ID', None)\n${...}.pop('AWS_SESSION_TOKEN', None)\n\
+                 if '__AWS_MFA_SESSION_SAVED_PROMPT' in ${...}: $PROMPT = ${...}.pop('__AWS_MFA_SESSION_SAVED_PROMPT')\n",
+            ),
+            (
+                Shell::Tcsh,
+                "unsetenv AWS_ACCESS_KEY_ID;\nunsetenv AWS_SESSION_TOKEN;\n\
+                 if ($?__aws_mfa_session_saved_prompt) set prompt = \"$__aws_mfa_session_saved_prompt\";\n\
+                 unset __aws_mfa_session_saved_prompt;\n",
+            ),
+        ];
+        for (shell, script) in expected {
+            assert_eq!(unset(shell), script, "{shell:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_export_then_unset_restores_bash_prompt() {
+        // Exporting twice still restores the prompt from before the first.
+        for prepend in [false, true] {
+            let p = prompt_with_profile("dev", prepend);
+            let export = export_prompt(Shell::Bash, &p);
+            let script = format!(
+                "PS1='$ '\n{export}{export}printf '%s|' \"$PS1\"\n{}printf '%s|%s' \"$PS1\" \"${{AWS_SESSION_TOKEN-unset}}\"",
+                unset(Shell::Bash)
+            );
+            let Ok(output) = Command::new("bash").arg("-c").arg(script).output() else {
+                return;
+            };
+            let expected = if prepend {
+                "[dev] $ |$ |unset"
+            } else {
+                "[dev] |$ |unset"
+            };
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_init_prompt_bash_overrides_rc_prompt() {
+        // The init script runs after ~/.bashrc, which commonly sets PS1.
+        for prepend in [false, true] {
+            let mut init = Vec::new();
+            Shell::Bash
+                .init_prompt(&mut init, &prompt_with_profile("dev", prepend))
+                .unwrap();
+            let script = format!(
+                "PS1='rc$ '\n{}printf '%s' \"$PS1\"",
+                String::from_utf8(init).unwrap()
+            );
+            let Ok(output) = Command::new("bash").arg("-c").arg(script).output() else {
+                return;
+            };
+            let expected = if prepend { "[dev] rc$ " } else { "[dev] " };
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refresh_alias_bash_runs() {
+        // The alias must survive two levels of quoting, even with a quote in the path.
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("it's aws-mfa-session");
+        fs::write(&exe, "#!/bin/sh\necho \"export REFRESHED=$1\"\n").unwrap();
+        std::fs::set_permissions(&exe, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let script = format!(
+            "shopt -s expand_aliases\n{}awsmfa-refresh\nprintf '%s' \"$REFRESHED\"",
+            refresh_alias(Shell::Bash, &exe.to_string_lossy())
         );
+        let Ok(output) = Command::new("bash").arg("-c").arg(script).output() else {
+            return;
+        };
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "refresh");
+    }
+
+    #[test]
+    fn test_ensure_init_script() {
+        for shell in [Shell::Nu, Shell::Elvish, Shell::Tcsh] {
+            let err = shell.ensure_init_script().unwrap_err().to_string();
+            assert!(err.contains("--shell cannot set up a"), "{err}");
+        }
         assert!(
-            output_str
-                .contains("Set-Variable -Name \"AWS_SESSION_TOKEN\" -Value \"token`$test`\"data\"")
+            Shell::Tcsh
+                .ensure_init_script()
+                .unwrap_err()
+                .to_string()
+                .contains("a tcsh session")
+        );
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::Xonsh] {
+            assert!(shell.ensure_init_script().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_expiry_hook_unsupported_shells_are_empty() {
+        for shell in [
+            Shell::Sh,
+            Shell::Cmd,
+            Shell::PowerShell,
+            Shell::Nu,
+            Shell::Elvish,
+            Shell::Xonsh,
+            Shell::Tcsh,
+        ] {
+            assert_eq!(expiry_hook(shell), "");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expiry_hook_bash_runs() {
+        // Evaluate the generated hook in a real bash when one is available.
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut hook = Vec::new();
+        Shell::Bash.expiry_hook(&mut hook, 300).unwrap();
+        let script = format!(
+            "PS1='$ '\nAWS_MFA_SESSION_EXPIRES_AT={}\n{}__aws_mfa_session_hook\nprintf '%s' \"$PS1\"",
+            now + 120,
+            String::from_utf8(hook).unwrap()
+        );
+        let Ok(output) = Command::new("bash").arg("-c").arg(script).output() else {
+            return;
+        };
+
+        let ps1 = String::from_utf8(output.stdout).unwrap();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(ps1 == "[0h1m] $ " || ps1 == "[0h2m] $ ", "{ps1}");
+        assert!(stderr.contains("expire in 2 min"), "{stderr}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_init_script_bash_hook_keeps_init_prompt() {
+        // The hook must save PS1 after the init script set it, not the rc's.
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let init = Shell::Bash
+            .init_script(&prompt_with_profile("dev", false), 300, "aws-mfa-session")
+            .unwrap();
+        let script = format!(
+            "PS1='rc$ '\nAWS_MFA_SESSION_EXPIRES_AT={}\n{init}__aws_mfa_session_hook\nprintf '%s' \"$PS1\"",
+            now + 7200
+        );
+        let Ok(output) = Command::new("bash").arg("-c").arg(script).output() else {
+            return;
+        };
+        let ps1 = String::from_utf8(output.stdout).unwrap();
+        assert!(ps1 == "[1h59m] [dev] " || ps1 == "[2h0m] [dev] ", "{ps1}");
+    }
+
+    #[test]
+    fn test_command_without_init_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::Bash.command("/bin/bash", "", dir.path()).unwrap();
+        assert_eq!(command.get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_command_bash_uses_rcfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::Bash
+            .command("/bin/bash", "echo hook\n", dir.path())
+            .unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[0], "--rcfile");
+        let rc = fs::read_to_string(args[1]).unwrap();
+        assert!(rc.starts_with("[ -f ~/.bashrc ] && . ~/.bashrc\n"));
+        assert!(rc.ends_with("echo hook\n"));
+    }
+
+    #[test]
+    fn test_command_zsh_uses_zdotdir() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::Zsh
+            .command("/bin/zsh", "echo hook\n", dir.path())
+            .unwrap();
+        let zdotdir = command
+            .get_envs()
+            .find(|(k, _)| *k == "ZDOTDIR")
+            .and_then(|(_, v)| v)
+            .unwrap();
+        assert_eq!(zdotdir, dir.path());
+        let zshrc = fs::read_to_string(dir.path().join(".zshrc")).unwrap();
+        assert!(zshrc.ends_with("echo hook\n"));
+        assert!(dir.path().join(".zshenv").exists());
+    }
+
+    #[test]
+    fn test_command_sh_uses_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::Sh
+            .command("/bin/sh", "echo hook\n", dir.path())
+            .unwrap();
+        let rc = command
+            .get_envs()
+            .find(|(k, _)| *k == "ENV")
+            .and_then(|(_, v)| v)
+            .unwrap();
+        assert!(fs::read_to_string(rc).unwrap().ends_with("echo hook\n"));
+    }
+
+    #[test]
+    fn test_command_powershell_no_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::PowerShell
+            .command("pwsh.exe", "echo hook\n", dir.path())
+            .unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[..2], ["-NoExit", "-Command"]);
+        assert!(args[2].to_string_lossy().starts_with(". '"));
+    }
+
+    #[test]
+    fn test_command_fish_uses_init_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = Shell::Fish
+            .command("fish", "echo hook\n", dir.path())
+            .unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args[0], "--init-command");
+        assert!(args[1].to_string_lossy().starts_with("source '"));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("init.fish")).unwrap(),
+            "echo hook\n"
         );
     }
 }
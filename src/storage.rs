@@ -0,0 +1,307 @@
+use crate::cache::{CacheKey, EncryptedStore};
+use crate::config::Config;
+use crate::credentials::{Profile, credential_file, update_credentials, update_credentials_all};
+use crate::error::CliError;
+use crate::secret::Secret;
+use crate::session::{Session, WireSession};
+
+use aws_sdk_sts::primitives::{DateTime, DateTimeFormat};
+use clap::ValueEnum;
+use ini::Ini;
+
+const KEYRING_SERVICE: &str = "aws-mfa-session";
+
+/// Where sessions are stored: by `--update-profile`, and as the cache behind
+/// `credential-process`.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum StorageKind {
+    /// The shared credentials file, in plain text
+    #[default]
+    File,
+    /// The OS keyring: Secret Service or the kernel keyring on Linux, Keychain
+    /// on macOS, Credential Manager on Windows
+    Keyring,
+    /// Files in the per-user cache directory, encrypted with a local key file
+    /// or a passphrase
+    Encrypted,
+}
+
+impl StorageKind {
+    /// Parse the `storage` config key.
+    pub fn parse(value: &str) -> Result<Self, CliError> {
+        StorageKind::from_str(value, true).map_err(|_| {
+            CliError::ValidationError(format!(
+                "config key storage: expected file, keyring or encrypted, got {value:?}"
+            ))
+        })
+    }
+
+    pub fn store(
+        self,
+        config: &Config,
+        profile: Option<&str>,
+    ) -> Result<Box<dyn SessionStore>, CliError> {
+        Ok(match self {
+            StorageKind::File => Box::new(FileStore),
+            StorageKind::Keyring => Box::new(KeyringStore),
+            StorageKind::Encrypted => {
+                let dir = EncryptedStore::default_dir().ok_or_else(|| {
+                    CliError::ValidationError("cannot determine the cache directory".to_string())
+                })?;
+                Box::new(EncryptedStore::new(
+                    dir,
+                    CacheKey::from_config(config, profile)?,
+                ))
+            }
+        })
+    }
+}
+
+/// Stores one session per name.
+pub trait SessionStore {
+    fn save(&self, name: &str, session: &Session, region: Option<&str>) -> Result<(), CliError>;
+
+    /// The session stored under `name`, expired or not.
+    fn load(&self, name: &str) -> Result<Option<Session>, CliError>;
+
+    /// Save several sessions at once, each with its name and region. Stores
+    /// that keep them in one file write it once.
+    fn save_all(&self, entries: &[(String, Session, Option<String>)]) -> Result<(), CliError> {
+        for (name, session, region) in entries {
+            self.save(name, session, region.as_deref())?;
+        }
+        Ok(())
+    }
+}
+
+/// A profile section in the shared credentials file, readable by every AWS tool.
+pub struct FileStore;
+
+impl FileStore {
+    fn profile(name: &str, session: &Session, region: Option<&str>) -> Result<Profile, CliError> {
+        Ok(Profile {
+            name: name.to_string(),
+            access_key_id: session.access_key_id.clone(),
+            secret_access_key: session.secret_access_key.clone(),
+            session_token: Some(session.session_token.clone()),
+            region: region.map(ToOwned::to_owned),
+            expiration: Some(session.expiration_rfc3339()?),
+        })
+    }
+}
+
+impl SessionStore for FileStore {
+    #[tracing::instrument(skip_all, fields(entry = %name))]
+    fn save(&self, name: &str, session: &Session, region: Option<&str>) -> Result<(), CliError> {
+        Ok(update_credentials(&Self::profile(name, session, region)?)?)
+    }
+
+    /// One atomic update of the credentials file for all of them.
+    fn save_all(&self, entries: &[(String, Session, Option<String>)]) -> Result<(), CliError> {
+        let profiles = entries
+            .iter()
+            .map(|(name, session, region)| Self::profile(name, session, region.as_deref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(update_credentials_all(&profiles)?)
+    }
+
+    /// A section without an expiration (written by hand or by an older
+    /// version) is not a session this tool can vouch for, so it is skipped.
+    #[tracing::instrument(skip_all, fields(entry = %name))]
+    fn load(&self, name: &str) -> Result<Option<Session>, CliError> {
+        let path = credential_file()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let ini = Ini::load_from_file(&path).map_err(|e| {
+            CliError::ValidationError(format!("invalid credentials file {}: {e}", path.display()))
+        })?;
+        let Some(section) = ini.section(Some(name)) else {
+            return Ok(None);
+        };
+        let fields = (
+            section.get("aws_access_key_id"),
+            section.get("aws_secret_access_key"),
+            section.get("aws_session_token"),
+            section.get("aws_session_expiration"),
+        );
+        let (Some(access_key_id), Some(secret_access_key), Some(session_token), Some(expiration)) =
+            fields
+        else {
+            return Ok(None);
+        };
+        let expiration = DateTime::from_str(expiration, DateTimeFormat::DateTime).map_err(|e| {
+            CliError::ValidationError(format!("profile {name}: invalid expiration: {e}"))
+        })?;
+        Ok(Some(Session {
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.into(),
+            session_token: session_token.into(),
+            expiration,
+        }))
+    }
+}
+
+/// One keyring entry per name, holding the session as JSON.
+pub struct KeyringStore;
+
+impl KeyringStore {
+    /// Entries to try, in order. On Linux the Secret Service persists across
+    /// logins but needs a desktop session; the kernel keyring also works on
+    /// headless machines.
+    fn entries(name: &str) -> Vec<keyring::Entry> {
+        let mut entries = Vec::new();
+        match keyring::Entry::new(KEYRING_SERVICE, name) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => tracing::debug!(error = %e, "default keyring unavailable"),
+        }
+        #[cfg(target_os = "linux")]
+        match keyring::keyutils::KeyutilsCredential::new_with_target(None, KEYRING_SERVICE, name) {
+            Ok(credential) => {
+                entries.push(keyring::Entry::new_with_credential(Box::new(credential)))
+            }
+            Err(e) => tracing::debug!(error = %e, "kernel keyring unavailable"),
+        }
+        entries
+    }
+}
+
+fn keyring_error(e: keyring::Error) -> CliError {
+    CliError::ValidationError(format!("keyring: {e}"))
+}
+
+impl SessionStore for KeyringStore {
+    #[tracing::instrument(skip_all, fields(entry = %name))]
+    fn save(&self, name: &str, session: &Session, _region: Option<&str>) -> Result<(), CliError> {
+        let secret = serde_json::to_string(&WireSession::from(session))
+            .map(Secret::from)
+            .map_err(|e| CliError::ValidationError(e.to_string()))?;
+        let mut last_error = None;
+        for entry in Self::entries(name) {
+            match entry.set_password(secret.expose()) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.map_or_else(
+            || CliError::ValidationError("no keyring available".to_string()),
+            keyring_error,
+        ))
+    }
+
+    #[tracing::instrument(skip_all, fields(entry = %name))]
+    fn load(&self, name: &str) -> Result<Option<Session>, CliError> {
+        for entry in Self::entries(name) {
+            match entry.get_password() {
+                Ok(secret) => {
+                    let secret = Secret::from(secret);
+                    let wire: WireSession = serde_json::from_str(secret.expose()).map_err(|e| {
+                        CliError::ValidationError(format!("keyring entry {name}: {e}"))
+                    })?;
+                    return Ok(Some(wire.into()));
+                }
+                Err(keyring::Error::NoEntry) => {}
+                // An unreachable store cannot hold the entry; try the next.
+                Err(e) => tracing::debug!(error = %e, "keyring read failed"),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// `credential_process` output, as the AWS SDKs and CLI read it.
+pub fn credential_process_json(session: &Session) -> Result<String, CliError> {
+    let json = serde_json::json!({
+        "Version": 1,
+        "AccessKeyId": session.access_key_id,
+        "SecretAccessKey": session.secret_access_key,
+        "SessionToken": session.session_token,
+        "Expiration": session.expiration_rfc3339()?,
+    });
+    Ok(json.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::AWS_SHARED_CREDENTIALS_FILE;
+    use crate::session::tests::session_expiring_in;
+    use serial_test::serial;
+
+    #[test]
+    fn test_storage_kind_parse() {
+        assert_eq!(StorageKind::parse("file").unwrap(), StorageKind::File);
+        assert_eq!(StorageKind::parse("Keyring").unwrap(), StorageKind::Keyring);
+        assert_eq!(
+            StorageKind::parse("encrypted").unwrap(),
+            StorageKind::Encrypted
+        );
+        assert!(StorageKind::parse("vault").is_err());
+        assert_eq!(StorageKind::default(), StorageKind::File);
+    }
+
+    #[test]
+    #[serial]
+    fn test_file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials");
+        std::fs::write(
+            &path,
+            "[dev]\naws_access_key_id = AKIA\naws_secret_access_key = long-term\n",
+        )
+        .unwrap();
+        unsafe { std::env::set_var(AWS_SHARED_CREDENTIALS_FILE, &path) };
+
+        let store = FileStore;
+        let session = session_expiring_in(3600);
+        let saved = store.save("dev-session", &session, Some("eu-west-1"));
+        let loaded = store.load("dev-session");
+        // Long-term keys have no expiration and are never mistaken for a session.
+        let long_term = store.load("dev");
+        let missing = store.load("prod");
+        unsafe { std::env::remove_var(AWS_SHARED_CREDENTIALS_FILE) };
+
+        saved.unwrap();
+        let loaded = loaded.unwrap().unwrap();
+        assert_eq!(loaded.access_key_id, session.access_key_id);
+        assert_eq!(loaded.session_token, session.session_token);
+        assert_eq!(loaded.expiration.secs(), session.expiration.secs());
+        assert!(long_term.unwrap().is_none());
+        assert!(missing.unwrap().is_none());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("aws_secret_access_key = long-term"));
+        assert!(content.contains("region = eu-west-1"));
+    }
+
+    #[test]
+    fn test_keyring_store_round_trip() {
+        // CI containers often have neither a Secret Service nor a usable kernel
+        // keyring; the store is only checked where one is reachable.
+        let store = KeyringStore;
+        let name = format!("test-{}", crate::server::random_token().unwrap());
+        let session = session_expiring_in(3600);
+        if store.save(&name, &session, None).is_err() {
+            return;
+        }
+        let loaded = store.load(&name).unwrap().unwrap();
+        assert_eq!(loaded.secret_access_key, session.secret_access_key);
+        assert_eq!(loaded.expiration.secs(), session.expiration.secs());
+        for entry in KeyringStore::entries(&name) {
+            let _ = entry.delete_credential();
+        }
+    }
+
+    #[test]
+    fn test_credential_process_json() {
+        let mut session = session_expiring_in(0);
+        session.expiration = DateTime::from_secs(1_700_000_000);
+        let json: serde_json::Value =
+            serde_json::from_str(&credential_process_json(&session).unwrap()).unwrap();
+        assert_eq!(json["Version"], 1);
+        assert_eq!(json["AccessKeyId"], "ASIATEST");
+        assert_eq!(json["SecretAccessKey"], "secret");
+        assert_eq!(json["SessionToken"], "token");
+        assert_eq!(json["Expiration"], "2023-11-14T22:13:20Z");
+    }
+}
@@ -1,7 +1,9 @@
 #[cfg(test)]
 mod integration_tests {
     use aws_mfa_session::Args;
+    use aws_mfa_session::Secret;
     use aws_mfa_session::get_mfa_serial_from_profile;
+    use aws_mfa_session::logging::LogFormat;
     use clap::Parser;
     use serial_test::serial;
     use std::env;
@@ -51,24 +53,44 @@ mod integration_tests {
         // Test that Args structure holds the correct values
         // Environment variable setting happens in the run() function
         let args = Args {
-            profile: Some("test-profile".to_string()),
+            profiles: vec!["test-profile".to_string()],
+            group: None,
             credentials_file: Some("/test/path/credentials".to_string()),
             region: None,
-            code: Some("123456".to_string()),
+            code: Some(Secret::from("123456")),
             arn: None,
             duration: 3600,
             shell: false,
             export: true,
+            outputs: Vec::new(),
+            k8s_name: "aws-mfa-session".to_string(),
+            k8s_namespace: None,
+            tfvars_names: [
+                "aws_access_key".to_string(),
+                "aws_secret_key".to_string(),
+                "aws_token".to_string(),
+            ],
+            shell_type: None,
             session_profile: None,
+            cli_cache: Vec::new(),
+            prompt: None,
+            prompt_prepend: false,
+            lookup_alias: false,
+            warn_before: 5,
+            command: None,
+            storage: None,
+            verbose: 0,
+            quiet: 0,
+            log_format: LogFormat::Text,
         };
 
         // Verify args hold the expected values that would be used for env vars
-        assert_eq!(args.profile, Some("test-profile".to_string()));
+        assert_eq!(args.profile(), Some("test-profile"));
         assert_eq!(
             args.credentials_file,
             Some("/test/path/credentials".to_string())
         );
-        assert_eq!(args.code, Some("123456".to_string()));
+        assert_eq!(args.code, Some(Secret::from("123456")));
         assert_eq!(args.duration, 3600);
         assert!(!args.shell);
         assert!(args.export);
@@ -183,7 +205,7 @@ mod integration_tests {
         assert_eq!(args.duration, 3600); // 1 hour default
         assert!(!args.shell);
         assert!(!args.export);
-        assert!(args.profile.is_none());
+        assert!(args.profile().is_none());
         assert!(args.credentials_file.is_none());
         assert!(args.region.is_none());
         assert!(args.arn.is_none());
@@ -213,13 +235,13 @@ mod integration_tests {
         ])
         .unwrap();
 
-        assert_eq!(args.profile, Some("dev-profile".to_string()));
+        assert_eq!(args.profile(), Some("dev-profile"));
         assert_eq!(
             args.credentials_file,
             Some("/custom/aws/credentials".to_string())
         );
         assert_eq!(args.region.unwrap().to_string(), "ap-southeast-2");
-        assert_eq!(args.code, Some("987654".to_string()));
+        assert_eq!(args.code, Some(Secret::from("987654")));
         assert_eq!(
             args.arn,
             Some("arn:aws:iam::123456789012:mfa/test-user".to_string())
@@ -441,4 +463,36 @@ mod integration_tests {
             env::remove_var("AWS_CONFIG_FILE");
         }
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_agent_detaches_and_exports_its_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("agent.sock");
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_aws-mfa-session"))
+            .args(["agent", "--shell-type", "bash", "--socket"])
+            .arg(&socket)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+
+        // The command returned, and the agent it left behind is listening.
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains(&format!(
+            "AWS_MFA_SESSION_AGENT_SOCK='{}'",
+            socket.display()
+        )));
+        assert!(std::os::unix::net::UnixStream::connect(&socket).is_ok());
+
+        let pid = stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("export AWS_MFA_SESSION_AGENT_PID='"))
+            .and_then(|rest| rest.strip_suffix('\''))
+            .unwrap();
+        let killed = std::process::Command::new("kill")
+            .arg(pid)
+            .status()
+            .unwrap();
+        assert!(killed.success());
+    }
 }